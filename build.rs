@@ -32,6 +32,19 @@ fn main() {
             "netavark_proxy.NetworkConfig",
             "#[derive(serde::Serialize)]",
         )
+        // Leases stored and configs written before a field was added still load
+        .type_attribute("netavark_proxy.NetworkConfig", "#[serde(default)]")
+        .type_attribute("netavark_proxy.LeaseEvent", "#[derive(serde::Serialize)]")
+        .type_attribute("netavark_proxy.ProxyInfo", "#[derive(serde::Serialize)]")
+        .type_attribute("netavark_proxy.LeaseInfo", "#[derive(serde::Serialize)]")
+        .type_attribute(
+            "netavark_proxy.InspectResponse",
+            "#[derive(serde::Serialize)]",
+        )
+        .field_attribute(
+            "netavark_proxy.LeaseEvent.kind",
+            "#[serde(serialize_with = \"crate::g_rpc::serialize_event_kind\")]",
+        )
         .out_dir(PathBuf::from("proto-build"));
 
     builder
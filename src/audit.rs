@@ -0,0 +1,300 @@
+/*
+   Audit log of the operations that change leases.
+
+   Every setup, teardown, clean, renewal and expiry is written as one JSON
+   object per line, with the caller that asked for it and the address it
+   resulted in. The log is kept apart from the debug output of the proxy and is
+   rotated once it grows past a size limit. The log tells who runs which
+   containers, so only root may read it.
+*/
+
+use crate::auth::Caller;
+use crate::cache::unix_now;
+use crate::g_rpc::{Lease, NetworkConfig};
+use log::error;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// What was done to a lease
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Setup,
+    Teardown,
+    /// All leases were dropped
+    Clean,
+    Renewal,
+    Expiry,
+    /// The lease of a container that went away without a teardown was released
+    Release,
+}
+
+/// A single line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Record {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub operation: Operation,
+    /// The process that asked for the operation, none for the ones the proxy does by itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    /// The client of the TCP listener that asked for the operation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+    pub mac_address: String,
+    pub ns_path: String,
+    pub interface: String,
+    /// The address of the lease, empty when there is none
+    pub address: String,
+    /// The DHCP server that handed out the lease
+    pub server_id: String,
+    /// "success" or "failure"
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(operation: Operation) -> Self {
+        Record {
+            timestamp: unix_now(),
+            operation,
+            pid: None,
+            uid: None,
+            remote_addr: None,
+            mac_address: String::new(),
+            ns_path: String::new(),
+            interface: String::new(),
+            address: String::new(),
+            server_id: String::new(),
+            outcome: "success",
+            error: None,
+        }
+    }
+
+    /// The container the operation was for
+    pub fn with_config(mut self, nc: &NetworkConfig) -> Self {
+        self.mac_address = nc.container_mac_addr.clone();
+        self.ns_path = nc.ns_path.clone();
+        self.interface = nc.container_iface.clone();
+        self
+    }
+
+    pub fn with_caller(mut self, caller: Option<&Caller>) -> Self {
+        if let Some(caller) = caller {
+            self.pid = caller.pid;
+            self.uid = Some(caller.uid.as_raw());
+            self.remote_addr = caller.remote_addr.map(|a| a.to_string());
+        }
+        self
+    }
+
+    /// The lease the operation resulted in
+    pub fn with_lease(mut self, lease: &Lease) -> Self {
+        if self.mac_address.is_empty() {
+            self.mac_address = lease.mac_address.clone();
+        }
+        self.address = lease.yiaddr.clone();
+        self.server_id = lease.srv_id.clone();
+        self
+    }
+
+    pub fn with_error(mut self, error: &str) -> Self {
+        self.outcome = "failure";
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+/// A JSON lines file that is rotated to `<path>.1`, `<path>.2` and so on when it gets
+/// too large. The oldest file is dropped.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    rotations: usize,
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl AuditLog {
+    /// Append to the audit log at the given path
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: path of the current audit log
+    /// * `max_size`: size in bytes after which the log is rotated
+    /// * `rotations`: number of rotated logs that are kept
+    pub fn open(path: &Path, max_size: u64, rotations: usize) -> io::Result<AuditLog> {
+        let file = open_log(path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            max_size,
+            rotations,
+            file: Mutex::new(Some((file, size))),
+        })
+    }
+
+    /// Append a record. Failures are logged, they do not fail the operation.
+    pub fn write(&self, record: &Record) {
+        if let Err(e) = self.append(record) {
+            error!("Could not write to audit log {:?}: {}", self.path, e);
+        }
+    }
+
+    fn append(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        if let Some((_, size)) = file.as_ref() {
+            if *size > 0 && size + line.len() as u64 > self.max_size {
+                // Close the file before it is renamed
+                *file = None;
+                self.rotate()?;
+            }
+        }
+        if file.is_none() {
+            let f = open_log(&self.path)?;
+            let size = f.metadata()?.len();
+            *file = Some((f, size));
+        }
+        if let Some((f, size)) = file.as_mut() {
+            f.write_all(&line)?;
+            *size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Shift the rotated logs by one and make the current log the first of them
+    fn rotate(&self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.rotations == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.rotations).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+/// Open a log for appending, readable by its owner only. A log created by an older
+/// version may still be readable by everyone.
+fn open_log(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{Gid, Uid};
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+
+    fn temp_path() -> PathBuf {
+        let name: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        let dir = std::env::temp_dir().join(format!("nv-proxy-audit-{name}"));
+        fs::create_dir(&dir).expect("could not create directory");
+        dir.join("nv-proxy.audit")
+    }
+
+    fn record() -> Record {
+        let nc = NetworkConfig {
+            container_mac_addr: "aa:bb:cc:dd:ee:ff".to_string(),
+            container_iface: "eth0".to_string(),
+            ns_path: "/run/netns/foo".to_string(),
+            ..Default::default()
+        };
+        let lease = Lease {
+            yiaddr: "10.0.0.5".to_string(),
+            srv_id: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let caller = Caller {
+            uid: Uid::from_raw(1000),
+            gid: Gid::from_raw(1000),
+            pid: Some(42),
+            remote_addr: None,
+        };
+        Record::new(Operation::Setup)
+            .with_config(&nc)
+            .with_caller(Some(&caller))
+            .with_lease(&lease)
+    }
+
+    #[test]
+    fn json_lines() {
+        let path = temp_path();
+        let log = AuditLog::open(&path, 1 << 20, 2).expect("could not open log");
+        log.write(&record());
+        log.write(&Record::new(Operation::Clean).with_error("denied"));
+
+        let content = fs::read_to_string(&path).expect("could not read log");
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("not json"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["operation"], "setup");
+        assert_eq!(lines[0]["pid"], 42);
+        assert_eq!(lines[0]["uid"], 1000);
+        assert_eq!(lines[0]["address"], "10.0.0.5");
+        assert_eq!(lines[0]["server_id"], "10.0.0.1");
+        assert_eq!(lines[0]["outcome"], "success");
+        assert_eq!(lines[1]["outcome"], "failure");
+        assert_eq!(lines[1]["error"], "denied");
+        assert!(lines[1].get("pid").is_none());
+        assert!(lines[0].get("remote_addr").is_none());
+        let mode = fs::metadata(&path).expect("no log").permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(path.parent().unwrap()).expect("could not clean up");
+    }
+
+    #[test]
+    fn rotation() {
+        let path = temp_path();
+        let line_len = serde_json::to_vec(&record()).unwrap().len() as u64 + 1;
+        // Two records fit in a file
+        let log = AuditLog::open(&path, 2 * line_len, 2).expect("could not open log");
+        for _ in 0..7 {
+            log.write(&record());
+        }
+        let lines = |p: &Path| {
+            fs::read_to_string(p)
+                .map(|c| c.lines().count())
+                .unwrap_or(0)
+        };
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", path.display()));
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&rotated(1)), 2);
+        assert_eq!(lines(&rotated(2)), 2);
+        assert!(!rotated(3).exists());
+        fs::remove_dir_all(path.parent().unwrap()).expect("could not clean up");
+    }
+}
@@ -0,0 +1,307 @@
+/*
+   Authorization of the callers of the proxy.
+
+   The proxy enters the network namespace a caller names and changes its
+   addresses and routes as root, so it has to know who is calling. Callers on
+   the unix socket are identified by the peer credentials of their connection
+   (SO_PEERCRED). Root may do anything. Members of the allowed group may only
+   act on network namespaces whose user namespace is owned by their own user,
+   as the namespaces of rootless containers are.
+
+   The namespace is opened once and checked through that descriptor. Everything
+   the proxy does in the namespace afterwards goes through the same descriptor,
+   so replacing the file at the path after the check does not redirect the proxy
+   into another namespace.
+
+   Callers on the optional TCP listener have no peer credentials. The listener
+   only accepts clients with a certificate signed by the configured client CA,
+   these are trusted like root.
+*/
+
+use crate::types::{CustomErr, ProxyError};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use tonic::transport::server::UdsConnectInfo;
+use tonic::{Request, Status};
+
+// ioctls of nsfs, from linux/nsfs.h
+const NSIO: u8 = 0xb7;
+nix::ioctl_none!(ns_get_userns, NSIO, 0x1);
+nix::ioctl_read_bad!(
+    ns_get_owner_uid,
+    nix::request_code_none!(NSIO, 0x4),
+    nix::libc::uid_t
+);
+
+/// The user and group of the process on the other end of the unix socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub uid: Uid,
+    pub gid: Gid,
+    pub pid: Option<i32>,
+    /// Address of a client of the TCP listener
+    pub remote_addr: Option<SocketAddr>,
+}
+
+impl Caller {
+    /// The peer credentials of the connection a request came in on
+    pub fn of<T>(request: &Request<T>) -> Result<Caller, Status> {
+        let cred = request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred);
+        if let Some(cred) = cred {
+            return Ok(Caller {
+                uid: Uid::from_raw(cred.uid()),
+                gid: Gid::from_raw(cred.gid()),
+                pid: cred.pid(),
+                remote_addr: None,
+            });
+        }
+        // The TLS handshake already verified the certificate against the client CA
+        if request.peer_certs().is_some() {
+            return Ok(Caller {
+                uid: Uid::from_raw(0),
+                gid: Gid::from_raw(0),
+                pid: None,
+                remote_addr: request.remote_addr(),
+            });
+        }
+        Err(Status::permission_denied(
+            "the credentials of the caller are unknown",
+        ))
+    }
+}
+
+/// A network namespace a caller was authorized for. It stays open while it is used.
+#[derive(Debug)]
+pub struct Namespace {
+    file: Option<File>,
+    path: String,
+    owner: Option<Uid>,
+}
+
+impl Namespace {
+    /// Open the network namespace at the given path. A missing namespace is only an error
+    /// once it is used.
+    fn open(ns_path: &str) -> Namespace {
+        let file = File::open(ns_path).ok();
+        let owner = file.as_ref().and_then(|f| namespace_owner(f).ok());
+        Namespace {
+            file,
+            path: ns_path.to_string(),
+            owner,
+        }
+    }
+
+    /// Path that reaches the namespace that was checked, for as long as this is not dropped
+    pub fn path(&self) -> String {
+        match &self.file {
+            Some(f) => format!("/proc/self/fd/{}", f.as_raw_fd()),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The user that owns the namespace, None when it could not be opened
+    pub fn owner(&self) -> Option<Uid> {
+        self.owner
+    }
+}
+
+/// Who may call the proxy besides root
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    // members of this group may call the proxy for the namespaces they own
+    group: Option<Group>,
+}
+
+impl Policy {
+    /// Allow root and the members of the given group, by name or numeric id
+    pub fn new(group: Option<&str>) -> Result<Policy, ProxyError> {
+        let group = match group {
+            None => None,
+            Some(name) => {
+                let found = match name.parse::<u32>() {
+                    Ok(gid) => Group::from_gid(Gid::from_raw(gid))?,
+                    Err(_) => Group::from_name(name)?,
+                };
+                match found {
+                    Some(g) => Some(g),
+                    None => return Err(ProxyError::new(format!("unknown group {name}"))),
+                }
+            }
+        };
+        Ok(Policy { group })
+    }
+
+    /// Check that the caller may use the proxy at all
+    pub fn authorize(&self, caller: &Caller) -> Result<(), Status> {
+        if caller.uid.is_root() || self.is_member(caller) {
+            return Ok(());
+        }
+        Err(Status::permission_denied(format!(
+            "user {} may not use the proxy",
+            caller.uid
+        )))
+    }
+
+    /// Check that the caller may change the network namespace at the given path. The
+    /// namespace that was checked is returned, it has to be used instead of the path.
+    pub fn authorize_namespace(&self, caller: &Caller, ns_path: &str) -> Result<Namespace, Status> {
+        self.authorize(caller)?;
+        let netns = Namespace::open(ns_path);
+        if caller.uid.is_root() {
+            return Ok(netns);
+        }
+        match (&netns.file, netns.owner) {
+            (Some(_), Some(owner)) if owner == caller.uid => Ok(netns),
+            (Some(_), Some(owner)) => Err(Status::permission_denied(format!(
+                "network namespace {} belongs to user {}, not to user {}",
+                ns_path, owner, caller.uid
+            ))),
+            _ => Err(Status::permission_denied(format!(
+                "could not find the owner of network namespace {ns_path}"
+            ))),
+        }
+    }
+
+    /// Check that the caller may act on a lease that was set up for a network namespace
+    /// owned by the given user. The namespace may be gone by now, but when it is still at
+    /// the path it has to belong to the same user.
+    pub fn authorize_owner(
+        &self,
+        caller: &Caller,
+        owner: Uid,
+        ns_path: &str,
+    ) -> Result<Namespace, Status> {
+        self.authorize(caller)?;
+        let netns = Namespace::open(ns_path);
+        if caller.uid.is_root() {
+            return Ok(netns);
+        }
+        if caller.uid != owner {
+            return Err(Status::permission_denied(format!(
+                "the lease belongs to user {}, not to user {}",
+                owner, caller.uid
+            )));
+        }
+        match netns.owner {
+            Some(current) if current != owner => Err(Status::permission_denied(format!(
+                "network namespace {ns_path} belongs to user {current} now, not to user {owner}"
+            ))),
+            _ => Ok(netns),
+        }
+    }
+
+    /// Check that the caller may change what belongs to other users, like clearing
+    /// all leases
+    pub fn authorize_all(&self, caller: &Caller) -> Result<(), Status> {
+        if caller.uid.is_root() {
+            return Ok(());
+        }
+        Err(Status::permission_denied(format!(
+            "only root may do this, not user {}",
+            caller.uid
+        )))
+    }
+
+    fn is_member(&self, caller: &Caller) -> bool {
+        let group = match &self.group {
+            Some(g) => g,
+            None => return false,
+        };
+        if caller.gid == group.gid {
+            return true;
+        }
+        // Supplementary members are listed by name
+        match User::from_uid(caller.uid) {
+            Ok(Some(user)) => group.mem.contains(&user.name),
+            _ => false,
+        }
+    }
+}
+
+/// The owner of the user namespace the given network namespace belongs to
+fn namespace_owner(netns: &File) -> Result<Uid, ProxyError> {
+    // SAFETY: the ioctls are called on an open nsfs file and return a new descriptor
+    // or write a single uid_t
+    let userns = unsafe { File::from_raw_fd(ns_get_userns(netns.as_raw_fd())?) };
+    let mut uid: nix::libc::uid_t = 0;
+    unsafe { ns_get_owner_uid(userns.as_raw_fd(), &mut uid) }?;
+    Ok(Uid::from_raw(uid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caller(uid: u32, gid: u32) -> Caller {
+        Caller {
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(gid),
+            pid: None,
+            remote_addr: None,
+        }
+    }
+
+    #[test]
+    fn only_root_by_default() {
+        let policy = Policy::default();
+        assert!(policy.authorize(&caller(0, 0)).is_ok());
+        assert!(policy.authorize_all(&caller(0, 0)).is_ok());
+        let err = policy.authorize(&caller(1000, 1000)).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert!(policy
+            .authorize_namespace(&caller(1000, 1000), "/proc/self/ns/net")
+            .is_err());
+        assert!(Caller::of(&Request::new(())).is_err());
+    }
+
+    #[test]
+    fn namespace_is_opened_once() {
+        let policy = Policy::default();
+        let netns = policy
+            .authorize_namespace(&caller(0, 0), "/proc/self/ns/net")
+            .expect("root was refused");
+        let path = netns.path();
+        assert!(path.starts_with("/proc/self/fd/"));
+        // The descriptor reaches the namespace that was checked
+        let checked = std::fs::metadata(&path).expect("namespace is gone");
+        let current = std::fs::metadata("/proc/self/ns/net").expect("no network namespace");
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::ino(&checked),
+            std::os::unix::fs::MetadataExt::ino(&current)
+        );
+    }
+
+    #[test]
+    fn group_members() {
+        let policy = Policy::new(Some("0")).expect("no root group");
+        assert!(policy.authorize(&caller(1000, 0)).is_ok());
+        assert!(policy.authorize_all(&caller(1000, 0)).is_err());
+        assert!(policy.authorize(&caller(1000, 1000)).is_err());
+        // The namespace of the tests does not belong to the member
+        let netns = File::open("/proc/self/ns/net").expect("no network namespace");
+        let owner = namespace_owner(&netns).expect("no namespace owner");
+        let member = caller(owner.as_raw() + 1, 0);
+        assert!(policy
+            .authorize_namespace(&member, "/proc/self/ns/net")
+            .is_err());
+        assert!(policy
+            .authorize_namespace(&caller(owner.as_raw(), 0), "/proc/self/ns/net")
+            .is_ok());
+        // Leases are torn down by the owner of their namespace, even once it is gone
+        let gone = "/run/netns/nv-proxy-does-not-exist";
+        assert!(policy.authorize_owner(&member, owner, gone).is_err());
+        assert!(policy
+            .authorize_owner(&caller(owner.as_raw(), 0), owner, gone)
+            .is_ok());
+        assert!(policy
+            .authorize_owner(&member, member.uid, "/proc/self/ns/net")
+            .is_err());
+
+        assert!(Policy::new(Some("nv-proxy-no-such-group")).is_err());
+    }
+}
@@ -1,104 +1,1131 @@
-use crate::g_rpc::{Lease as NetavarkLease, Lease};
-use log::{debug, error};
+use crate::g_rpc::{
+    Lease as NetavarkLease, Lease, LeaseEvent, LeaseEventKind, NetworkConfig, Version,
+};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs::File;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Cursor, Write};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
 
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct ClearError {
-    msg: String,
+// Number of lease events kept for watchers that fall behind
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+// Minimum number of journal records before the journal is compacted
+const JOURNAL_COMPACT_MIN: usize = 1024;
+// Most identities whose last address is remembered, the oldest ones are forgotten first
+const HISTORY_CAPACITY: usize = 1024;
+
+// Schema version of the lease files written by this proxy. Version 0 is the bare map of
+// leases per mac address, written before the files had a schema version.
+pub const LEASE_SCHEMA_VERSION: u32 = 3;
+
+/// The IP family of a lease
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "v4"),
+            IpFamily::V6 => write!(f, "v6"),
+        }
+    }
+}
+
+/// Identifies a single lease. A container can hold both a v4 and a v6 lease for its mac
+/// address, and the same mac address can be used on more than one parent interface.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LeaseKey {
+    /// Mac address of the container
+    pub mac_address: String,
+    /// The parent interface on the host
+    pub interface: String,
+    pub family: IpFamily,
+    /// Client identifier of a container on an ipvlan link, which shares its mac address
+    /// with the other containers on the parent interface
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub client_id: String,
+}
+
+impl LeaseKey {
+    /// The mac address is kept in lower case, so that netavark and the client can spell it
+    /// either way
+    pub fn new(mac_address: &str, interface: &str, family: IpFamily) -> Self {
+        LeaseKey {
+            mac_address: mac_address.to_ascii_lowercase(),
+            interface: interface.to_string(),
+            family,
+            client_id: String::new(),
+        }
+    }
+
+    /// Key of the lease that is requested with a network configuration
+    pub fn from_config(network_config: &NetworkConfig) -> Self {
+        let family = match Version::from_i32(network_config.version) {
+            Some(Version::V6) => IpFamily::V6,
+            _ => IpFamily::V4,
+        };
+        LeaseKey {
+            client_id: network_config.client_id().unwrap_or_default(),
+            ..LeaseKey::new(
+                &network_config.container_mac_addr,
+                &network_config.host_iface,
+                family,
+            )
+        }
+    }
+
+    /// Key of a lease that was stored by mac address alone. Leases stored before the
+    /// network configuration was kept with them have no parent interface, see
+    /// [`LeaseCache::lookup`].
+    fn from_entry(mac_address: &str, entry: &CachedLease) -> Self {
+        let family = if entry.lease.is_v6 {
+            IpFamily::V6
+        } else {
+            IpFamily::V4
+        };
+        LeaseKey {
+            client_id: entry.network_config.client_id().unwrap_or_default(),
+            ..LeaseKey::new(mac_address, &entry.network_config.host_iface, family)
+        }
+    }
 }
-/// The Writer on the cache must be clearable so that any new changes can be overwritten
-pub trait Clear {
-    fn clear(&mut self) -> Result<(), ClearError>;
+
+impl fmt::Display for LeaseKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on {} ({})",
+            self.mac_address, self.interface, self.family
+        )?;
+        if !self.client_id.is_empty() {
+            write!(f, " as {}", self.client_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// The leases kept by the cache
+pub type Leases = HashMap<LeaseKey, CachedLease>;
+
+/// Who an address was handed to. A container that is created again keeps its id and
+/// network but usually gets a new mac address, so both are remembered.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Identity {
+    /// The mac address on a parent interface
+    Mac(LeaseKey),
+    /// The container on a podman network
+    Container {
+        container_id: String,
+        network_name: String,
+        family: IpFamily,
+    },
 }
-impl Clear for Cursor<Vec<u8>> {
-    fn clear(&mut self) -> Result<(), ClearError> {
+
+impl Identity {
+    /// The identities of a lease, the container one only when netavark named the container
+    fn of(key: &LeaseKey, network_config: &NetworkConfig) -> Vec<Identity> {
+        let mut identities = Vec::new();
+        if !network_config.container_id.is_empty() {
+            identities.push(Identity::Container {
+                container_id: network_config.container_id.clone(),
+                network_name: network_config.network_name.clone(),
+                family: key.family,
+            });
+        }
+        identities.push(Identity::Mac(key.clone()));
+        identities
+    }
+}
+
+/// The last address handed to an identity
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressRecord {
+    pub address: String,
+    /// Unix time in seconds when the address was last acquired or released
+    pub updated_at: u64,
+}
+
+/// The last address of each identity, kept after its lease is gone
+pub type AddressHistory = HashMap<Identity, AddressRecord>;
+
+/// Remember an address, forgetting the oldest addresses once the history is full
+fn add_to_history(history: &mut AddressHistory, identity: Identity, record: AddressRecord) {
+    history.insert(identity, record);
+    while history.len() > HISTORY_CAPACITY {
+        let oldest = history
+            .iter()
+            .min_by_key(|(_, r)| r.updated_at)
+            .map(|(i, _)| i.clone());
+        match oldest {
+            Some(i) => history.remove(&i),
+            None => break,
+        };
+    }
+}
+
+/// An entry of the address history as it is written to the lease files
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredAddress<'a> {
+    identity: Cow<'a, Identity>,
+    #[serde(flatten)]
+    record: Cow<'a, AddressRecord>,
+}
+
+impl<'a> StoredAddress<'a> {
+    fn borrowed(identity: &'a Identity, record: &'a AddressRecord) -> Self {
+        StoredAddress {
+            identity: Cow::Borrowed(identity),
+            record: Cow::Borrowed(record),
+        }
+    }
+}
+
+/// A lease together with its key, as it is written to the lease files
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredLease<'a> {
+    key: Cow<'a, LeaseKey>,
+    lease: Cow<'a, CachedLease>,
+}
+
+impl<'a> StoredLease<'a> {
+    fn borrowed(key: &'a LeaseKey, lease: &'a CachedLease) -> Self {
+        StoredLease {
+            key: Cow::Borrowed(key),
+            lease: Cow::Borrowed(lease),
+        }
+    }
+}
+
+/// Describes the lease file it is written to, so that a newer proxy can migrate it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaHeader {
+    /// Version of the layout of the leases
+    pub schema_version: u32,
+    /// Version of the proxy that wrote the file
+    pub writer_version: String,
+    /// Unix time in seconds when the file was created
+    pub created_at: u64,
+}
+
+impl SchemaHeader {
+    /// Header of a file written by this proxy
+    pub fn new(created_at: u64) -> Self {
+        SchemaHeader {
+            schema_version: LEASE_SCHEMA_VERSION,
+            writer_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at,
+        }
+    }
+
+    /// Header of a file written before the files had a schema version
+    fn legacy() -> Self {
+        SchemaHeader {
+            schema_version: 0,
+            writer_version: String::from("unknown"),
+            created_at: unix_now(),
+        }
+    }
+
+    /// Files written by a newer proxy may hold leases this proxy does not understand,
+    /// loading them could lose leases so they are refused.
+    fn check(&self) -> io::Result<()> {
+        if self.schema_version > LEASE_SCHEMA_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "lease file has schema version {} written by proxy {}, this proxy only supports up to version {}",
+                    self.schema_version, self.writer_version, LEASE_SCHEMA_VERSION
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Bring leases stored with an older schema version up to the current version
+///
+/// # Arguments
+///
+/// * `schema_version`: version the leases were stored with
+/// * `leases`: the stored leases
+///
+/// returns: Result<Leases, Error>
+fn migrate(schema_version: u32, leases: Value) -> io::Result<Leases> {
+    match schema_version {
+        // Version 1 only added the header, the leases are the same as in version 0.
+        // Both keep the leases per mac address.
+        0 | 1 => {
+            let by_mac: HashMap<String, Vec<CachedLease>> = serde_json::from_value(leases)?;
+            Ok(by_mac
+                .into_iter()
+                .flat_map(|(mac_address, l)| {
+                    l.into_iter()
+                        .map(move |e| (LeaseKey::from_entry(&mac_address, &e), e))
+                })
+                .collect())
+        }
+        // Version 3 added the address history next to the leases
+        2 | 3 => {
+            let stored: Vec<StoredLease> = serde_json::from_value(leases)?;
+            Ok(stored
+                .into_iter()
+                .map(|l| (l.key.into_owned(), l.lease.into_owned()))
+                .collect())
+        }
+        v => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown lease schema version {v}"),
+        )),
+    }
+}
+
+/// The persisted bytes behind a lease store. Replacing the contents must leave either the
+/// previous or the new contents behind, never a mix of both.
+pub trait Persist {
+    /// Read back all of the contents
+    fn contents(&mut self) -> io::Result<Vec<u8>>;
+    /// Replace all of the contents
+    fn persist(&mut self, contents: &[u8]) -> io::Result<()>;
+    /// Add to the end of the contents
+    fn append(&mut self, contents: &[u8]) -> io::Result<()>;
+}
+
+impl Persist for Cursor<Vec<u8>> {
+    fn contents(&mut self) -> io::Result<Vec<u8>> {
+        Ok(self.get_ref().clone())
+    }
+
+    fn persist(&mut self, contents: &[u8]) -> io::Result<()> {
         self.set_position(0);
         self.get_mut().clear();
+        self.write_all(contents)?;
+        self.flush()
+    }
+
+    fn append(&mut self, contents: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::End(0))?;
+        self.write_all(contents)?;
+        self.flush()
+    }
+}
+
+/// A file that is replaced atomically. The contents are written to a temporary file in
+/// the same directory, synced to disk and then renamed over the file. A crash or a full
+/// disk while writing leaves either the old or the new file, never a truncated one.
+#[derive(Debug)]
+pub struct AtomicFile {
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl AtomicFile {
+    /// Use the file at the given path, keeping the contents of an existing file
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: path of the file
+    ///
+    /// returns: AtomicFile
+    pub fn open<P: AsRef<Path>>(path: P) -> AtomicFile {
+        let path = path.as_ref().to_path_buf();
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(path.file_name().unwrap_or_default());
+        tmp_name.push(".tmp");
+        AtomicFile {
+            tmp_path: path.with_file_name(tmp_name),
+            path,
+        }
+    }
+
+    /// Create the file with the given contents, replacing any existing file
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: path of the file
+    /// * `contents`: initial contents of the file
+    ///
+    /// returns: Result<AtomicFile, Error>
+    pub fn create<P: AsRef<Path>>(path: P, contents: &[u8]) -> io::Result<AtomicFile> {
+        let mut file = AtomicFile::open(path);
+        file.persist(contents)?;
+        Ok(file)
+    }
+
+    /// Path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Persist for AtomicFile {
+    fn contents(&mut self) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut f) => {
+                f.read_to_end(&mut contents)?;
+            }
+            // A file that was never written has no contents
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(contents)
+    }
+
+    fn persist(&mut self, contents: &[u8]) -> io::Result<()> {
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.tmp_path)?;
+        if let Err(e) = tmp.write_all(contents).and_then(|_| tmp.sync_all()) {
+            // Do not leave a partial file behind
+            let _ = std::fs::remove_file(&self.tmp_path);
+            return Err(e);
+        }
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        // The rename is only durable once the directory is synced
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
         Ok(())
     }
+
+    fn append(&mut self, contents: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        file.write_all(contents)?;
+        file.sync_data()
+    }
+}
+
+/// Where the lease cache keeps its leases across restarts of the proxy
+pub trait LeaseStore {
+    /// Load the leases that were stored before
+    fn load(&mut self) -> io::Result<Leases>;
+    /// Store a lease and remember the addresses that came with it, in a single write.
+    /// `leases` already holds the new lease.
+    fn put(
+        &mut self,
+        key: &LeaseKey,
+        entry: &CachedLease,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()>;
+    /// Forget a lease and remember the addresses it had, in a single write. `leases` no
+    /// longer holds the lease.
+    fn remove(
+        &mut self,
+        key: &LeaseKey,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()>;
+    /// Forget all leases. The address history is kept.
+    fn clear(&mut self) -> io::Result<()>;
+    /// The address history that was loaded and remembered since
+    fn history(&self) -> &AddressHistory;
+}
+
+impl<S: LeaseStore + ?Sized> LeaseStore for Box<S> {
+    fn load(&mut self) -> io::Result<Leases> {
+        (**self).load()
+    }
+
+    fn put(
+        &mut self,
+        key: &LeaseKey,
+        entry: &CachedLease,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        (**self).put(key, entry, remembered, leases)
+    }
+
+    fn remove(
+        &mut self,
+        key: &LeaseKey,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        (**self).remove(key, remembered, leases)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        (**self).clear()
+    }
+
+    fn history(&self) -> &AddressHistory {
+        (**self).history()
+    }
+}
+
+/// The lease file of the snapshot store
+#[derive(Debug, Serialize)]
+struct Snapshot<'a> {
+    #[serde(flatten)]
+    header: SchemaHeader,
+    leases: Vec<StoredLease<'a>>,
+    history: Vec<StoredAddress<'a>>,
+}
+
+/// Stores the leases as a single json list of leases, behind a schema header. Every change
+/// writes the whole list again.
+#[derive(Debug)]
+pub struct SnapshotStore<W: Persist> {
+    writer: W,
+    header: SchemaHeader,
+    history: AddressHistory,
 }
 
-impl Clear for File {
-    fn clear(&mut self) -> Result<(), ClearError> {
-        match self.set_len(0) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(ClearError { msg: e.to_string() }),
+impl<W: Persist> SnapshotStore<W> {
+    pub fn new(writer: W) -> Self {
+        SnapshotStore {
+            writer,
+            header: SchemaHeader::new(unix_now()),
+            history: AddressHistory::new(),
+        }
+    }
+
+    fn save(&mut self, leases: &Leases) -> io::Result<()> {
+        let contents = serde_json::to_vec(&Snapshot {
+            header: self.header.clone(),
+            leases: leases
+                .iter()
+                .map(|(key, l)| StoredLease::borrowed(key, l))
+                .collect(),
+            history: self
+                .history
+                .iter()
+                .map(|(i, r)| StoredAddress::borrowed(i, r))
+                .collect(),
+        })?;
+        self.writer.persist(&contents)
+    }
+
+    fn remember(&mut self, remembered: &[(Identity, AddressRecord)]) {
+        for (identity, record) in remembered {
+            add_to_history(&mut self.history, identity.clone(), record.clone());
         }
     }
 }
-/// The leasing cache holds a in memory record of the leases, and a on file version
+
+impl<W: Persist> LeaseStore for SnapshotStore<W> {
+    fn load(&mut self) -> io::Result<Leases> {
+        let contents = self.writer.contents()?;
+        if contents.is_empty() {
+            return Ok(Leases::new());
+        }
+        let (header, leases, history) = match serde_json::from_slice(&contents)? {
+            Value::Object(mut file) if file.contains_key("schema_version") => {
+                let leases = file.remove("leases").unwrap_or_default();
+                let history = file.remove("history");
+                let header: SchemaHeader = serde_json::from_value(Value::Object(file))?;
+                (header, leases, history)
+            }
+            leases => (SchemaHeader::legacy(), leases, None),
+        };
+        header.check()?;
+        if header.schema_version < LEASE_SCHEMA_VERSION {
+            debug!(
+                "migrating lease file from schema version {}",
+                header.schema_version
+            );
+        }
+        // The file is written with the current schema from now on
+        self.header = SchemaHeader::new(header.created_at);
+        if let Some(history) = history {
+            let stored: Vec<StoredAddress> = serde_json::from_value(history)?;
+            for a in stored {
+                add_to_history(
+                    &mut self.history,
+                    a.identity.into_owned(),
+                    a.record.into_owned(),
+                );
+            }
+        }
+        migrate(header.schema_version, leases)
+    }
+
+    fn put(
+        &mut self,
+        _key: &LeaseKey,
+        _entry: &CachedLease,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        self.remember(remembered);
+        self.save(leases)
+    }
+
+    fn remove(
+        &mut self,
+        _key: &LeaseKey,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        self.remember(remembered);
+        self.save(leases)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.save(&Leases::new())
+    }
+
+    fn history(&self) -> &AddressHistory {
+        &self.history
+    }
+}
+
+/// A single change to the leases as it is written to the journal. The journal starts with
+/// a header record, journals without one were written with schema version 0.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum JournalRecord<'a> {
+    Header(SchemaHeader),
+    Put(Box<StoredLease<'a>>),
+    Remove { key: Cow<'a, LeaseKey> },
+    Remember(Box<StoredAddress<'a>>),
+}
+
+/// A journal record of schema version 0 and 1, which kept the leases per mac address
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum LegacyJournalRecord {
+    Header(SchemaHeader),
+    Put {
+        mac_address: String,
+        leases: Vec<CachedLease>,
+    },
+    Remove {
+        mac_address: String,
+    },
+}
+
+/// Stores the leases as an append-only journal of json lines, one line per change. Loading
+/// replays the journal. Once the journal holds many more records than there are leases it is
+/// compacted, by replacing it with a single put per lease.
 #[derive(Debug)]
-pub struct LeaseCache<W: Write + Clear> {
-    mem: HashMap<String, Vec<NetavarkLease>>,
+pub struct JournalStore<W: Persist> {
     writer: W,
+    header: SchemaHeader,
+    history: AddressHistory,
+    // records in the journal since it was last compacted
+    records: usize,
 }
 
-impl<W: Write + Clear> LeaseCache<W> {
-    ///
+impl<W: Persist> JournalStore<W> {
+    pub fn new(writer: W) -> Self {
+        JournalStore {
+            writer,
+            header: SchemaHeader::new(unix_now()),
+            history: AddressHistory::new(),
+            records: 0,
+        }
+    }
+
+    /// Append the records of a change with a single write
+    fn append(&mut self, records: &[JournalRecord], leases: &Leases) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        self.writer.append(&lines)?;
+        self.records += records.len();
+        let live = leases.len() + self.history.len();
+        if self.records >= JOURNAL_COMPACT_MIN && self.records > 2 * live {
+            self.compact(leases)?;
+        }
+        Ok(())
+    }
+
+    /// Append a change to the leases followed by a record per remembered address
+    fn append_remembering(
+        &mut self,
+        record: JournalRecord,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        for (identity, address) in remembered {
+            add_to_history(&mut self.history, identity.clone(), address.clone());
+        }
+        let mut records = vec![record];
+        records.extend(remembered.iter().map(|(identity, address)| {
+            JournalRecord::Remember(Box::new(StoredAddress::borrowed(identity, address)))
+        }));
+        self.append(&records, leases)
+    }
+
+    /// Replace the journal with a single record per lease and remembered address
+    fn compact(&mut self, leases: &Leases) -> io::Result<()> {
+        debug!("compacting lease journal of {} records", self.records);
+        let mut contents = serde_json::to_vec(&JournalRecord::Header(self.header.clone()))?;
+        contents.push(b'\n');
+        for (key, l) in leases {
+            serde_json::to_writer(
+                &mut contents,
+                &JournalRecord::Put(Box::new(StoredLease::borrowed(key, l))),
+            )?;
+            contents.push(b'\n');
+        }
+        for (i, r) in &self.history {
+            serde_json::to_writer(
+                &mut contents,
+                &JournalRecord::Remember(Box::new(StoredAddress::borrowed(i, r))),
+            )?;
+            contents.push(b'\n');
+        }
+        self.writer.persist(&contents)?;
+        self.records = leases.len() + self.history.len();
+        Ok(())
+    }
+}
+
+impl<W: Persist> LeaseStore for JournalStore<W> {
+    fn load(&mut self) -> io::Result<Leases> {
+        let contents = self.writer.contents()?;
+        let mut leases = Leases::new();
+        let mut lines = contents
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .peekable();
+        self.records = 0;
+        let mut header = SchemaHeader::legacy();
+        while let Some(line) = lines.next() {
+            // Journals of older schema versions are migrated while they are replayed
+            let record = if header.schema_version < 2 {
+                serde_json::from_slice(line).map(|r| match r {
+                    LegacyJournalRecord::Header(h) => Some(h),
+                    LegacyJournalRecord::Put {
+                        mac_address,
+                        leases: l,
+                    } => {
+                        // Keys hold the mac address in lower case, the records as netavark sent it
+                        leases.retain(|key, _| !key.mac_address.eq_ignore_ascii_case(&mac_address));
+                        for e in l {
+                            leases.insert(LeaseKey::from_entry(&mac_address, &e), e);
+                        }
+                        None
+                    }
+                    LegacyJournalRecord::Remove { mac_address } => {
+                        leases.retain(|key, _| !key.mac_address.eq_ignore_ascii_case(&mac_address));
+                        None
+                    }
+                })
+            } else {
+                serde_json::from_slice(line).map(|r| match r {
+                    JournalRecord::Header(h) => Some(h),
+                    JournalRecord::Put(l) => {
+                        leases.insert(l.key.into_owned(), l.lease.into_owned());
+                        None
+                    }
+                    JournalRecord::Remove { key } => {
+                        leases.remove(&key);
+                        None
+                    }
+                    JournalRecord::Remember(a) => {
+                        add_to_history(
+                            &mut self.history,
+                            a.identity.into_owned(),
+                            a.record.into_owned(),
+                        );
+                        None
+                    }
+                })
+            };
+            match record {
+                Ok(Some(h)) => {
+                    h.check()?;
+                    header = h;
+                    continue;
+                }
+                Ok(None) => {}
+                // A crash while appending can only cut off the last record
+                Err(e) if lines.peek().is_none() => {
+                    warn!(
+                        "Ignoring incomplete last record of the lease journal: {}",
+                        e
+                    );
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            self.records += 1;
+        }
+        // Start from a clean journal with the current schema, this also drops an
+        // incomplete last record
+        self.header = SchemaHeader::new(header.created_at);
+        self.compact(&leases)?;
+        Ok(leases)
+    }
+
+    fn put(
+        &mut self,
+        key: &LeaseKey,
+        entry: &CachedLease,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        let record = JournalRecord::Put(Box::new(StoredLease::borrowed(key, entry)));
+        self.append_remembering(record, remembered, leases)
+    }
+
+    fn remove(
+        &mut self,
+        key: &LeaseKey,
+        remembered: &[(Identity, AddressRecord)],
+        leases: &Leases,
+    ) -> io::Result<()> {
+        let record = JournalRecord::Remove {
+            key: Cow::Borrowed(key),
+        };
+        self.append_remembering(record, remembered, leases)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.compact(&Leases::new())
+    }
+
+    fn history(&self) -> &AddressHistory {
+        &self.history
+    }
+}
+
+/// Returns the current time as seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A lease as it is stored in the cache. Next to the DHCP lease itself the proxy keeps
+/// the network configuration it was requested for, and the absolute times at which the
+/// lease was obtained and when it runs out.
+///
+/// The lease fields are flattened so a stored lease reads like a plain lease.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedLease {
+    #[serde(flatten)]
+    pub lease: NetavarkLease,
+    /// The configuration netavark sent when the lease was set up
+    #[serde(default)]
+    pub network_config: NetworkConfig,
+    /// Unix time in seconds when the lease was obtained or last renewed
+    #[serde(default)]
+    pub obtained_at: u64,
+    /// Unix time in seconds when the lease runs out. None for infinite leases
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Set when the lease ran out without being renewed
+    #[serde(default)]
+    pub expired: bool,
+    /// Uid of the user that owned the network namespace at setup. Kept so the lease can
+    /// be torn down by that user once the namespace is gone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<u32>,
+}
+
+impl CachedLease {
+    pub fn new(lease: NetavarkLease, network_config: NetworkConfig, now: u64) -> Self {
+        // A lease time of 0xffffffff means infinity as per RFC 2131. Zero is used
+        // by leases that never carried the option at all.
+        let expires_at = match lease.lease_time {
+            0 | u32::MAX => None,
+            t => Some(now + u64::from(t)),
+        };
+        CachedLease {
+            lease,
+            network_config,
+            obtained_at: now,
+            expires_at,
+            expired: false,
+            owner: None,
+        }
+    }
+
+    /// Unix time in seconds when the lease should be renewed with the server that
+    /// handed it out (T1). Defaults to half of the lease time.
+    pub fn renew_at(&self) -> Option<u64> {
+        self.timer(self.lease.t1, 1, 2)
+    }
+
+    /// Unix time in seconds when the lease should be rebound with any server (T2).
+    /// Defaults to 7/8 of the lease time.
+    pub fn rebind_at(&self) -> Option<u64> {
+        self.timer(self.lease.t2, 7, 8)
+    }
+
+    /// Whether the lease ran out at the given time
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(e) => now >= e,
+            None => false,
+        }
+    }
+
+    fn timer(&self, value: u32, num: u64, den: u64) -> Option<u64> {
+        let expires_at = self.expires_at?;
+        if value > 0 && value != u32::MAX {
+            return Some(self.obtained_at + u64::from(value));
+        }
+        Some(self.obtained_at + (expires_at - self.obtained_at) * num / den)
+    }
+}
+
+/// The leasing cache holds a in memory record of the leases, and a stored version
+#[derive(Debug)]
+pub struct LeaseCache<S: LeaseStore> {
+    mem: Leases,
+    store: S,
+    // every change to a lease is sent out to the lease watchers
+    events: broadcast::Sender<LeaseEvent>,
+}
+
+impl<S: LeaseStore> LeaseCache<S> {
+    /// Create the cache with the leases that were stored before.
     ///
     /// # Arguments
     ///
-    /// * `writer`: any type that can has the Write and Clear trait implemented. In production this
-    /// is a file. In development/testing this is a Cursor of bytes
+    /// * `store`: any type that has the LeaseStore trait implemented. In production this
+    ///   is a snapshot or journal in a file. In development/testing the file is a Cursor of bytes
     ///
-    /// returns: Result<LeaseCache<W>, Error>
+    /// returns: Result<LeaseCache<S>, Error>
     ///
-    pub fn new(writer: W) -> Result<LeaseCache<W>, io::Error> {
-        Ok(LeaseCache {
-            mem: HashMap::new(),
-            writer,
-        })
+    pub fn new(mut store: S) -> Result<LeaseCache<S>, io::Error> {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let mem = store.load()?;
+        debug!("loaded {} leases", mem.len());
+        Ok(LeaseCache { mem, store, events })
+    }
+
+    /// Subscribe to the events of every lease that is added, renewed, expired or removed
+    pub fn subscribe(&self) -> broadcast::Receiver<LeaseEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send a lease event to the watchers. Only called once the change is written to the
+    /// store, so watchers never see a change that is lost when the proxy restarts.
+    fn notify(&self, kind: LeaseEventKind, key: &LeaseKey, entry: &CachedLease, previous: &str) {
+        let event = LeaseEvent {
+            kind: kind as i32,
+            mac_address: key.mac_address.clone(),
+            host_iface: key.interface.clone(),
+            container_iface: entry.network_config.container_iface.clone(),
+            lease: Some(entry.lease.clone()),
+            timestamp: unix_now(),
+            previous_address: previous.to_string(),
+        };
+        // Sending only fails when nobody is watching
+        let _ = self.events.send(event);
     }
 
     /// Add a new lease to a memory and file system cache
     ///
     /// # Arguments
     ///
-    /// * `mac_addr`: Mac address of the container
+    /// * `key`: Identifies the lease of the container
     /// * `lease`: New lease that should be saved in the cache
+    /// * `network_config`: The configuration the lease was requested for
     ///
     /// returns: Result<(), Error>
     ///
-    pub fn add_lease(&mut self, mac_addr: &str, lease: &NetavarkLease) -> Result<(), io::Error> {
-        debug!("add lease: {:?}", mac_addr);
+    pub fn add_lease(
+        &mut self,
+        key: &LeaseKey,
+        lease: &NetavarkLease,
+        network_config: &NetworkConfig,
+    ) -> Result<(), io::Error> {
+        self.add_owned_lease(key, lease, network_config, None)
+    }
+
+    /// Add a new lease like [`LeaseCache::add_lease`], set up for a network namespace
+    /// owned by the given user
+    pub fn add_owned_lease(
+        &mut self,
+        key: &LeaseKey,
+        lease: &NetavarkLease,
+        network_config: &NetworkConfig,
+        owner: Option<u32>,
+    ) -> Result<(), io::Error> {
+        debug!("add lease: {}", key);
+        let mut entry = CachedLease::new(lease.clone(), network_config.clone(), unix_now());
+        entry.owner = owner;
         // Update cache memory with new lease
         let cache = &mut self.mem;
-        cache.insert(mac_addr.to_string(), vec![lease.clone()]);
-        // write updated memory cache to the file system
-        self.save_memory_to_fs()
+        cache.insert(key.clone(), entry.clone());
+        // write updated memory cache to the store
+        let remembered = self.remembered(key, &entry, false);
+        self.store_lease(key, &remembered)?;
+        self.notify(LeaseEventKind::Acquired, key, &entry, "");
+        Ok(())
     }
 
-    /// When a lease changes, update the lease in memory and on the writer.
+    /// When a lease changes, update the lease in memory and on the writer. The lease
+    /// times start over from now, as is the case after a successful renewal.
     ///
     /// # Arguments
     ///
-    /// * `mac_addr`: Mac address of the container
+    /// * `key`: Identifies the lease of the container
     /// * `lease`: Newest lease information
     ///
     /// returns: Result<(), Error>
     ///
-    pub fn update_lease(&mut self, mac_addr: &str, lease: NetavarkLease) -> Result<(), io::Error> {
-        let cache = &mut self.mem;
+    pub fn update_lease(&mut self, key: &LeaseKey, lease: NetavarkLease) -> Result<(), io::Error> {
+        // keep the network configuration the lease was requested for and its owner
+        let (network_config, previous, owner) = match self.mem.get(key) {
+            Some(l) => (l.network_config.clone(), l.lease.yiaddr.clone(), l.owner),
+            None => (NetworkConfig::default(), String::new(), None),
+        };
+        let mut entry = CachedLease::new(lease, network_config, unix_now());
+        entry.owner = owner;
         // write to the memory cache
-        cache.insert(mac_addr.to_string(), vec![lease]);
-        // write updated memory cache to the file system
-        self.save_memory_to_fs()
+        let cache = &mut self.mem;
+        cache.insert(key.clone(), entry.clone());
+        // write updated memory cache to the store
+        let remembered = self.remembered(key, &entry, true);
+        self.store_lease(key, &remembered)?;
+        if previous.is_empty() || previous == entry.lease.yiaddr {
+            self.notify(LeaseEventKind::Renewed, key, &entry, "");
+        } else {
+            self.notify(LeaseEventKind::AddressChanged, key, &entry, &previous);
+        }
+        Ok(())
+    }
+
+    /// Mark a lease as expired. The lease stays in the cache so it can still be torn
+    /// down, but it is no longer renewed and a new setup will start a fresh DORA.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: Identifies the lease of the container
+    ///
+    /// returns: Result<(), Error>
+    ///
+    pub fn expire_lease(&mut self, key: &LeaseKey) -> Result<(), io::Error> {
+        debug!("expire lease: {}", key);
+        let entry = match self.mem.get_mut(key) {
+            Some(l) => {
+                l.expired = true;
+                l.clone()
+            }
+            None => return Ok(()),
+        };
+        self.store_lease(key, &[])?;
+        self.notify(LeaseEventKind::Expired, key, &entry, "");
+        Ok(())
+    }
+
+    /// Get the cached lease of a container
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: Identifies the lease of the container
+    pub fn get(&self, key: &LeaseKey) -> Option<&CachedLease> {
+        self.mem.get(key)
+    }
+
+    /// Returns a copy of every lease that has not expired yet, with its key.
+    pub fn active_leases(&self) -> Vec<(LeaseKey, CachedLease)> {
+        self.mem
+            .iter()
+            .filter(|(_, e)| !e.expired)
+            .map(|(key, e)| (key.clone(), e.clone()))
+            .collect()
+    }
+
+    /// Returns a copy of every lease, expired or not, with its key.
+    pub fn leases(&self) -> Vec<(LeaseKey, CachedLease)> {
+        self.mem
+            .iter()
+            .map(|(key, e)| (key.clone(), e.clone()))
+            .collect()
+    }
+
+    /// Returns the leases of the given mac address and container. Empty arguments match
+    /// every lease.
+    ///
+    /// # Arguments
+    ///
+    /// * `mac_address`: mac address of the container interface
+    /// * `container_id`: id of the podman container
+    pub fn find(&self, mac_address: &str, container_id: &str) -> Vec<(LeaseKey, CachedLease)> {
+        self.mem
+            .iter()
+            .filter(|(key, _)| {
+                mac_address.is_empty() || key.mac_address.eq_ignore_ascii_case(mac_address)
+            })
+            .filter(|(_, e)| {
+                container_id.is_empty() || e.network_config.container_id == container_id
+            })
+            .map(|(key, e)| (key.clone(), e.clone()))
+            .collect()
+    }
+
+    /// Finds the key of the lease a configuration refers to. Configurations without a
+    /// cached mac address are looked up by container, narrowed down by the network and
+    /// interfaces they carry. None when more than one lease of the container matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `nc`: configuration sent by netavark
+    pub fn lookup(&self, nc: &NetworkConfig) -> Option<LeaseKey> {
+        let key = LeaseKey::from_config(nc);
+        if self.mem.contains_key(&key) {
+            return Some(key);
+        }
+        // A lease migrated from a file that did not store the parent interface
+        let legacy = LeaseKey::new(&key.mac_address, "", key.family);
+        if !key.interface.is_empty() && key.client_id.is_empty() && self.mem.contains_key(&legacy) {
+            return Some(legacy);
+        }
+        if nc.container_id.is_empty() {
+            return Some(key);
+        }
+        let matches = |wanted: &str, cached: &str| wanted.is_empty() || wanted == cached;
+        let mut found = self.mem.iter().filter(|(_, e)| {
+            let cached = &e.network_config;
+            cached.container_id == nc.container_id
+                && matches(&nc.network_name, &cached.network_name)
+                && matches(&nc.host_iface, &cached.host_iface)
+                && matches(&nc.container_iface, &cached.container_iface)
+        });
+        match (found.next(), found.next()) {
+            (Some((found, _)), None) => Some(found.clone()),
+            (None, _) => Some(key),
+            _ => None,
+        }
     }
 
     /// When a singular container is taken down. Remove that lease from the cache memory and fs
     ///
     /// # Arguments
     ///
-    /// * `mac_addr`: Mac address of the container
-    pub fn remove_lease(&mut self, mac_addr: &str) -> Result<Lease, io::Error> {
-        debug!("remove lease: {:?}", mac_addr);
+    /// * `key`: Identifies the lease of the container
+    pub fn remove_lease(&mut self, key: &LeaseKey) -> Result<Lease, io::Error> {
+        debug!("remove lease: {}", key);
         let mem = &mut self.mem;
         // Check and see if the lease exists, if not create an empty one
-        let lease = match mem.get(mac_addr) {
+        let lease = match mem.get(key) {
             None => Lease {
                 t1: 0,
                 t2: 0,
@@ -117,47 +1144,108 @@ impl<W: Write + Clear> LeaseCache<W> {
                 ntp_servers: vec![],
                 host_name: "".to_string(),
             },
-            Some(l) => l[0].clone(),
+            Some(l) => l.lease.clone(),
         };
         // Try and remove the lease. If it doesnt exist, exit with the blank lease
-        if mem.remove(mac_addr).is_none() {
-            return Ok(lease);
-        }
+        let removed = match self.mem.remove(key) {
+            Some(l) => l,
+            None => return Ok(lease),
+        };
 
-        // write updated memory cache to the file system
-        match self.save_memory_to_fs() {
-            Ok(_) => Ok(lease),
-            Err(e) => Err(e),
+        // The address of a released lease is remembered along with the removal, so the
+        // container can ask for it again when it is created again
+        let remembered = if lease.yiaddr.is_empty() {
+            Vec::new()
+        } else {
+            self.remembered(key, &removed, false)
+        };
+        // write updated memory cache to the store
+        if let Err(e) = self.store.remove(key, &remembered, &self.mem) {
+            error!("Could not update lease information: {:?}", e);
+            return Err(e);
         }
+        self.notify(LeaseEventKind::Released, key, &removed, "");
+        Ok(lease)
     }
 
     /// Clean up the memory and file system on tear down of the proxy server
     pub fn teardown(&mut self) -> Result<(), io::Error> {
-        self.mem.clear();
-        self.save_memory_to_fs()
-    }
-
-    /// Save the memory contents to the file system. This will remove the contents in the file,
-    /// then write the memory map to the file. This method will be called any the lease memory cache
-    /// changes (new lease, remove lease, update lease)
-    fn save_memory_to_fs(&mut self) -> io::Result<()> {
-        let mem = &self.mem;
-        let writer = &mut self.writer;
-        // Clear the writer so we can add the old leases
-        match writer.clear() {
-            Ok(_) => {
-                serde_json::to_writer(writer.by_ref(), &mem)?;
-                writer.flush()
-            }
-            Err(e) => {
-                error!(
-                    "Could not clear the writer. Not updating lease information: {:?}",
-                    e
-                );
-                Ok(())
-            }
+        let released: Vec<(LeaseKey, CachedLease)> = self.mem.drain().collect();
+        self.store.clear()?;
+        for (key, l) in released {
+            self.notify(LeaseEventKind::Released, &key, &l, "");
         }
+        Ok(())
     }
+
+    /// The address last handed to the container of a configuration, even when its lease is
+    /// gone. The container is looked up before its mac address, which usually changes when
+    /// a container is created again.
+    ///
+    /// # Arguments
+    ///
+    /// * `nc`: configuration of the container
+    pub fn previous_address(&self, nc: &NetworkConfig) -> Option<String> {
+        let history = self.store.history();
+        Identity::of(&LeaseKey::from_config(nc), nc)
+            .iter()
+            .find_map(|i| history.get(i))
+            .map(|r| r.address.clone())
+    }
+
+    /// The address of a lease to remember for each of its identities
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: Identifies the lease of the container
+    /// * `entry`: the lease, which may already be removed from the cache
+    /// * `only_changes`: skip identities that already remember the address
+    fn remembered(
+        &self,
+        key: &LeaseKey,
+        entry: &CachedLease,
+        only_changes: bool,
+    ) -> Vec<(Identity, AddressRecord)> {
+        let record = AddressRecord {
+            address: entry.lease.yiaddr.clone(),
+            updated_at: unix_now(),
+        };
+        let history = self.store.history();
+        Identity::of(key, &entry.network_config)
+            .into_iter()
+            .filter(|i| {
+                !only_changes || history.get(i).map(|r| &r.address) != Some(&record.address)
+            })
+            .map(|i| (i, record.clone()))
+            .collect()
+    }
+
+    /// Save a lease to the store. This method will be called any time the lease memory
+    /// cache adds or changes a lease (new lease, update lease, expire lease)
+    fn store_lease(
+        &mut self,
+        key: &LeaseKey,
+        remembered: &[(Identity, AddressRecord)],
+    ) -> io::Result<()> {
+        let entry = match self.mem.get(key) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        if let Err(e) = self.store.put(key, entry, remembered, &self.mem) {
+            error!("Could not update lease information: {:?}", e);
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// Returns the number of active leases per parent interface
+    pub fn leases_per_interface(&self) -> HashMap<String, usize> {
+        let mut count = HashMap::new();
+        for (key, _) in self.active_leases() {
+            *count.entry(key.interface).or_insert(0) += 1;
+        }
+        count
+    }
+
     // rust validators require both len and is_empty if you define one
     // of them
     pub fn len(&self) -> usize {
@@ -173,12 +1261,18 @@ impl<W: Write + Clear> LeaseCache<W> {
 
 #[cfg(test)]
 mod cache_tests {
-    use crate::cache::LeaseCache;
-    use crate::g_rpc::{Lease as NetavarkLease, Lease};
+    use crate::cache::{
+        add_to_history, AddressHistory, AddressRecord, AtomicFile, CachedLease, Identity, IpFamily,
+        JournalStore, LeaseCache, LeaseKey, LeaseStore, Persist, SchemaHeader, SnapshotStore,
+        HISTORY_CAPACITY, JOURNAL_COMPACT_MIN, LEASE_SCHEMA_VERSION,
+    };
+    use crate::g_rpc::{Lease as NetavarkLease, Lease, LeaseEventKind, NetworkConfig, Version};
     use macaddr::MacAddr6;
     use rand::{thread_rng, Rng};
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
     use std::collections::HashMap;
-    use std::io::Cursor;
+    use std::io::{self, Cursor};
 
     // Create a single random ipv4 addr
     fn random_ipv4() -> String {
@@ -191,8 +1285,8 @@ mod cache_tests {
             rng.gen_range(0..255)
         )
     }
-    // Create a single random mac address
-    fn random_macaddr() -> MacAddr6 {
+    // Create a single random mac address, in lower case as netavark sends it
+    fn random_macaddr() -> String {
         let mut rng = thread_rng();
         MacAddr6::new(
             rng.gen::<u8>(),
@@ -202,6 +1296,27 @@ mod cache_tests {
             rng.gen::<u8>(),
             rng.gen::<u8>(),
         )
+        .to_string()
+        .to_ascii_lowercase()
+    }
+    // Key of a v4 lease on the default network configuration
+    fn key(mac_address: &str) -> LeaseKey {
+        LeaseKey::new(mac_address, "", IpFamily::V4)
+    }
+    // Read the leases per mac address from the contents of a lease file
+    fn stored_leases<T: DeserializeOwned>(
+        contents: &[u8],
+    ) -> serde_json::Result<HashMap<String, Vec<T>>> {
+        let file: Value = serde_json::from_slice(contents)?;
+        let mut leases = HashMap::new();
+        for stored in file["leases"].as_array().into_iter().flatten() {
+            let mac_address = stored["key"]["mac_address"].as_str().unwrap_or_default();
+            leases
+                .entry(mac_address.to_string())
+                .or_insert_with(Vec::new)
+                .push(T::deserialize(&stored["lease"])?);
+        }
+        Ok(leases)
     }
     // Create a single random lease
     fn random_lease(mac_address: &String) -> Lease {
@@ -226,7 +1341,7 @@ mod cache_tests {
     }
     // Shared information for all tests
     struct CacheTestSetup {
-        cache: LeaseCache<Cursor<Vec<u8>>>,
+        cache: LeaseCache<SnapshotStore<Cursor<Vec<u8>>>>,
         macaddrs: Vec<String>,
         range: u8,
     }
@@ -235,7 +1350,7 @@ mod cache_tests {
         fn new() -> Self {
             // Use byte Cursor instead of file for testing
             let buff = Cursor::new(Vec::new());
-            let cache = match LeaseCache::new(buff) {
+            let cache = match LeaseCache::new(SnapshotStore::new(buff)) {
                 Ok(cache) => cache,
                 Err(e) => panic!("Could not create leases cache: {e:?}"),
             };
@@ -262,18 +1377,18 @@ mod cache_tests {
 
         for i in 0..range {
             // Create a random mac address to create a random lease of that mac address
-            let mac_address = random_macaddr().to_string();
+            let mac_address = random_macaddr();
             macaddrs.push(mac_address.clone());
             let lease = random_lease(&mac_address);
 
             // Add the lease to the cache
             cache
-                .add_lease(&mac_address, &lease)
+                .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
                 .expect("could not add lease to cache");
 
             // Deserialize the written bytes to compare
-            let lease_bytes = cache.writer.get_ref().as_slice();
-            let s: HashMap<String, Vec<NetavarkLease>> = match serde_json::from_slice(lease_bytes) {
+            let lease_bytes = cache.store.writer.get_ref().as_slice();
+            let s: HashMap<String, Vec<NetavarkLease>> = match stored_leases(lease_bytes) {
                 Ok(s) => s,
                 Err(e) => panic!("Error: {e:?}"),
             };
@@ -305,18 +1420,18 @@ mod cache_tests {
         let range = setup.range;
         for i in 0..range {
             // Create a random mac address to create a random lease of that mac address
-            let mac_address = random_macaddr().to_string();
+            let mac_address = random_macaddr();
             macaddrs.push(mac_address.clone());
             let lease = random_lease(&mac_address);
 
             // Add the lease to the cache
             cache
-                .add_lease(&mac_address, &lease)
+                .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
                 .expect("could not add lease to cache");
 
             // Deserialize the written bytes to compare
-            let lease_bytes = cache.writer.get_ref().as_slice();
-            let s: HashMap<String, Vec<NetavarkLease>> = match serde_json::from_slice(lease_bytes) {
+            let lease_bytes = cache.store.writer.get_ref().as_slice();
+            let s: HashMap<String, Vec<NetavarkLease>> = match stored_leases(lease_bytes) {
                 Ok(s) => s,
                 Err(e) => panic!("Error: {e:?}"),
             };
@@ -340,8 +1455,8 @@ mod cache_tests {
         }
         for i in 0..range {
             // Deserialize the written bytes to compare
-            let lease_bytes = cache.writer.get_ref().as_slice();
-            let s: HashMap<String, Vec<NetavarkLease>> = match serde_json::from_slice(lease_bytes) {
+            let lease_bytes = cache.store.writer.get_ref().as_slice();
+            let s: HashMap<String, Vec<NetavarkLease>> = match stored_leases(lease_bytes) {
                 Ok(s) => s,
                 Err(e) => panic!("Error: {e:?}"),
             };
@@ -358,15 +1473,15 @@ mod cache_tests {
                 .clone();
 
             let removed_lease = cache
-                .remove_lease(macaddr)
+                .remove_lease(&key(macaddr))
                 .unwrap_or_else(|_| panic!("Could not remove {macaddr:?} from leases"));
             // Assure the lease is no longer in memory
             assert_eq!(deserialized_lease, removed_lease);
             assert_eq!(s.len(), (range - i) as usize);
 
             // Deserialize the cache again to assure the lease is not in the writer
-            let lease_bytes = cache.writer.get_ref().as_slice();
-            let s: HashMap<String, Vec<NetavarkLease>> = match serde_json::from_slice(lease_bytes) {
+            let lease_bytes = cache.store.writer.get_ref().as_slice();
+            let s: HashMap<String, Vec<NetavarkLease>> = match stored_leases(lease_bytes) {
                 Ok(s) => s,
                 Err(e) => panic!("Error: {e:?}"),
             };
@@ -376,7 +1491,7 @@ mod cache_tests {
 
             // Remove a lease that does not exist
             let removed_lease = cache
-                .remove_lease(macaddr)
+                .remove_lease(&key(macaddr))
                 .expect("Could not remove the lease successfully");
             // The returned lease should be a blank one
             assert_eq!(removed_lease.mac_address, "".to_string());
@@ -392,18 +1507,18 @@ mod cache_tests {
 
         for i in 0..range {
             // Create a random mac address to create a random lease of that mac address
-            let mac_address = random_macaddr().to_string();
+            let mac_address = random_macaddr();
             macaddrs.push(mac_address.clone());
             let lease = random_lease(&mac_address);
 
             // Add the lease to the cache
             cache
-                .add_lease(&mac_address, &lease)
+                .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
                 .expect("could not add lease to cache");
 
             // Deserialize the written bytes to compare
-            let lease_bytes = cache.writer.get_ref().as_slice();
-            let s: HashMap<String, Vec<NetavarkLease>> = match serde_json::from_slice(lease_bytes) {
+            let lease_bytes = cache.store.writer.get_ref().as_slice();
+            let s: HashMap<String, Vec<NetavarkLease>> = match stored_leases(lease_bytes) {
                 Ok(s) => s,
                 Err(e) => panic!("Error: {e:?}"),
             };
@@ -436,12 +1551,12 @@ mod cache_tests {
             let new_lease = random_lease(macaddr);
 
             cache
-                .update_lease(macaddr, new_lease.clone())
+                .update_lease(&key(macaddr), new_lease.clone())
                 .expect("Could not update the lease");
 
             // Deserialize the cache again to assure the lease is not in the writer
-            let lease_bytes = cache.writer.get_ref().as_slice();
-            let s: HashMap<String, Vec<NetavarkLease>> = match serde_json::from_slice(lease_bytes) {
+            let lease_bytes = cache.store.writer.get_ref().as_slice();
+            let s: HashMap<String, Vec<NetavarkLease>> = match stored_leases(lease_bytes) {
                 Ok(s) => s,
                 Err(e) => panic!("Error: {e:?}"),
             };
@@ -455,4 +1570,664 @@ mod cache_tests {
             assert_eq!(deserialized_updated_lease, &new_lease);
         }
     }
+
+    #[test]
+    fn owner_is_kept() {
+        let mut cache = CacheTestSetup::new().cache;
+        let mac_address = random_macaddr();
+        let key = key(&mac_address);
+        cache
+            .add_owned_lease(
+                &key,
+                &random_lease(&mac_address),
+                &NetworkConfig::default(),
+                Some(1000),
+            )
+            .expect("could not add lease to cache");
+        cache
+            .update_lease(&key, random_lease(&mac_address))
+            .expect("Could not update the lease");
+        assert_eq!(cache.get(&key).expect("lease is gone").owner, Some(1000));
+    }
+
+    #[test]
+    fn lease_timers() {
+        let mut lease = random_lease(&random_macaddr());
+        lease.lease_time = 3600;
+        lease.t1 = 0;
+        lease.t2 = 0;
+        let entry = CachedLease::new(lease.clone(), NetworkConfig::default(), 1000);
+        // Without t1 and t2 from the server the RFC 2131 defaults are used
+        assert_eq!(entry.expires_at, Some(4600));
+        assert_eq!(entry.renew_at(), Some(2800));
+        assert_eq!(entry.rebind_at(), Some(4150));
+
+        lease.t1 = 1000;
+        lease.t2 = 2000;
+        let entry = CachedLease::new(lease.clone(), NetworkConfig::default(), 1000);
+        assert_eq!(entry.renew_at(), Some(2000));
+        assert_eq!(entry.rebind_at(), Some(3000));
+        assert!(!entry.is_expired_at(4599));
+        assert!(entry.is_expired_at(4600));
+
+        // Infinite leases never expire nor need to be renewed
+        lease.lease_time = u32::MAX;
+        let entry = CachedLease::new(lease, NetworkConfig::default(), 1000);
+        assert_eq!(entry.expires_at, None);
+        assert_eq!(entry.renew_at(), None);
+        assert!(!entry.is_expired_at(u64::MAX));
+    }
+
+    #[test]
+    fn expire_leases() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        cache
+            .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
+            .expect("could not add lease to cache");
+        assert_eq!(cache.active_leases().len(), 1);
+
+        cache
+            .expire_lease(&key(&mac_address))
+            .expect("could not expire lease");
+        // The lease is kept until teardown, but it is no longer active
+        assert_eq!(cache.len(), 1);
+        assert!(cache.active_leases().is_empty());
+        assert!(
+            cache
+                .get(&key(&mac_address))
+                .expect("lease is gone")
+                .expired
+        );
+
+        // The expired flag is written out with the lease
+        let lease_bytes = cache.store.writer.get_ref().as_slice();
+        let s: HashMap<String, Vec<CachedLease>> = match stored_leases(lease_bytes) {
+            Ok(s) => s,
+            Err(e) => panic!("Error: {e:?}"),
+        };
+        assert!(s.get(&mac_address).expect("lease not written")[0].expired);
+
+        // A new lease for the same container starts fresh
+        cache
+            .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
+            .expect("could not add lease to cache");
+        assert_eq!(cache.active_leases().len(), 1);
+    }
+
+    #[test]
+    fn lease_events() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let mut events = cache.subscribe();
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        cache
+            .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
+            .expect("could not add lease to cache");
+        cache
+            .update_lease(&key(&mac_address), lease.clone())
+            .expect("could not update lease");
+        let mut new_lease = lease.clone();
+        new_lease.yiaddr = random_ipv4();
+        cache
+            .update_lease(&key(&mac_address), new_lease.clone())
+            .expect("could not update lease");
+        cache
+            .expire_lease(&key(&mac_address))
+            .expect("could not expire lease");
+        cache
+            .remove_lease(&key(&mac_address))
+            .expect("could not remove lease");
+
+        let expected = [
+            LeaseEventKind::Acquired,
+            LeaseEventKind::Renewed,
+            LeaseEventKind::AddressChanged,
+            LeaseEventKind::Expired,
+            LeaseEventKind::Released,
+        ];
+        for kind in expected {
+            let event = events.try_recv().expect("missing lease event");
+            assert_eq!(event.kind, kind as i32);
+            assert_eq!(event.mac_address, mac_address);
+            if kind == LeaseEventKind::AddressChanged {
+                assert_eq!(event.previous_address, lease.yiaddr);
+                assert_eq!(event.lease, Some(new_lease.clone()));
+            }
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    // Contents that can not be written, like a lease file on a full disk
+    struct Unwritable;
+
+    impl Persist for Unwritable {
+        fn contents(&mut self) -> io::Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn persist(&mut self, _: &[u8]) -> io::Result<()> {
+            Err(io::Error::other("no space left"))
+        }
+
+        fn append(&mut self, contents: &[u8]) -> io::Result<()> {
+            self.persist(contents)
+        }
+    }
+
+    #[test]
+    fn no_events_for_unwritten_leases() {
+        let mut cache =
+            LeaseCache::new(SnapshotStore::new(Unwritable)).expect("could not create cache");
+        let mut events = cache.subscribe();
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        assert!(cache
+            .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
+            .is_err());
+        assert!(cache.remove_lease(&key(&mac_address)).is_err());
+        assert!(events.try_recv().is_err());
+    }
+
+    // Contents that count how often they are written
+    #[derive(Default)]
+    struct Counted {
+        contents: Cursor<Vec<u8>>,
+        writes: usize,
+    }
+
+    impl Persist for Counted {
+        fn contents(&mut self) -> io::Result<Vec<u8>> {
+            self.contents.contents()
+        }
+
+        fn persist(&mut self, contents: &[u8]) -> io::Result<()> {
+            self.writes += 1;
+            self.contents.persist(contents)
+        }
+
+        fn append(&mut self, contents: &[u8]) -> io::Result<()> {
+            self.writes += 1;
+            self.contents.append(contents)
+        }
+    }
+
+    #[test]
+    fn one_write_per_change() {
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        let nc = NetworkConfig {
+            container_id: "d3b07384d113".to_string(),
+            ..Default::default()
+        };
+        let mut cache = LeaseCache::new(SnapshotStore::new(Counted::default()))
+            .expect("could not create cache");
+        // the lease is written along with the address of both of its identities
+        cache
+            .add_lease(&key(&mac_address), &lease, &nc)
+            .expect("could not add lease");
+        assert_eq!(cache.store.writer.writes, 1);
+        assert_eq!(cache.store.history().len(), 2);
+        cache
+            .remove_lease(&key(&mac_address))
+            .expect("could not remove lease");
+        assert_eq!(cache.store.writer.writes, 2);
+
+        let mut journal =
+            LeaseCache::new(JournalStore::new(Counted::default())).expect("could not create cache");
+        let loaded = journal.store.writer.writes;
+        journal
+            .add_lease(&key(&mac_address), &lease, &nc)
+            .expect("could not add lease");
+        assert_eq!(journal.store.writer.writes, loaded + 1);
+        journal
+            .remove_lease(&key(&mac_address))
+            .expect("could not remove lease");
+        assert_eq!(journal.store.writer.writes, loaded + 2);
+        // a record is still written per change and per remembered address
+        assert_eq!(journal.store.records, 6);
+    }
+
+    #[test]
+    fn atomic_file() {
+        let dir = std::env::temp_dir().join(format!("nv-proxy-test-{}", random_macaddr()));
+        std::fs::create_dir_all(&dir).expect("could not create test dir");
+        let path = dir.join("nv-proxy.lease");
+
+        let mut cache = LeaseCache::new(SnapshotStore::new(
+            AtomicFile::create(&path, b"{}").expect("could not create lease file"),
+        ))
+        .expect("could not create cache");
+        assert_eq!(
+            std::fs::read(&path).expect("could not read lease file"),
+            b"{}"
+        );
+
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        cache
+            .add_lease(&key(&mac_address), &lease, &NetworkConfig::default())
+            .expect("could not add lease");
+        let contents = std::fs::read(cache.store.writer.path()).expect("could not read lease file");
+        let s: HashMap<String, Vec<Lease>> =
+            stored_leases(&contents).expect("Could not read lease file");
+        assert_eq!(s[&mac_address][0], lease);
+
+        // Only the lease file is left behind
+        let entries: Vec<_> = std::fs::read_dir(&dir)
+            .expect("could not read test dir")
+            .collect();
+        assert_eq!(entries.len(), 1);
+        std::fs::remove_dir_all(&dir).expect("could not remove test dir");
+    }
+
+    #[test]
+    fn snapshot_reload() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let macaddrs: Vec<String> = (0..setup.range + 1).map(|_| random_macaddr()).collect();
+        for mac_address in &macaddrs {
+            let lease = random_lease(mac_address);
+            cache
+                .add_lease(&key(mac_address), &lease, &NetworkConfig::default())
+                .expect("could not add lease");
+        }
+        cache
+            .remove_lease(&key(&macaddrs[0]))
+            .expect("could not remove lease");
+
+        let buff = Cursor::new(cache.store.writer.get_ref().clone());
+        let reloaded = LeaseCache::new(SnapshotStore::new(buff)).expect("could not load cache");
+        assert_eq!(reloaded.mem, cache.mem);
+        assert!(reloaded.get(&key(&macaddrs[0])).is_none());
+    }
+
+    #[test]
+    fn sticky_addresses() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let mac_address = random_macaddr();
+        let mut nc = NetworkConfig {
+            host_iface: "eth0".to_string(),
+            container_mac_addr: mac_address.clone(),
+            container_id: "d3b07384d113".to_string(),
+            network_name: "podman1".to_string(),
+            ..Default::default()
+        };
+        let lease = random_lease(&mac_address);
+        let k = LeaseKey::from_config(&nc);
+        cache
+            .add_lease(&k, &lease, &nc)
+            .expect("could not add lease");
+        cache.remove_lease(&k).expect("could not remove lease");
+        cache.teardown().expect("could not tear down cache");
+
+        // The address outlives the lease and the proxy
+        let buff = Cursor::new(cache.store.writer.get_ref().clone());
+        let reloaded = LeaseCache::new(SnapshotStore::new(buff)).expect("could not load cache");
+        assert!(reloaded.is_empty());
+        assert_eq!(reloaded.previous_address(&nc), Some(lease.yiaddr.clone()));
+        // A container created again gets a new mac address
+        nc.container_mac_addr = random_macaddr();
+        assert_eq!(reloaded.previous_address(&nc), Some(lease.yiaddr.clone()));
+        nc.network_name = "podman2".to_string();
+        assert_eq!(reloaded.previous_address(&nc), None);
+
+        let mut history = AddressHistory::new();
+        for i in 0..HISTORY_CAPACITY + 1 {
+            let identity = Identity::Mac(key(&i.to_string()));
+            let record = AddressRecord {
+                address: lease.yiaddr.clone(),
+                updated_at: i as u64,
+            };
+            add_to_history(&mut history, identity, record);
+        }
+        // The oldest address is forgotten first
+        assert_eq!(history.len(), HISTORY_CAPACITY);
+        assert!(!history.contains_key(&Identity::Mac(key("0"))));
+    }
+
+    #[test]
+    fn journal_replay() {
+        let mut cache = LeaseCache::new(JournalStore::new(Cursor::new(Vec::new())))
+            .expect("could not create cache");
+        let macaddrs: Vec<String> = (0..5).map(|_| random_macaddr()).collect();
+        for mac_address in &macaddrs {
+            let lease = random_lease(mac_address);
+            cache
+                .add_lease(&key(mac_address), &lease, &NetworkConfig::default())
+                .expect("could not add lease");
+        }
+        let new_lease = random_lease(&macaddrs[1]);
+        cache
+            .update_lease(&key(&macaddrs[1]), new_lease.clone())
+            .expect("could not update lease");
+        cache
+            .remove_lease(&key(&macaddrs[0]))
+            .expect("could not remove lease");
+        // one record per change, and one per address that is remembered
+        assert_eq!(cache.store.records, 14);
+
+        // An incomplete last record is dropped on replay
+        let mut journal = cache.store.writer.get_ref().clone();
+        journal.extend_from_slice(b"{\"op\":\"remove\",\"mac_add");
+        let replayed = LeaseCache::new(JournalStore::new(Cursor::new(journal)))
+            .expect("could not replay journal");
+        assert_eq!(replayed.mem, cache.mem);
+        assert!(replayed.get(&key(&macaddrs[0])).is_none());
+        assert_eq!(
+            replayed
+                .get(&key(&macaddrs[1]))
+                .expect("missing lease")
+                .lease,
+            new_lease
+        );
+        // the replayed journal is compacted to a record per lease and remembered address
+        assert_eq!(replayed.store.history(), cache.store.history());
+        assert_eq!(replayed.store.records, 9);
+    }
+
+    #[test]
+    fn legacy_journal_replay() {
+        let record = |op: &str, mac_address: &str, lease: Option<&Lease>| {
+            let mut line = serde_json::json!({ "op": op, "mac_address": mac_address });
+            if let Some(l) = lease {
+                let entry = CachedLease::new(l.clone(), NetworkConfig::default(), 0);
+                line["leases"] = serde_json::json!([entry]);
+            }
+            let mut line = serde_json::to_vec(&line).expect("could not write record");
+            line.push(b'\n');
+            line
+        };
+        // Netavark may have sent the mac addresses in upper case
+        let removed = "AA:BB:CC:DD:EE:01";
+        let replaced = "AA:BB:CC:DD:EE:02";
+        let first = random_lease(&replaced.to_string());
+        let second = random_lease(&replaced.to_string());
+        let journal = [
+            record("put", removed, Some(&random_lease(&removed.to_string()))),
+            record("remove", removed, None),
+            record("put", replaced, Some(&first)),
+            record("put", replaced, Some(&second)),
+        ]
+        .concat();
+        let replayed = LeaseCache::new(JournalStore::new(Cursor::new(journal)))
+            .expect("could not replay journal");
+        assert!(replayed.get(&key(removed)).is_none());
+        assert_eq!(replayed.mem.len(), 1);
+        assert_eq!(
+            replayed.get(&key(replaced)).expect("missing lease").lease,
+            second
+        );
+    }
+
+    #[test]
+    fn journal_compaction() {
+        let mut store = JournalStore::new(Cursor::new(Vec::new()));
+        let mut leases = HashMap::new();
+        let mac_address = random_macaddr();
+        let entry = CachedLease::new(random_lease(&mac_address), NetworkConfig::default(), 0);
+        leases.insert(key(&mac_address), entry.clone());
+        for _ in 0..JOURNAL_COMPACT_MIN - 1 {
+            store
+                .put(&key(&mac_address), &entry, &[], &leases)
+                .expect("could not store lease");
+        }
+        assert_eq!(store.records, JOURNAL_COMPACT_MIN - 1);
+        store
+            .put(&key(&mac_address), &entry, &[], &leases)
+            .expect("could not store lease");
+        assert_eq!(store.records, 1);
+        assert_eq!(store.load().expect("could not load journal"), leases);
+    }
+
+    #[test]
+    fn migrate_unversioned_file() {
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        // A lease file as written before it had a schema version
+        let mut old = HashMap::new();
+        old.insert(mac_address.clone(), vec![lease.clone()]);
+        let buff = Cursor::new(serde_json::to_vec(&old).expect("could not serialize leases"));
+
+        let mut cache = LeaseCache::new(SnapshotStore::new(buff)).expect("could not load cache");
+        assert_eq!(
+            cache.get(&key(&mac_address)).expect("missing lease").lease,
+            lease
+        );
+        cache
+            .expire_lease(&key(&mac_address))
+            .expect("could not expire lease");
+
+        // The next write uses the current schema
+        let file: Value = serde_json::from_slice(cache.store.writer.get_ref())
+            .expect("could not read lease file");
+        assert_eq!(file["schema_version"], LEASE_SCHEMA_VERSION);
+        assert_eq!(file["writer_version"], env!("CARGO_PKG_VERSION"));
+        assert!(file["created_at"].is_u64());
+        let s: HashMap<String, Vec<Lease>> =
+            stored_leases(cache.store.writer.get_ref()).expect("could not read leases");
+        assert_eq!(s[&mac_address][0], lease);
+    }
+
+    #[test]
+    fn refuse_newer_schema() {
+        let mut header = SchemaHeader::new(0);
+        header.schema_version = LEASE_SCHEMA_VERSION + 1;
+        let mut snapshot = serde_json::to_value(&header).expect("could not serialize header");
+        snapshot["leases"] = Value::Object(Default::default());
+        let buff = Cursor::new(serde_json::to_vec(&snapshot).expect("could not serialize file"));
+        assert!(LeaseCache::new(SnapshotStore::new(buff)).is_err());
+
+        let mut journal = serde_json::to_value(&header).expect("could not serialize header");
+        journal["op"] = Value::from("header");
+        let buff = Cursor::new(serde_json::to_vec(&journal).expect("could not serialize journal"));
+        assert!(LeaseCache::new(JournalStore::new(buff)).is_err());
+    }
+
+    #[test]
+    fn leases_per_key() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let mac_address = random_macaddr();
+        let mut nc = NetworkConfig {
+            host_iface: "eth0".to_string(),
+            container_mac_addr: mac_address.clone(),
+            ..Default::default()
+        };
+        let v4 = LeaseKey::from_config(&nc);
+        nc.version = Version::V6 as i32;
+        let v6 = LeaseKey::from_config(&nc);
+        nc.host_iface = "eth1".to_string();
+        let other_iface = LeaseKey::from_config(&nc);
+        assert_eq!(v4, LeaseKey::new(&mac_address, "eth0", IpFamily::V4));
+        assert_eq!(v6.family, IpFamily::V6);
+
+        // The same mac address holds a lease per family and parent interface
+        for k in [&v4, &v6, &other_iface] {
+            cache
+                .add_lease(k, &random_lease(&mac_address), &nc)
+                .expect("could not add lease");
+        }
+        assert_eq!(cache.len(), 3);
+
+        let removed = cache.get(&v6).expect("missing lease").lease.clone();
+        assert_eq!(
+            cache.remove_lease(&v6).expect("could not remove lease"),
+            removed
+        );
+        assert!(cache.get(&v4).is_some());
+        assert!(cache.get(&other_iface).is_some());
+        let s: HashMap<String, Vec<NetavarkLease>> =
+            stored_leases(cache.store.writer.get_ref()).expect("could not read leases");
+        assert_eq!(s[&mac_address].len(), 2);
+    }
+
+    #[test]
+    fn lookup_by_container() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let container_id = "d3b07384d113";
+        let mut keys = Vec::new();
+        for network in ["podman1", "podman2"] {
+            let mac_address = random_macaddr();
+            let nc = NetworkConfig {
+                host_iface: "eth0".to_string(),
+                container_mac_addr: mac_address.clone(),
+                container_id: container_id.to_string(),
+                network_name: network.to_string(),
+                ..Default::default()
+            };
+            let k = LeaseKey::from_config(&nc);
+            cache
+                .add_lease(&k, &random_lease(&mac_address), &nc)
+                .expect("could not add lease");
+            keys.push(k);
+        }
+        assert_eq!(cache.find("", container_id).len(), 2);
+        assert_eq!(cache.find(&keys[0].mac_address, container_id).len(), 1);
+        assert!(cache.find("", "unknown").is_empty());
+
+        // A container with two leases needs the network to pick one
+        let mut nc = NetworkConfig {
+            container_id: container_id.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(cache.lookup(&nc), None);
+        nc.network_name = "podman2".to_string();
+        assert_eq!(cache.lookup(&nc), Some(keys[1].clone()));
+
+        // Configurations written before the container fields existed still load
+        let old: NetworkConfig =
+            serde_json::from_str(r#"{"host_iface": "eth0", "container_mac_addr": "aa"}"#)
+                .expect("could not parse config");
+        assert!(old.container_id.is_empty());
+        assert_eq!(
+            cache.lookup(&old),
+            Some(LeaseKey::new("aa", "eth0", IpFamily::V4))
+        );
+    }
+
+    #[test]
+    fn ipvlan_keys() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        // Containers on ipvlan links all have the mac address of the parent
+        let mac_address = random_macaddr();
+        let mut keys = Vec::new();
+        for container_id in ["d3b07384d113", "c157a79031e1"] {
+            let nc = NetworkConfig {
+                host_iface: "eth0".to_string(),
+                container_iface: "eth1".to_string(),
+                container_mac_addr: mac_address.clone(),
+                container_id: container_id.to_string(),
+                ipvlan: true,
+                ..Default::default()
+            };
+            let k = LeaseKey::from_config(&nc);
+            assert_eq!(k.client_id, format!("{container_id}/eth1"));
+            cache
+                .add_lease(&k, &random_lease(&mac_address), &nc)
+                .expect("could not add lease");
+            keys.push(k);
+        }
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(cache.find(&mac_address, "").len(), 2);
+
+        let stored = serde_json::to_string(&keys[0]).expect("could not serialize key");
+        let loaded: LeaseKey = serde_json::from_str(&stored).expect("could not parse key");
+        assert_eq!(loaded, keys[0]);
+        // Keys by mac address are stored as before
+        let mac_key = LeaseKey::new(&mac_address, "eth0", IpFamily::V4);
+        let stored = serde_json::to_string(&mac_key).expect("could not serialize key");
+        assert!(!stored.contains("client_id"));
+    }
+
+    #[test]
+    fn mac_address_case() {
+        let setup = CacheTestSetup::new();
+        let mut cache = setup.cache;
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        let nc = NetworkConfig {
+            container_mac_addr: mac_address.to_ascii_uppercase(),
+            host_iface: "eth0".to_string(),
+            ..Default::default()
+        };
+        cache
+            .add_lease(&LeaseKey::from_config(&nc), &lease, &nc)
+            .expect("could not add lease to cache");
+        let k = LeaseKey::new(&mac_address, "eth0", IpFamily::V4);
+        assert_eq!(k, LeaseKey::from_config(&nc));
+        assert_eq!(cache.get(&k).map(|e| &e.lease), Some(&lease));
+        assert_eq!(cache.find(&nc.container_mac_addr, "").len(), 1);
+    }
+
+    #[test]
+    fn lookup_legacy_lease() {
+        let mac_address = random_macaddr();
+        let lease = random_lease(&mac_address);
+        // Leases written before the network configuration was stored with them
+        let mut old = HashMap::new();
+        old.insert(mac_address.clone(), vec![lease.clone()]);
+        let buff = Cursor::new(serde_json::to_vec(&old).expect("could not serialize leases"));
+        let cache = LeaseCache::new(SnapshotStore::new(buff)).expect("could not load cache");
+
+        let nc = NetworkConfig {
+            container_mac_addr: mac_address.clone(),
+            host_iface: "eth0".to_string(),
+            ..Default::default()
+        };
+        let found = cache.lookup(&nc).expect("lease not found");
+        assert_eq!(found, key(&mac_address));
+        assert_eq!(cache.get(&found).map(|e| &e.lease), Some(&lease));
+    }
+
+    #[test]
+    fn migrate_mac_keyed_journal() {
+        let mac_address = random_macaddr();
+        let nc = NetworkConfig {
+            host_iface: "eth0".to_string(),
+            ..Default::default()
+        };
+        let entry = CachedLease::new(random_lease(&mac_address), nc, 0);
+        let removed_mac = random_macaddr();
+        // A journal of schema version 1, which kept the leases per mac address
+        let mut header = SchemaHeader::new(0);
+        header.schema_version = 1;
+        let mut journal = serde_json::to_value(&header).expect("could not serialize header");
+        journal["op"] = Value::from("header");
+        let records = [
+            journal,
+            serde_json::json!({"op": "put", "mac_address": mac_address, "leases": [entry]}),
+            serde_json::json!({"op": "put", "mac_address": removed_mac, "leases": [entry]}),
+            serde_json::json!({"op": "remove", "mac_address": removed_mac}),
+        ];
+        let mut contents = Vec::new();
+        for r in records {
+            contents.extend(serde_json::to_vec(&r).expect("could not serialize record"));
+            contents.push(b'\n');
+        }
+
+        let cache = LeaseCache::new(JournalStore::new(Cursor::new(contents)))
+            .expect("could not replay journal");
+        assert_eq!(cache.len(), 1);
+        let k = LeaseKey::new(&mac_address, "eth0", IpFamily::V4);
+        assert_eq!(cache.get(&k), Some(&entry));
+
+        // The journal is compacted with the current schema
+        let first_line = cache
+            .store
+            .writer
+            .get_ref()
+            .split(|b| *b == b'\n')
+            .next()
+            .map(|l| l.to_vec())
+            .unwrap_or_default();
+        let header: Value = serde_json::from_slice(&first_line).expect("missing header");
+        assert_eq!(header["schema_version"], LEASE_SCHEMA_VERSION);
+    }
 }
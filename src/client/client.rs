@@ -1,12 +1,16 @@
 use clap::{Parser, Subcommand};
-use commands::{setup, teardown};
+use commands::{health, inspect, setup, teardown, version, watch};
+use output::Format;
 use std::process;
+use std::time::Duration;
 use tonic::{Code, Status};
 
-use netavark_proxy::g_rpc::{Lease, NetworkConfig};
+use netavark_proxy::proxy_client::ClientOptions;
 use netavark_proxy::proxy_conf::{DEFAULT_NETWORK_CONFIG, DEFAULT_UDS_PATH};
+use netavark_proxy::{ClientTls, ProxyClient, ProxyEndpoint};
 
 pub mod commands;
+pub mod output;
 
 #[derive(Parser, Debug)]
 #[clap(version = env!("CARGO_PKG_VERSION"))]
@@ -14,9 +18,28 @@ struct Opts {
     /// Use specific uds path
     #[clap(short, long)]
     uds: Option<String>,
+    /// Connect to the TCP listener of the proxy at this https://host:port url
+    #[clap(short, long, conflicts_with = "uds", requires_all = &["tls-ca", "tls-cert", "tls-key"])]
+    endpoint: Option<String>,
+    /// CA certificate to verify the proxy with, in PEM format
+    #[clap(long, requires = "endpoint")]
+    tls_ca: Option<String>,
+    /// Client certificate to present to the proxy, in PEM format
+    #[clap(long, requires = "endpoint")]
+    tls_cert: Option<String>,
+    /// Private key of the client certificate, in PEM format
+    #[clap(long, requires = "endpoint")]
+    tls_key: Option<String>,
     /// Instead of reading from STDIN, read the configuration to be applied from the given file.
+    /// Not read when the configuration is given on the command line.
     #[clap(short, long)]
     file: Option<String>,
+    /// Seconds to wait for the proxy to answer, by default it is waited for as long as it takes
+    #[clap(short, long)]
+    timeout: Option<u64>,
+    /// Print the results as json, as a table or as shell variables to eval
+    #[clap(long, arg_enum, global = true, default_value = "json")]
+    format: Format,
     /// Netavark trig command
     #[clap(subcommand)]
     subcmd: SubCommand,
@@ -28,8 +51,14 @@ enum SubCommand {
     Setup(setup::Setup),
     /// Undo any configuration applied via setup command.
     Teardown(teardown::Teardown),
-    // Display info about netavark.
-    // Version(version::Version),
+    /// Print the cached leases with the configuration they were set up for.
+    Inspect(inspect::Inspect),
+    /// Print lease events as json lines until interrupted.
+    Watch(watch::Watch),
+    /// Check if the proxy is able to serve requests, exits non-zero when it is not.
+    Health(health::Health),
+    /// Print the version of the client and the version and capabilities of the proxy.
+    Version(version::Version),
 }
 
 #[cfg(unix)]
@@ -39,42 +68,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // This should be moved to somewhere central.  We also need to add override logic.
     env_logger::builder().format_timestamp(None).init();
     let opts = Opts::parse();
+    if let Err(e) = run(opts).await {
+        output::print_error(&e);
+        process_failure(e);
+    }
+    Ok(())
+}
+
+async fn run(opts: Opts) -> Result<(), Status> {
     let file = opts
         .file
         .unwrap_or_else(|| DEFAULT_NETWORK_CONFIG.to_string());
-    let uds_path = opts.uds.unwrap_or_else(|| DEFAULT_UDS_PATH.to_string());
-    let input_config = NetworkConfig::load(&file)?;
-    let result = match opts.subcmd {
-        SubCommand::Setup(_) => {
-            let s = setup::Setup::new(input_config);
-            s.exec(&uds_path).await
-        }
-        SubCommand::Teardown(_) => {
-            let t = teardown::Teardown::new(input_config);
-            t.exec(&uds_path).await
+    let endpoint = match opts.endpoint {
+        Some(url) => {
+            let tls = match (opts.tls_ca, opts.tls_cert, opts.tls_key) {
+                (Some(ca), Some(cert), Some(key)) => Some(ClientTls { ca, cert, key }),
+                _ => None,
+            };
+            ProxyEndpoint::new(&url, tls).map_err(Status::invalid_argument)?
         }
+        None => ProxyEndpoint::Uds(opts.uds.unwrap_or_else(|| DEFAULT_UDS_PATH.to_string())),
     };
-    let r = match result {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("Error: {}", e.message());
-            eprintln!("Error: {e}");
-            process_failure(e)
-        }
+    let options = ClientOptions {
+        request_timeout: opts.timeout.map(Duration::from_secs),
+        ..Default::default()
     };
-
-    let pp = ::serde_json::to_string_pretty(&r);
-    // TODO this should probably return an empty lease so consumers
-    // don't soil themselves
-    println!("{}", pp.unwrap_or_else(|_| "".to_string()));
-    Ok(())
+    let client = ProxyClient::connect_with(endpoint, options).await?;
+    match opts.subcmd {
+        SubCommand::Setup(s) => output::print_lease(opts.format, &s.exec(&client, &file).await?),
+        SubCommand::Teardown(t) => output::print_lease(opts.format, &t.exec(&client, &file).await?),
+        SubCommand::Inspect(i) => output::print_inspect(opts.format, &i.exec(&client).await?),
+        SubCommand::Watch(w) => w.exec(&client, opts.format).await,
+        SubCommand::Health(h) => h.exec(&client, opts.format).await,
+        SubCommand::Version(v) => output::print_version(opts.format, &v.exec(&client).await?),
+    }
 }
 
 //
 // process_failure makes the client exit with a specific
 // error code
 //
-fn process_failure(status: Status) -> Lease {
+fn process_failure(status: Status) -> ! {
     let mut rc: i32 = 1;
 
     match status.code() {
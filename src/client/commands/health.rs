@@ -0,0 +1,30 @@
+use clap::Parser;
+use log::debug;
+use netavark_proxy::ProxyClient;
+use tonic::Status;
+use tonic_health::proto::health_check_response::ServingStatus;
+
+use crate::output::{self, Format};
+
+#[derive(Parser, Debug)]
+pub struct Health {
+    /// Check the health of this service instead of the whole proxy
+    #[clap(short, long, default_value = "")]
+    service: String,
+}
+
+impl Health {
+    pub async fn exec(&self, client: &ProxyClient, format: Format) -> Result<(), Status> {
+        debug!("Checking the health of the proxy");
+        let response = client.health(&self.service).await?;
+        let status = ServingStatus::from_i32(response.status).unwrap_or(ServingStatus::Unknown);
+        output::print_health(format, status.as_str_name());
+        if status != ServingStatus::Serving {
+            return Err(Status::unavailable(format!(
+                "proxy is {}",
+                status.as_str_name()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+use clap::Parser;
+use log::debug;
+use netavark_proxy::g_rpc::{InspectRequest, InspectResponse};
+use netavark_proxy::ProxyClient;
+use tonic::Status;
+
+#[derive(Parser, Debug)]
+pub struct Inspect {
+    /// Only show the leases of this mac address
+    #[clap(short, long)]
+    mac: Option<String>,
+    /// Only show the leases of this podman container
+    #[clap(short, long)]
+    container_id: Option<String>,
+}
+
+impl Inspect {
+    pub async fn exec(&self, client: &ProxyClient) -> Result<InspectResponse, Status> {
+        debug!("Inspecting leases");
+        let request = InspectRequest {
+            mac_address: self.mac.clone().unwrap_or_default(),
+            container_id: self.container_id.clone().unwrap_or_default(),
+        };
+        client.inspect(request).await
+    }
+}
@@ -1,3 +1,124 @@
+use clap::Parser;
+use netavark_proxy::g_rpc::{NetworkConfig, Version};
+use netavark_proxy::types::ProxyError;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tonic::Status;
+
+pub mod health;
+pub mod inspect;
 pub mod setup;
 pub mod teardown;
-// pub mod version;
+pub mod version;
+pub mod watch;
+
+/// The network configuration of a setup or teardown, given on the command line instead
+/// of as json
+#[derive(Parser, Debug)]
+pub struct ConfigArgs {
+    /// Configuration as comma separated key=value pairs, like
+    /// host_iface=eth0,container_iface=eth0,mac=3c:e1:a1:c1:7a:3f,ns=/run/netns/foo
+    #[clap(forbid_empty_values = true, parse(try_from_str = parse))]
+    config: Option<NetworkConfig>,
+    /// Interface of the host the container interface is linked to
+    #[clap(long)]
+    host_iface: Option<String>,
+    /// Interface inside of the container
+    #[clap(long)]
+    container_iface: Option<String>,
+    /// Mac address of the container interface
+    #[clap(long)]
+    mac: Option<String>,
+    /// Path of the network namespace of the container
+    #[clap(long)]
+    ns: Option<String>,
+    /// Host name to send to the DHCP server
+    #[clap(long)]
+    hostname: Option<String>,
+    /// Domain name of the container
+    #[clap(long)]
+    domain: Option<String>,
+    /// Address family of the lease, 4 or 6
+    #[clap(long, parse(try_from_str = parse))]
+    family: Option<Version>,
+    /// Podman container the configuration belongs to
+    #[clap(long)]
+    container_id: Option<String>,
+    /// Name of the podman container
+    #[clap(long)]
+    container_name: Option<String>,
+    /// Podman network the configuration belongs to
+    #[clap(long)]
+    network_name: Option<String>,
+    /// Address to ask the DHCP server for
+    #[clap(long)]
+    requested_address: Option<IpAddr>,
+    /// The container interface is an ipvlan link
+    #[clap(long)]
+    ipvlan: bool,
+}
+
+impl ConfigArgs {
+    /// The configuration of the arguments, or the json configuration in the given file
+    /// when there are none. A configuration from the arguments is checked with `validate`.
+    ///
+    /// # Arguments
+    ///
+    /// * `file`: path of the json configuration
+    /// * `validate`: checks the configuration has what the command needs
+    ///
+    /// returns: Result<NetworkConfig, Status>
+    pub fn config(
+        &self,
+        file: &str,
+        validate: fn(&NetworkConfig) -> Result<(), ProxyError>,
+    ) -> Result<NetworkConfig, Status> {
+        let nc = match self.build() {
+            Some(nc) => nc,
+            None => {
+                return NetworkConfig::load(file)
+                    .map_err(|e| Status::invalid_argument(e.to_string()))
+            }
+        };
+        validate(&nc).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(nc)
+    }
+
+    /// The configuration with the flags applied over the key=value pairs, none when
+    /// neither were given
+    fn build(&self) -> Option<NetworkConfig> {
+        let mut given = self.config.is_some() || self.ipvlan;
+        let mut nc = self.config.clone().unwrap_or_default();
+        for (flag, field) in [
+            (&self.host_iface, &mut nc.host_iface),
+            (&self.container_iface, &mut nc.container_iface),
+            (&self.mac, &mut nc.container_mac_addr),
+            (&self.ns, &mut nc.ns_path),
+            (&self.hostname, &mut nc.host_name),
+            (&self.domain, &mut nc.domain_name),
+            (&self.container_id, &mut nc.container_id),
+            (&self.container_name, &mut nc.container_name),
+            (&self.network_name, &mut nc.network_name),
+        ] {
+            if let Some(value) = flag {
+                *field = value.clone();
+                given = true;
+            }
+        }
+        if let Some(family) = self.family {
+            nc.version = family as i32;
+            given = true;
+        }
+        if let Some(address) = self.requested_address {
+            nc.requested_address = address.to_string();
+            given = true;
+        }
+        nc.ipvlan |= self.ipvlan;
+        given.then_some(nc)
+    }
+}
+
+// ProxyError is no std error, so clap gets its message
+fn parse<T: FromStr<Err = ProxyError>>(s: &str) -> Result<T, String> {
+    T::from_str(s).map_err(|e| e.to_string())
+}
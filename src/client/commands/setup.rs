@@ -1,27 +1,23 @@
 use clap::Parser;
 use log::debug;
 use netavark_proxy::g_rpc::{Lease, NetworkConfig};
+use netavark_proxy::ProxyClient;
 use tonic::Status;
 
+use super::ConfigArgs;
+
 #[derive(Parser, Debug)]
 pub struct Setup {
-    /// Network namespace path
-    #[clap(forbid_empty_values = false, required = false)]
-    config: NetworkConfig,
+    #[clap(flatten)]
+    args: ConfigArgs,
 }
 
 impl Setup {
-    pub fn new(config: NetworkConfig) -> Self {
-        Self { config }
-    }
-
-    pub async fn exec(&self, p: &str) -> Result<Lease, Status> {
+    pub async fn exec(&self, client: &ProxyClient, file: &str) -> Result<Lease, Status> {
         debug!("{:?}", "Setting up...");
-        debug!(
-            "input: {:#?}",
-            serde_json::to_string_pretty(&self.config.clone())
-        );
+        let config = self.args.config(file, NetworkConfig::validate_setup)?;
+        debug!("input: {:#?}", serde_json::to_string_pretty(&config));
 
-        self.config.clone().get_lease(p).await
+        client.setup(config).await
     }
 }
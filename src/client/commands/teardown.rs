@@ -1,22 +1,21 @@
 use clap::Parser;
 use log::debug;
 use netavark_proxy::g_rpc::{Lease, NetworkConfig};
+use netavark_proxy::ProxyClient;
 use tonic::Status;
 
+use super::ConfigArgs;
+
 #[derive(Parser, Debug)]
 pub struct Teardown {
-    /// Network namespace path
-    #[clap(forbid_empty_values = true, required = true)]
-    config: NetworkConfig,
+    #[clap(flatten)]
+    args: ConfigArgs,
 }
 
 impl Teardown {
-    pub fn new(config: NetworkConfig) -> Self {
-        Self { config }
-    }
-
-    pub async fn exec(&self, p: &str) -> Result<Lease, Status> {
+    pub async fn exec(&self, client: &ProxyClient, file: &str) -> Result<Lease, Status> {
         debug!("Entering teardown");
-        self.config.clone().drop_lease(p).await
+        let config = self.args.config(file, NetworkConfig::validate_teardown)?;
+        client.teardown(config).await
     }
 }
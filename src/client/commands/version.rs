@@ -0,0 +1,15 @@
+use clap::Parser;
+use log::debug;
+use netavark_proxy::g_rpc::ProxyInfo;
+use netavark_proxy::ProxyClient;
+use tonic::Status;
+
+#[derive(Parser, Debug)]
+pub struct Version {}
+
+impl Version {
+    pub async fn exec(&self, client: &ProxyClient) -> Result<ProxyInfo, Status> {
+        debug!("Asking the proxy for its version");
+        client.info().await
+    }
+}
@@ -0,0 +1,34 @@
+use clap::Parser;
+use log::debug;
+use netavark_proxy::g_rpc::WatchRequest;
+use netavark_proxy::ProxyClient;
+use tonic::Status;
+
+use crate::output::{self, Format};
+
+#[derive(Parser, Debug)]
+pub struct Watch {
+    /// Only show events of leases for this mac address
+    #[clap(short, long)]
+    mac: Option<String>,
+    /// Only show events of leases on this host or container interface
+    #[clap(short, long)]
+    interface: Option<String>,
+}
+
+impl Watch {
+    pub async fn exec(&self, client: &ProxyClient, format: Format) -> Result<(), Status> {
+        debug!("Watching leases");
+        let request = WatchRequest {
+            mac_address: self.mac.clone().unwrap_or_default(),
+            interface: self.interface.clone().unwrap_or_default(),
+        };
+        let mut events = client.watch(request).await?;
+        // Print every event as a single line
+        output::print_event_header(format);
+        while let Some(event) = events.message().await? {
+            output::print_event(format, &event)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+// How the client prints the results of the proxy. JSON is meant for netavark,
+// the table for humans and the env format for shell scripts that `eval` it.
+
+use clap::ArgEnum;
+use netavark_proxy::g_rpc::{
+    InspectResponse, Lease, LeaseEvent, LeaseEventKind, LeaseInfo, ProxyInfo,
+};
+use netavark_proxy::proxy_conf::API_VERSION;
+use serde::Serialize;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Table,
+    Env,
+}
+
+/// Print the lease handed out by a setup or dropped by a teardown
+pub fn print_lease(format: Format, lease: &Lease) -> Result<(), Status> {
+    match format {
+        Format::Json => println!("{}", to_json_pretty(lease)?),
+        Format::Table => {
+            let mut rows = vec![vec!["FIELD".to_string(), "VALUE".to_string()]];
+            for (name, value) in lease_vars(lease) {
+                rows.push(vec![name.to_lowercase(), value]);
+            }
+            print!("{}", table(&rows));
+        }
+        Format::Env => {
+            for (name, value) in lease_vars(lease) {
+                println!("NV_{}={}", name, shell_quote(&value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the cached leases
+pub fn print_inspect(format: Format, response: &InspectResponse) -> Result<(), Status> {
+    match format {
+        Format::Json => println!("{}", to_json_pretty(response)?),
+        Format::Table => {
+            let mut rows = vec![[
+                "MAC ADDRESS",
+                "ADDRESS",
+                "CONTAINER",
+                "INTERFACE",
+                "NETWORK",
+                "EXPIRES",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect()];
+            for info in &response.leases {
+                rows.push(info_row(info));
+            }
+            print!("{}", table(&rows));
+        }
+        Format::Env => {
+            println!("NV_LEASE_COUNT={}", response.leases.len());
+            for (i, info) in response.leases.iter().enumerate() {
+                let config = info.config.clone().unwrap_or_default();
+                let lease = info.lease.clone().unwrap_or_default();
+                println!(
+                    "NV_LEASE_{}_CONTAINER_ID={}",
+                    i,
+                    shell_quote(&config.container_id)
+                );
+                println!(
+                    "NV_LEASE_{}_INTERFACE={}",
+                    i,
+                    shell_quote(&config.container_iface)
+                );
+                for (name, value) in lease_vars(&lease) {
+                    println!("NV_LEASE_{}_{}={}", i, name, shell_quote(&value));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the header of a stream of lease events, if the format has one
+pub fn print_event_header(format: Format) {
+    if format == Format::Table {
+        println!(
+            "{}",
+            event_columns(&["TIME", "EVENT", "MAC ADDRESS", "INTERFACE", "ADDRESS"])
+        );
+    }
+}
+
+/// Print a lease event on a single line, so that the stream can be read line by line
+pub fn print_event(format: Format, event: &LeaseEvent) -> Result<(), Status> {
+    let kind = LeaseEventKind::from_i32(event.kind)
+        .map(|k| k.as_str_name())
+        .unwrap_or("UNKNOWN");
+    let lease = event.lease.clone().unwrap_or_default();
+    match format {
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string(event).map_err(|e| Status::internal(e.to_string()))?
+        ),
+        Format::Table => println!(
+            "{}",
+            event_columns(&[
+                &event.timestamp.to_string(),
+                kind,
+                &event.mac_address,
+                &event.container_iface,
+                &lease.yiaddr,
+            ])
+        ),
+        Format::Env => {
+            let mut vars = vec![
+                ("EVENT", kind.to_string()),
+                ("TIMESTAMP", event.timestamp.to_string()),
+                ("HOST_IFACE", event.host_iface.clone()),
+                ("CONTAINER_IFACE", event.container_iface.clone()),
+                ("PREVIOUS_IP", event.previous_address.clone()),
+            ];
+            vars.extend(lease_vars(&lease));
+            // The mac address of the event is set even when the lease is gone
+            for (name, value) in vars.iter_mut() {
+                if *name == "MAC_ADDRESS" {
+                    *value = event.mac_address.clone();
+                }
+            }
+            let line: Vec<String> = vars
+                .iter()
+                .map(|(name, value)| format!("NV_{}={}", name, shell_quote(value)))
+                .collect();
+            println!("{}", line.join(" "));
+        }
+    }
+    Ok(())
+}
+
+/// Print the serving status of the proxy
+pub fn print_health(format: Format, status: &str) {
+    match format {
+        Format::Json => println!("{}", serde_json::json!({ "status": status })),
+        Format::Table => print!(
+            "{}",
+            table(&[vec!["STATUS".to_string()], vec![status.to_string()]])
+        ),
+        Format::Env => println!("NV_STATUS={}", shell_quote(status)),
+    }
+}
+
+/// Print the version of the client next to the version and capabilities of the proxy
+pub fn print_version(format: Format, proxy: &ProxyInfo) -> Result<(), Status> {
+    let client = env!("CARGO_PKG_VERSION");
+    let capabilities = proxy.capabilities.join(" ");
+    match format {
+        Format::Json => println!(
+            "{}",
+            to_json_pretty(&serde_json::json!({
+                "client": { "version": client, "api_version": API_VERSION },
+                "proxy": proxy,
+            }))?
+        ),
+        Format::Table => print!(
+            "{}",
+            table(&[
+                vec![
+                    "".to_string(),
+                    "VERSION".to_string(),
+                    "API VERSION".to_string(),
+                    "CAPABILITIES".to_string(),
+                ],
+                vec![
+                    "client".to_string(),
+                    client.to_string(),
+                    API_VERSION.to_string(),
+                    String::new(),
+                ],
+                vec![
+                    "proxy".to_string(),
+                    proxy.version.clone(),
+                    proxy.api_version.to_string(),
+                    capabilities,
+                ],
+            ])
+        ),
+        Format::Env => {
+            println!("NV_CLIENT_VERSION={}", shell_quote(client));
+            println!("NV_CLIENT_API_VERSION={API_VERSION}");
+            println!("NV_PROXY_VERSION={}", shell_quote(&proxy.version));
+            println!("NV_PROXY_API_VERSION={}", proxy.api_version);
+            println!("NV_PROXY_CAPABILITIES={}", shell_quote(&capabilities));
+        }
+    }
+    Ok(())
+}
+
+/// Print a failed call as a JSON object on stderr, whatever the format of the output is
+pub fn print_error(status: &Status) {
+    #[derive(Serialize)]
+    struct Error<'a> {
+        code: String,
+        message: &'a str,
+    }
+    let error = Error {
+        code: format!("{:?}", status.code()),
+        message: status.message(),
+    };
+    eprintln!("{}", serde_json::json!({ "error": error }));
+}
+
+fn to_json_pretty<T: Serialize>(value: &T) -> Result<String, Status> {
+    serde_json::to_string_pretty(value).map_err(|e| Status::internal(e.to_string()))
+}
+
+/// The fields of a lease as the names of shell variables and their values
+fn lease_vars(lease: &Lease) -> Vec<(&'static str, String)> {
+    let prefix_length = Ipv4Addr::from_str(&lease.subnet_mask)
+        .map(|m| u32::from(m).count_ones().to_string())
+        .unwrap_or_default();
+    vec![
+        ("IP", lease.yiaddr.clone()),
+        ("SUBNET_MASK", lease.subnet_mask.clone()),
+        ("PREFIX_LENGTH", prefix_length),
+        (
+            "GATEWAY",
+            lease.gateways.first().cloned().unwrap_or_default(),
+        ),
+        ("GATEWAYS", lease.gateways.join(" ")),
+        ("BROADCAST", lease.broadcast_addr.clone()),
+        ("DNS_SERVERS", lease.dns_servers.join(" ")),
+        ("NTP_SERVERS", lease.ntp_servers.join(" ")),
+        ("DOMAIN_NAME", lease.domain_name.clone()),
+        ("HOST_NAME", lease.host_name.clone()),
+        ("MTU", lease.mtu.to_string()),
+        ("MAC_ADDRESS", lease.mac_address.clone()),
+        ("SERVER_ID", lease.srv_id.clone()),
+        ("LEASE_TIME", lease.lease_time.to_string()),
+        ("T1", lease.t1.to_string()),
+        ("T2", lease.t2.to_string()),
+    ]
+}
+
+fn info_row(info: &LeaseInfo) -> Vec<String> {
+    let config = info.config.clone().unwrap_or_default();
+    let lease = info.lease.clone().unwrap_or_default();
+    let container = if config.container_name.is_empty() {
+        config.container_id.chars().take(12).collect()
+    } else {
+        config.container_name.clone()
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let expires = if info.expired {
+        "expired".to_string()
+    } else if info.expires_at == 0 {
+        "never".to_string()
+    } else {
+        format!("in {}s", info.expires_at.saturating_sub(now))
+    };
+    vec![
+        config.container_mac_addr,
+        lease.yiaddr,
+        container,
+        config.container_iface,
+        config.network_name,
+        expires,
+    ]
+}
+
+/// Rows with their columns padded to the widest cell, the first row is the header
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = Vec::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            match widths.get_mut(i) {
+                Some(w) => *w = (*w).max(cell.len()),
+                None => widths.push(cell.len()),
+            }
+        }
+    }
+    let mut out = String::new();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{:<width$}", cell, width = widths[i]))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Events are printed as they come, so their columns have fixed widths
+fn event_columns(cells: &[&str]) -> String {
+    format!(
+        "{:<10}  {:<15}  {:<17}  {:<10}  {}",
+        cells[0], cells[1], cells[2], cells[3], cells[4]
+    )
+}
+
+/// Quote a value for a POSIX shell, so that `eval` assigns it as is
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_values_are_quoted() {
+        assert_eq!(shell_quote("10.0.0.5"), "'10.0.0.5'");
+        assert_eq!(shell_quote("it's; rm -rf /"), r"'it'\''s; rm -rf /'");
+        let lease = Lease {
+            yiaddr: "10.0.0.5".to_string(),
+            subnet_mask: "255.255.255.0".to_string(),
+            gateways: vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()],
+            ..Default::default()
+        };
+        let vars = lease_vars(&lease);
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(var("PREFIX_LENGTH"), Some("24"));
+        assert_eq!(var("GATEWAY"), Some("10.0.0.1"));
+        assert_eq!(var("GATEWAYS"), Some("10.0.0.1 10.0.0.2"));
+    }
+
+    #[test]
+    fn table_columns_are_aligned() {
+        let rows = vec![
+            vec!["A".to_string(), "B".to_string()],
+            vec!["long cell".to_string(), "x".to_string()],
+        ];
+        assert_eq!(table(&rows), "A          B\nlong cell  x\n");
+    }
+}
@@ -1,5 +1,10 @@
 use crate::dhcp_service::DhcpServiceErrorKind::{Bug, InvalidArgument, NoLease, Timeout};
+use crate::ip::NamespaceGuard;
+use crate::metrics::Metrics;
+use crate::relay::{DhcpTransport, RelayClient};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Instant;
 
 use crate::g_rpc::{Lease as NetavarkLease, Lease, NetworkConfig};
 use log::warn;
@@ -35,14 +40,95 @@ impl DhcpServiceError {
 ///
 /// These clients are managed differently. so it is important to keep these separate.
 pub enum DhcpClient {
-    V4Client(Box<DhcpV4Client>),
+    V4Client(Box<dyn MozimClient>),
     V6Client(/*TODO implement v6 client*/),
+    RelayClient(Box<RelayClient>),
+}
+
+/// The mozim client the service waits on for a lease, so the exchange can be tested
+/// without raw sockets. mozim 0.1 does not export its events, so a client handles one
+/// round of them at a time.
+pub trait MozimClient {
+    /// Wait up to wait_time seconds for the DHCP server and handle what arrived. None
+    /// while there is no lease yet. An error of kind Timeout means the wait itself failed.
+    fn next_lease(&mut self, wait_time: isize) -> Result<Option<DhcpV4Lease>, DhcpError>;
+
+    /// Give a lease back to the server that handed it out
+    fn release(&mut self, lease: &DhcpV4Lease) -> Result<(), DhcpError>;
+}
+
+impl MozimClient for DhcpV4Client {
+    fn next_lease(&mut self, wait_time: isize) -> Result<Option<DhcpV4Lease>, DhcpError> {
+        let events = self.poll(wait_time).map_err(|e| {
+            log::error!("DHCP socket timed out: {}", e.to_string());
+            DhcpError::new(ErrorKind::Timeout, e.to_string())
+        })?;
+        for event in events {
+            if let Some(lease) = self.process(event)? {
+                return Ok(Some(lease));
+            }
+        }
+        Ok(None)
+    }
+
+    fn release(&mut self, lease: &DhcpV4Lease) -> Result<(), DhcpError> {
+        DhcpV4Client::release(self, lease)
+    }
+}
+
+/// How the proxy reaches the DHCP servers, the same for every lease
+#[derive(Debug, Clone)]
+pub struct DhcpSettings {
+    /// Seconds to wait for the DHCP server
+    pub timeout: isize,
+    /// Relay the messages to the DHCP servers instead of broadcasting them on the
+    /// parent interface
+    pub relay: Option<Arc<dyn DhcpTransport>>,
+    /// Run the DHCP client on the container interface from inside the network
+    /// namespace of the container, instead of on the parent interface of the host
+    pub in_namespace: bool,
+    /// Path of the network namespace of the container to run the client in, instead of
+    /// the path of the network configuration
+    pub netns: Option<String>,
+}
+
+impl DhcpSettings {
+    pub fn new(timeout: isize) -> Self {
+        DhcpSettings {
+            timeout,
+            relay: None,
+            in_namespace: false,
+            netns: None,
+        }
+    }
+
+    /// The same settings with a different timeout
+    pub fn with_timeout(&self, timeout: isize) -> Self {
+        DhcpSettings {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    /// The same settings for a network namespace that was already opened. The
+    /// configuration keeps its own path, it identifies the container to the DHCP server.
+    pub fn with_namespace(&self, ns_path: String) -> Self {
+        DhcpSettings {
+            netns: Some(ns_path),
+            ..self.clone()
+        }
+    }
 }
 /// DHCP service is responsible for creating, handling, and managing the dhcp lease process.
 pub struct DhcpService {
     client: Option<DhcpClient>,
     network_config: NetworkConfig,
     timeout: isize,
+    // network namespace the client runs in, None for the namespace of the proxy
+    namespace: Option<String>,
+    // whether the client renews an existing lease instead of running a DORA
+    renewing: bool,
+    metrics: Option<Arc<Metrics>>,
 }
 
 trait IP4Conv {
@@ -72,23 +158,133 @@ impl IP6Conv for IpAddr {
 }
 
 impl DhcpService {
-    pub fn new(nc: &NetworkConfig, timeout: isize) -> Result<DhcpService, DhcpServiceError> {
-        let client = Self::create_client(nc)?;
+    pub fn new(
+        nc: &NetworkConfig,
+        settings: &DhcpSettings,
+    ) -> Result<DhcpService, DhcpServiceError> {
+        let client = Self::create_client(nc, None, settings)?;
         Ok(DhcpService {
             client: Some(client),
             network_config: nc.clone(),
-            timeout,
+            timeout: settings.timeout,
+            namespace: Self::namespace(nc, settings),
+            renewing: false,
+            metrics: None,
+        })
+    }
+
+    /// Create a dhcp service that starts from an existing lease. Instead of a DORA the
+    /// client sends a REQUEST for the leased address, which is how a lease is renewed.
+    ///
+    /// The relay client renews the way a client in the RENEWING state does, with the
+    /// leased address in ciaddr. mozim 0.1 does not expose that state to a client that
+    /// starts from a lease, so its REQUEST names the server that handed out the lease and
+    /// asks for the address in the requested IP address option, as in SELECTING.
+    pub fn with_lease(
+        nc: &NetworkConfig,
+        lease: &NetavarkLease,
+        settings: &DhcpSettings,
+    ) -> Result<DhcpService, DhcpServiceError> {
+        let v4_lease = match DhcpV4Lease::try_from(lease.clone()) {
+            Ok(l) => l,
+            Err(e) => return Err(DhcpServiceError::new(InvalidArgument, e.to_string())),
+        };
+        let client = Self::create_client(nc, Some(v4_lease), settings)?;
+        Ok(DhcpService {
+            client: Some(client),
+            network_config: nc.clone(),
+            timeout: settings.timeout,
+            namespace: Self::namespace(nc, settings),
+            renewing: true,
+            metrics: None,
+        })
+    }
+
+    /// Create a dhcp service that verifies a cached lease the way a client in the
+    /// INIT-REBOOT state does. In relay mode the REQUEST for the cached address carries no
+    /// server identifier, so whichever server is responsible for the network answers it.
+    ///
+    /// mozim 0.1 always sends a server identifier, taken from siaddr, and servers ignore a
+    /// REQUEST for 0.0.0.0. Without a relay the REQUEST names the server that handed out
+    /// the lease instead, the same as [`DhcpService::with_lease`].
+    pub fn init_reboot(
+        nc: &NetworkConfig,
+        lease: &NetavarkLease,
+        settings: &DhcpSettings,
+    ) -> Result<DhcpService, DhcpServiceError> {
+        if settings.relay.is_none() {
+            return Self::with_lease(nc, lease, settings);
+        }
+        let mut lease = lease.clone();
+        lease.srv_id = Ipv4Addr::UNSPECIFIED.to_string();
+        Self::with_lease(nc, &lease, settings)
+    }
+
+    /// Create a dhcp service whose DORA asks for the given address. The DISCOVER carries
+    /// the address in the requested IP address option (50), a server that does not hand
+    /// it out offers another one. A server that has no binding for the client still
+    /// answers, which it would not do for a REQUEST in the INIT-REBOOT state.
+    ///
+    /// Only the relay client can do this. mozim 0.1 has no way to add the option to its
+    /// DISCOVER.
+    pub fn requesting(
+        nc: &NetworkConfig,
+        address: Ipv4Addr,
+        settings: &DhcpSettings,
+    ) -> Result<DhcpService, DhcpServiceError> {
+        if settings.relay.is_none() {
+            return Err(DhcpServiceError::new(
+                InvalidArgument,
+                String::from("requesting an address needs the DHCP relay"),
+            ));
+        }
+        let client = match Self::create_client(nc, None, settings)? {
+            DhcpClient::RelayClient(c) => {
+                DhcpClient::RelayClient(Box::new(c.with_requested_address(address)))
+            }
+            client => client,
+        };
+        Ok(DhcpService {
+            client: Some(client),
+            network_config: nc.clone(),
+            timeout: settings.timeout,
+            namespace: Self::namespace(nc, settings),
+            renewing: false,
+            metrics: None,
         })
     }
+
+    /// Count the DHCP exchanges of this service in the given metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
     /// Based on the IP version, use the dhcp client to process a dhcp lease using DORA.
     /// Note: By using process you pass ownership of the dhcp service.
     pub fn get_lease(mut self) -> Result<NetavarkLease, DhcpServiceError> {
         // match the ip version to create the correct dhcp client
         if let Some(client) = self.client.take() {
-            return match client {
-                DhcpClient::V4Client(v4_client) => self.get_v4_lease(*v4_client),
+            let _netns = match &self.namespace {
+                Some(ns_path) => Some(
+                    NamespaceGuard::enter(ns_path)
+                        .map_err(|e| DhcpServiceError::new(Bug, e.to_string()))?,
+                ),
+                None => None,
+            };
+            let start = Instant::now();
+            let result = match client {
+                DhcpClient::V4Client(v4_client) => self.get_v4_lease(v4_client),
                 DhcpClient::V6Client() => self.get_v6_lease(),
+                DhcpClient::RelayClient(relay_client) => self.get_relay_lease(*relay_client),
             };
+            if let Some(metrics) = &self.metrics {
+                if self.renewing {
+                    metrics.observe_renewal(result.is_ok());
+                } else if result.is_ok() {
+                    metrics.observe_dora(start.elapsed());
+                }
+            }
+            return result;
         }
         Err(DhcpServiceError::new(
             Bug,
@@ -99,12 +295,20 @@ impl DhcpService {
     pub fn release_lease(mut self, lease: &Lease) -> Result<(), DhcpError> {
         // match the ip version to create the correct dhcp client
         if let Some(client) = self.client.take() {
+            let _netns = match &self.namespace {
+                Some(ns_path) => Some(NamespaceGuard::enter(ns_path)?),
+                None => None,
+            };
             return match client {
                 DhcpClient::V4Client(mut v4_client) => {
                     let v4_lease = DhcpV4Lease::try_from(lease.clone())?;
-                    v4_client.release(&v4_lease)
+                    v4_client.release(&for_mozim(v4_lease))
                 }
                 DhcpClient::V6Client() => self.release_v6_lease(),
+                DhcpClient::RelayClient(mut relay_client) => {
+                    let v4_lease = DhcpV4Lease::try_from(lease.clone())?;
+                    relay_client.release(&v4_lease)
+                }
             };
         }
         // Releasing a lease is not a fatal error
@@ -115,43 +319,72 @@ impl DhcpService {
         Ok(())
     }
 
-    /// Performs a DHCP DORA on a ipv4 network configuration.
+    /// Performs a DHCP DORA on a ipv4 network configuration. mozim retransmits and gives
+    /// up on its own timers, so the service keeps waiting until it does.
     /// # Arguments
     ///
     /// * `client`: a IPv4 mozim dhcp client. When this method is called, it takes ownership of client.
     ///
     /// returns: Result<Lease, DhcpSearchError>. Either finds a lease successfully, finds no lease, or fails
-    fn get_v4_lease(&self, mut client: DhcpV4Client) -> Result<NetavarkLease, DhcpServiceError> {
-        let timeout = self.timeout;
+    fn get_v4_lease(
+        &self,
+        mut client: Box<dyn MozimClient>,
+    ) -> Result<NetavarkLease, DhcpServiceError> {
         loop {
-            match client.poll(timeout) {
-                Ok(events) => {
-                    for event in events {
-                        match client.process(event) {
-                            Ok(Some(new_lease)) => {
-                                log::debug!("successfully found a lease");
-                                let mut netavark_lease =
-                                    <NetavarkLease as From<MozimV4Lease>>::from(new_lease);
-                                netavark_lease.add_domain_name(&self.network_config.domain_name);
-                                netavark_lease
-                                    .add_mac_address(&self.network_config.container_mac_addr);
-                                return Ok(netavark_lease);
-                            }
-                            Err(err) => {
-                                return Err(DhcpServiceError::new(NoLease, err.to_string()))
-                            }
-                            Ok(None) => { /*No lease found, keep looking for one*/ }
-                        };
-                    }
+            match client.next_lease(self.timeout) {
+                Ok(Some(new_lease)) => {
+                    log::debug!("successfully found a lease");
+                    return Ok(self.to_netavark_lease(new_lease));
                 }
-                Err(dhcp_error) => {
-                    log::error!("DHCP socket timed out: {}", dhcp_error.to_string());
-                    return Err(DhcpServiceError::new(Timeout, dhcp_error.to_string()));
+                Ok(None) => log::info!("Socket timed out, retrying for a lease"),
+                Err(err) if err.kind() == ErrorKind::Timeout => {
+                    return Err(DhcpServiceError::new(Timeout, err.to_string()))
                 }
-            };
-            log::info!("Socket timed out, retrying for a lease");
+                Err(err) => {
+                    self.observe_refusal(&err);
+                    return Err(DhcpServiceError::new(NoLease, err.to_string()));
+                }
+            }
         }
     }
+    /// Obtains a lease through the relay agent
+    ///
+    /// * `client`: a relay client. When this method is called, it takes ownership of client.
+    fn get_relay_lease(&self, mut client: RelayClient) -> Result<NetavarkLease, DhcpServiceError> {
+        match client.get_lease(self.timeout) {
+            Ok(new_lease) => {
+                log::debug!("successfully found a lease through the relay");
+                Ok(self.to_netavark_lease(new_lease))
+            }
+            Err(err) if err.kind() == ErrorKind::Timeout => {
+                Err(DhcpServiceError::new(Timeout, err.to_string()))
+            }
+            Err(err) => {
+                self.observe_refusal(&err);
+                Err(DhcpServiceError::new(NoLease, err.to_string()))
+            }
+        }
+    }
+
+    /// Complete a lease from mozim with what the proxy knows of the container
+    fn to_netavark_lease(&self, lease: MozimV4Lease) -> NetavarkLease {
+        let mut netavark_lease = <NetavarkLease as From<MozimV4Lease>>::from(lease);
+        netavark_lease.add_domain_name(&self.network_config.domain_name);
+        netavark_lease.add_mac_address(&self.network_config.container_mac_addr);
+        netavark_lease
+    }
+
+    /// Count a lease the server refused or a reply that could not be used
+    fn observe_refusal(&self, err: &DhcpError) {
+        if let Some(metrics) = &self.metrics {
+            match err.kind() {
+                ErrorKind::NoLease => metrics.observe_nak(),
+                ErrorKind::InvalidDhcpServerReply => metrics.observe_invalid_reply(),
+                _ => {}
+            }
+        }
+    }
+
     /// TODO
     /// Performs a DHCP DORA on a IPv6 network configuration.
     /// # Arguments
@@ -170,21 +403,55 @@ impl DhcpService {
     /// Create a DHCP client
     /// # Arguments
     ///
-    /// * `iface`: network interface name
-    /// * `version`: Version - can be Ipv4 or Ipv6
+    /// * `nc`: network configuration holding the interface name and the ip version
+    /// * `lease`: an existing lease to renew, None to start a new DORA
+    /// * `settings`: how the DHCP servers are reached
     ///
     /// returns: Result<DhcpV4Client, DhcpError>. If there are no invalid arguments, mozim creates a client.
-    fn create_client(nc: &NetworkConfig) -> Result<DhcpClient, DhcpServiceError> {
+    fn create_client(
+        nc: &NetworkConfig,
+        lease: Option<DhcpV4Lease>,
+        settings: &DhcpSettings,
+    ) -> Result<DhcpClient, DhcpServiceError> {
         let version = &nc.version;
         let iface = &nc.host_iface;
         match version {
             //V4
             0 => {
-                let config = match DhcpV4Config::new_proxy(iface, &nc.container_mac_addr) {
+                if let Some(relay) = &settings.relay {
+                    return match RelayClient::new(relay.clone(), nc, lease) {
+                        Ok(client) => Ok(DhcpClient::RelayClient(Box::new(client))),
+                        Err(err) => Err(DhcpServiceError::new(InvalidArgument, err.to_string())),
+                    };
+                }
+                // mozim looks up the interface and opens its sockets in the namespace the
+                // thread is in, so enter the container namespace before building the config
+                let _netns = match Self::namespace(nc, settings) {
+                    Some(ns_path) => match NamespaceGuard::enter(&ns_path) {
+                        Ok(guard) => Some(guard),
+                        Err(e) => {
+                            return Err(DhcpServiceError::new(InvalidArgument, e.to_string()))
+                        }
+                    },
+                    None => None,
+                };
+                let config = if settings.in_namespace {
+                    DhcpV4Config::new(&nc.container_iface)
+                } else {
+                    DhcpV4Config::new_proxy(iface, &nc.container_mac_addr)
+                };
+                let mut config = match config {
                     Ok(c) => c,
                     Err(e) => return Err(DhcpServiceError::new(InvalidArgument, e.to_string())),
                 };
-                match DhcpV4Client::init(config, None) {
+                // mozim can only send the host name as client identifier, so containers that
+                // share a mac address send their client identifier as host name. Its raw
+                // socket sees the replies to the shared mac address.
+                if let Some(client_id) = nc.client_id() {
+                    config.set_host_name(&client_id);
+                    config.use_host_name_as_client_id();
+                }
+                match DhcpV4Client::init(config, lease.map(for_mozim)) {
                     Ok(client) => Ok(DhcpClient::V4Client(Box::new(client))),
                     Err(err) => Err(DhcpServiceError::new(InvalidArgument, err.to_string())),
                 }
@@ -201,6 +468,15 @@ impl DhcpService {
         }
     }
 
+    /// The network namespace the client of a configuration runs in
+    fn namespace(nc: &NetworkConfig, settings: &DhcpSettings) -> Option<String> {
+        if settings.in_namespace && settings.relay.is_none() && nc.version == 0 {
+            Some(settings.netns.clone().unwrap_or_else(|| nc.ns_path.clone()))
+        } else {
+            None
+        }
+    }
+
     fn release_v6_lease(&self) -> Result<(), DhcpError> {
         Err(DhcpError::new(
             ErrorKind::Bug,
@@ -209,6 +485,16 @@ impl DhcpService {
     }
 }
 
+/// mozim 0.1 takes the server identifier of its REQUEST and RELEASE messages, and the
+/// destination of a unicast RELEASE, from siaddr. That is the next server of the reply and
+/// usually 0.0.0.0, so hand it the server identifier instead.
+fn for_mozim(mut lease: DhcpV4Lease) -> DhcpV4Lease {
+    if !lease.srv_id.is_unspecified() {
+        lease.siaddr = lease.srv_id;
+    }
+    lease
+}
+
 impl std::fmt::Display for DhcpServiceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.msg)
@@ -226,3 +512,327 @@ impl From<DhcpServiceError> for Status {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::scripted::{Reply, ScriptedTransport};
+    use crate::relay::{DhcpMessage, MessageType};
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Mutex;
+
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+
+    fn config() -> NetworkConfig {
+        NetworkConfig {
+            host_iface: "eth0".to_string(),
+            container_iface: "eth0".to_string(),
+            container_mac_addr: MAC.to_string(),
+            domain_name: "podman.local".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn settings(transport: &Arc<ScriptedTransport>) -> DhcpSettings {
+        let mut settings = DhcpSettings::new(1);
+        settings.relay = Some(transport.clone());
+        settings
+    }
+
+    fn kinds(received: &[DhcpMessage]) -> Vec<Option<MessageType>> {
+        received.iter().map(|m| m.message_type()).collect()
+    }
+
+    /// A mozim client that goes through a script of rounds, the wait runs out once the
+    /// script does
+    struct ScriptedMozim {
+        rounds: VecDeque<Result<Option<DhcpV4Lease>, DhcpError>>,
+        released: Arc<Mutex<Vec<DhcpV4Lease>>>,
+    }
+
+    impl MozimClient for ScriptedMozim {
+        fn next_lease(&mut self, _wait_time: isize) -> Result<Option<DhcpV4Lease>, DhcpError> {
+            self.rounds.pop_front().unwrap_or_else(|| {
+                Err(DhcpError::new(
+                    ErrorKind::Timeout,
+                    "script ran out".to_string(),
+                ))
+            })
+        }
+
+        fn release(&mut self, lease: &DhcpV4Lease) -> Result<(), DhcpError> {
+            self.released.lock().expect("poisoned").push(lease.clone());
+            Ok(())
+        }
+    }
+
+    /// A service of the default mode around a scripted mozim client
+    fn mozim_service(
+        rounds: Vec<Result<Option<DhcpV4Lease>, DhcpError>>,
+        released: &Arc<Mutex<Vec<DhcpV4Lease>>>,
+    ) -> DhcpService {
+        DhcpService {
+            client: Some(DhcpClient::V4Client(Box::new(ScriptedMozim {
+                rounds: rounds.into(),
+                released: released.clone(),
+            }))),
+            network_config: config(),
+            timeout: 1,
+            namespace: None,
+            renewing: false,
+            metrics: None,
+        }
+    }
+
+    fn mozim_lease() -> DhcpV4Lease {
+        let mut lease = DhcpV4Lease::default();
+        lease.yiaddr = Ipv4Addr::new(10, 0, 0, 5);
+        lease.srv_id = Ipv4Addr::new(10, 0, 0, 1);
+        lease.subnet_mask = Ipv4Addr::new(255, 255, 255, 0);
+        lease.lease_time = 3600;
+        lease
+    }
+
+    #[test]
+    fn mozim_dora_lease() {
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::new());
+        // Rounds without a lease are waited out until mozim hands one over
+        let lease = mozim_service(vec![Ok(None), Ok(None), Ok(Some(mozim_lease()))], &released)
+            .with_metrics(metrics.clone())
+            .get_lease()
+            .unwrap_or_else(|e| panic!("no lease: {e}"));
+        assert_eq!(lease.yiaddr, "10.0.0.5");
+        assert_eq!(lease.mac_address, MAC);
+        assert_eq!(lease.domain_name, "podman.local");
+        assert!(metrics
+            .render(&HashMap::new())
+            .contains("nv_proxy_dora_duration_seconds_count 1"));
+
+        // mozim takes the server of the RELEASE from siaddr
+        assert!(mozim_service(vec![], &released)
+            .release_lease(&lease)
+            .is_ok());
+        let released = released.lock().expect("poisoned");
+        assert_eq!(released[0].siaddr, Ipv4Addr::new(10, 0, 0, 1));
+    }
+
+    #[test]
+    fn mozim_errors() {
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Arc::new(Metrics::new());
+        let code = |rounds| {
+            let err = mozim_service(rounds, &released)
+                .with_metrics(metrics.clone())
+                .get_lease()
+                .expect_err("got a lease");
+            Status::from(err).code()
+        };
+        // A server that does not answer is a timeout, like in relay mode
+        assert_eq!(code(vec![Ok(None)]), Code::Aborted);
+        assert_eq!(
+            code(vec![Err(DhcpError::new(
+                ErrorKind::NoLease,
+                "refused".to_string()
+            ))]),
+            Code::NotFound
+        );
+        assert_eq!(
+            code(vec![Err(DhcpError::new(
+                ErrorKind::InvalidDhcpServerReply,
+                "garbage".to_string()
+            ))]),
+            Code::NotFound
+        );
+        let rendered = metrics.render(&HashMap::new());
+        assert!(rendered.contains("nv_proxy_dhcp_naks_total 1"));
+        assert!(rendered.contains("nv_proxy_dhcp_invalid_replies_total 1"));
+    }
+
+    #[test]
+    fn namespace_client_enters_namespace_first() {
+        let mut settings = DhcpSettings::new(1);
+        settings.in_namespace = true;
+        let nc = NetworkConfig {
+            ns_path: "/run/netns/nv-proxy-does-not-exist".to_string(),
+            ..config()
+        };
+        // the namespace is entered before mozim looks up the container interface
+        match DhcpService::new(&nc, &settings) {
+            Err(e) => assert_eq!(Status::from(e).code(), Code::InvalidArgument),
+            Ok(_) => panic!("client created outside its namespace"),
+        }
+    }
+
+    #[test]
+    fn mozim_lease_names_the_server() {
+        let mut lease = DhcpV4Lease::default();
+        lease.srv_id = Ipv4Addr::new(10, 0, 0, 1);
+        assert_eq!(for_mozim(lease).siaddr, Ipv4Addr::new(10, 0, 0, 1));
+
+        let mut lease = DhcpV4Lease::default();
+        lease.siaddr = Ipv4Addr::new(10, 0, 0, 2);
+        assert_eq!(for_mozim(lease).siaddr, Ipv4Addr::new(10, 0, 0, 2));
+    }
+
+    #[test]
+    fn requesting_needs_relay() {
+        let err =
+            DhcpService::requesting(&config(), Ipv4Addr::new(10, 0, 0, 5), &DhcpSettings::new(1))
+                .err()
+                .expect("requested an address without the relay");
+        assert_eq!(Status::from(err).code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn client_id_ignores_opened_namespace() {
+        let transport = ScriptedTransport::new(vec![]);
+        let nc = NetworkConfig {
+            ipvlan: true,
+            ns_path: "/run/netns/foo".to_string(),
+            ..config()
+        };
+        let settings = settings(&transport).with_namespace("/proc/self/fd/9".to_string());
+        assert!(DhcpService::new(&nc, &settings)
+            .and_then(|s| s.get_lease())
+            .is_err());
+        // renewals and releases use the stored configuration, they have to send the same id
+        let received = transport.received();
+        assert_eq!(received[0].option(61), Some(&b"\0/run/netns/foo/eth0"[..]));
+    }
+
+    #[test]
+    fn dora_lease() {
+        let transport = ScriptedTransport::new(vec![
+            Reply::Message(MessageType::Offer),
+            Reply::Message(MessageType::Ack),
+        ]);
+        let metrics = Arc::new(Metrics::new());
+        let lease = DhcpService::new(&config(), &settings(&transport))
+            .and_then(|s| s.with_metrics(metrics.clone()).get_lease())
+            .unwrap_or_else(|e| panic!("no lease: {e}"));
+        assert_eq!(lease.yiaddr, "10.0.0.5");
+        assert_eq!(lease.subnet_mask, "255.255.255.0");
+        assert_eq!(lease.gateways, vec!["10.0.0.1"]);
+        assert_eq!(lease.dns_servers, vec!["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(lease.srv_id, "10.0.0.1");
+        assert_eq!(lease.mtu, 1400);
+        assert_eq!((lease.lease_time, lease.t1, lease.t2), (3600, 1800, 3150));
+        // what the proxy knows of the container wins over the server
+        assert_eq!(lease.mac_address, MAC);
+        assert_eq!(lease.domain_name, "podman.local");
+        assert!(metrics
+            .render(&HashMap::new())
+            .contains("nv_proxy_dora_duration_seconds_count 1"));
+
+        let service = DhcpService::new(&config(), &settings(&transport))
+            .unwrap_or_else(|e| panic!("no service: {e}"));
+        assert!(service.release_lease(&lease).is_ok());
+        let received = transport.received();
+        assert_eq!(
+            kinds(&received),
+            vec![
+                Some(MessageType::Discover),
+                Some(MessageType::Request),
+                Some(MessageType::Release)
+            ]
+        );
+        assert_eq!(received[2].ciaddr, Ipv4Addr::new(10, 0, 0, 5));
+    }
+
+    #[test]
+    fn retransmits_without_usable_reply() {
+        // Garbage is skipped like silence, the DISCOVER is sent again in both cases
+        let transport = ScriptedTransport::new(vec![
+            Reply::Silence,
+            Reply::Garbage,
+            Reply::Message(MessageType::Offer),
+            Reply::Message(MessageType::Ack),
+        ]);
+        let lease = DhcpService::new(&config(), &settings(&transport))
+            .and_then(|s| s.get_lease())
+            .unwrap_or_else(|e| panic!("no lease: {e}"));
+        assert_eq!(lease.yiaddr, "10.0.0.5");
+        let received = transport.received();
+        assert_eq!(
+            kinds(&received),
+            vec![
+                Some(MessageType::Discover),
+                Some(MessageType::Discover),
+                Some(MessageType::Discover),
+                Some(MessageType::Request)
+            ]
+        );
+        assert!(received.iter().all(|m| m.xid == received[0].xid));
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let transport = ScriptedTransport::new(vec![]);
+        let err = DhcpService::new(&config(), &settings(&transport))
+            .and_then(|s| s.get_lease())
+            .expect_err("got a lease from a silent server");
+        assert_eq!(Status::from(err).code(), Code::Aborted);
+        // 50ms, 100ms, 200ms and 400ms go by before the timeout of a second
+        let received = transport.received();
+        assert_eq!(received.len(), 5);
+        assert!(received
+            .iter()
+            .all(|m| m.message_type() == Some(MessageType::Discover)));
+    }
+
+    #[test]
+    fn refused_leases() {
+        let metrics = Arc::new(Metrics::new());
+        let transport = ScriptedTransport::new(vec![
+            Reply::Message(MessageType::Offer),
+            Reply::Message(MessageType::Nak),
+        ]);
+        let err =
+            DhcpService::requesting(&config(), Ipv4Addr::new(10, 0, 0, 9), &settings(&transport))
+                .and_then(|s| s.with_metrics(metrics.clone()).get_lease())
+                .expect_err("lease was not refused");
+        assert_eq!(Status::from(err).code(), Code::NotFound);
+        // The requested address is asked for in the DISCOVER, which every server answers
+        let received = transport.received();
+        assert_eq!(received[0].message_type(), Some(MessageType::Discover));
+        assert_eq!(received[0].option(50), Some(&[10, 0, 0, 9][..]));
+
+        // An ACK that does not make a lease is refused by the proxy
+        let transport = ScriptedTransport::new(vec![
+            Reply::Message(MessageType::Offer),
+            Reply::Malformed(MessageType::Ack),
+        ]);
+        let err = DhcpService::new(&config(), &settings(&transport))
+            .and_then(|s| s.with_metrics(metrics.clone()).get_lease())
+            .expect_err("malformed lease was accepted");
+        assert_eq!(Status::from(err).code(), Code::NotFound);
+
+        let rendered = metrics.render(&HashMap::new());
+        assert!(rendered.contains("nv_proxy_dhcp_naks_total 1"));
+        assert!(rendered.contains("nv_proxy_dhcp_invalid_replies_total 1"));
+    }
+
+    #[test]
+    fn renewal() {
+        let metrics = Arc::new(Metrics::new());
+        let transport = ScriptedTransport::new(vec![Reply::Message(MessageType::Ack)]);
+        let lease = NetavarkLease {
+            yiaddr: "10.0.0.5".to_string(),
+            siaddr: "0.0.0.0".to_string(),
+            srv_id: "10.0.0.1".to_string(),
+            subnet_mask: "255.255.255.0".to_string(),
+            ..Default::default()
+        };
+        let renewed = DhcpService::with_lease(&config(), &lease, &settings(&transport))
+            .and_then(|s| s.with_metrics(metrics.clone()).get_lease())
+            .unwrap_or_else(|e| panic!("lease was not renewed: {e}"));
+        assert_eq!(renewed.yiaddr, lease.yiaddr);
+        // RENEWING asks for the leased address in ciaddr
+        let received = transport.received();
+        assert_eq!(received[0].ciaddr, Ipv4Addr::new(10, 0, 0, 5));
+        assert!(metrics
+            .render(&HashMap::new())
+            .contains("nv_proxy_renewals_total{result=\"success\"} 1"));
+    }
+}
@@ -5,16 +5,39 @@
    Long term this file/function should move into netavark
 */
 
-use crate::g_rpc::{Lease as NetavarkLease, Lease};
+use crate::g_rpc::{Lease as NetavarkLease, Lease, NetworkConfig};
 use crate::types::{CustomErr, ProxyError};
-use ipnet::IpNet;
-use log::debug;
+use ipnet::{IpNet, Ipv4Net};
+use log::{debug, error};
+use macaddr::MacAddr;
+use netlink_packet_core::{
+    NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_REPLACE, NLM_F_REQUEST,
+};
+use netlink_packet_route::address::Nla;
+use netlink_packet_route::link::nlas::{Info, InfoKind, Nla as LinkNla};
+use netlink_packet_route::route::Nla as RouteNla;
+use netlink_packet_route::{
+    AddressMessage, LinkMessage, RouteMessage, RtnlMessage, AF_INET, AF_INET6, RTNLGRP_LINK,
+    RTN_UNICAST, RTPROT_STATIC, RT_SCOPE_UNIVERSE, RT_TABLE_MAIN,
+};
+use netlink_sys::protocols::NETLINK_ROUTE;
+use netlink_sys::SocketAddr;
+use nix::errno::Errno;
+use nix::sched::{setns, CloneFlags};
 use nv::network::core_utils;
 use nv::network::netlink;
 use nv::network::netlink::Socket;
+use std::fmt;
+use std::fs::File;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::str::FromStr;
 
+// Address lifetime the kernel treats as forever
+const INFINITY_LIFE_TIME: u32 = u32::MAX;
+
 trait IpConv {
     fn to_v4(&self) -> Result<&Ipv4Addr, ProxyError>;
     fn to_v6(&self) -> Result<&Ipv6Addr, ProxyError>;
@@ -47,23 +70,56 @@ impl IpConv for IpAddr {
    domain and host names, etc. will be implemented in podman; not here.
 */
 
+/// The kind of link the container interface is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkKind {
+    MacVlan,
+    /// Shares the mac address of its parent interface
+    IpVlan,
+}
+
+impl LinkKind {
+    pub fn of(nc: &NetworkConfig) -> LinkKind {
+        if nc.ipvlan {
+            LinkKind::IpVlan
+        } else {
+            LinkKind::MacVlan
+        }
+    }
+}
+
+impl fmt::Display for LinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkKind::MacVlan => write!(f, "macvlan"),
+            LinkKind::IpVlan => write!(f, "ipvlan"),
+        }
+    }
+}
+
+// The address of a lease on a macvlan or ipvlan link, which is configured the same way
 #[derive(Clone, Debug)]
-struct MacVLAN {
+struct VlanAddress {
     address: IpAddr,
     gateways: Vec<IpNet>,
     interface: String,
     // Unset right now
     // mtu: u32,
     prefix_length: u8,
+    // seconds the kernel keeps the address, from the lease time
+    valid_lft: u32,
+    // seconds the address is preferred, from t1
+    preferred_lft: u32,
 }
 
 trait Address<T> {
     fn new(l: &Lease, interface: &str) -> Result<Self, ProxyError>
     where
         Self: Sized;
-    fn add_ip(&self, nls: &mut Socket) -> Result<(), ProxyError>;
+    fn add_ip(&self, nls: &mut Socket, ns_path: &str) -> Result<(), ProxyError>;
     fn add_gws(&self, nls: &mut Socket) -> Result<(), ProxyError>;
-    fn remove(self) -> Result<(), ProxyError>;
+    fn replace_gws(&self, nls: &mut Socket, ns_path: &str) -> Result<(), ProxyError>;
+    fn remove(&self, nls: &mut Socket) -> Result<(), ProxyError>;
 }
 
 fn handle_gws(g: Vec<String>, netmask: &str) -> Result<Vec<IpNet>, ProxyError> {
@@ -110,9 +166,9 @@ fn test_handle_gws() {
     assert!(handle_gws(gws, netmask).is_ok())
 }
 // IPV4 implementation
-impl Address<Ipv4Addr> for MacVLAN {
-    fn new(l: &NetavarkLease, interface: &str) -> Result<MacVLAN, ProxyError> {
-        debug!("new ipv4 macvlan for {}", interface);
+impl Address<Ipv4Addr> for VlanAddress {
+    fn new(l: &NetavarkLease, interface: &str) -> Result<VlanAddress, ProxyError> {
+        debug!("new ipv4 address for {}", interface);
         let address = match IpAddr::from_str(&l.yiaddr) {
             Ok(a) => a,
             Err(e) => {
@@ -129,25 +185,33 @@ impl Address<Ipv4Addr> for MacVLAN {
             Ok(u) => u as u8,
             Err(e) => return Err(ProxyError::new(e.to_string())),
         };
-        Ok(MacVLAN {
+        let (valid_lft, preferred_lft) = get_lifetimes(l.lease_time, l.t1);
+        Ok(VlanAddress {
             address,
             gateways,
             interface: interface.to_string(),
             // Disabled for now
             // mtu: l.mtu,
             prefix_length,
+            valid_lft,
+            preferred_lft,
         })
     }
 
-    //  add the ip address to the container namespace
-    fn add_ip(&self, nls: &mut Socket) -> Result<(), ProxyError> {
+    //  add the ip address to the container namespace.  The address carries
+    //  the lifetimes of the lease, so should the proxy go away the kernel
+    //  removes the address once the lease runs out.  Adding an existing
+    //  address replaces it, which refreshes its lifetimes.
+    fn add_ip(&self, nls: &mut Socket, ns_path: &str) -> Result<(), ProxyError> {
         debug!("adding network information for {}", self.interface);
         let ip = IpNet::new(self.address, self.prefix_length)?;
         let dev = nls.get_link(netlink::LinkID::Name(self.interface.clone()))?;
-        match nls.add_addr(dev.header.index, &ip) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(ProxyError::new(e.to_string())),
-        }
+        AddressSocket::open(ns_path)?.replace_addr(
+            dev.header.index,
+            &ip,
+            self.valid_lft,
+            self.preferred_lft,
+        )
     }
 
     // add one or more routes to the container namespace
@@ -159,32 +223,137 @@ impl Address<Ipv4Addr> for MacVLAN {
         }
     }
 
+    // add the routes to the container namespace, replacing routes that are
+    // already there instead of failing on them
+    fn replace_gws(&self, nls: &mut Socket, ns_path: &str) -> Result<(), ProxyError> {
+        debug!("replacing gateways on {}", self.interface);
+        let dev = nls.get_link(netlink::LinkID::Name(self.interface.clone()))?;
+        let socket = AddressSocket::open(ns_path)?;
+        for gw in &self.gateways {
+            if let IpAddr::V4(ip) = gw.addr() {
+                socket.replace_default_route(dev.header.index, ip)?;
+            }
+        }
+        Ok(())
+    }
+
     /*
-       For now, nv will remove the interface; this causes all IP stuff
-       to fold.
+       On container teardown nv removes the interface, which causes all
+       IP stuff to fold.  When a lease lapses the interface stays, so the
+       routes and the address have to be taken off explicitly.
     */
-    fn remove(self) -> Result<(), ProxyError> {
-        debug!("removing interface {}", self.interface);
-        todo!()
+    fn remove(&self, nls: &mut Socket) -> Result<(), ProxyError> {
+        debug!("removing network information from {}", self.interface);
+        for gw in &self.gateways {
+            let gw = match gw.addr() {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(_) => continue,
+            };
+            let route = netlink::Route::Ipv4 {
+                dest: Ipv4Net::default(),
+                gw,
+                metric: None,
+            };
+            // The kernel may have dropped the route already, that is fine
+            if let Err(e) = nls.del_route(&route) {
+                debug!("could not remove route via {}: {}", gw, e);
+            }
+        }
+        let ip = IpNet::new(self.address, self.prefix_length)?;
+        let dev = nls.get_link(netlink::LinkID::Name(self.interface.clone()))?;
+        match nls.del_addr(dev.header.index, &ip) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ProxyError::new(e.to_string())),
+        }
     }
 }
 
 // setup takes the DHCP lease and some additional information and
 // applies the TCP/IP information to the namespace.
-pub fn setup(lease: &NetavarkLease, interface: &str, ns_path: &str) -> Result<(), ProxyError> {
-    debug!("setting up {}", interface);
-    let vlan = match MacVLAN::new(lease, interface) {
+pub fn setup(
+    lease: &NetavarkLease,
+    interface: &str,
+    ns_path: &str,
+    kind: LinkKind,
+) -> Result<(), ProxyError> {
+    debug!("setting up {} {}", kind, interface);
+    // The lease of an ipvlan link was requested for the container rather than for
+    // the mac address, it must not end up on another kind of link
+    if kind == LinkKind::IpVlan && !AddressSocket::open(ns_path)?.is_ipvlan(interface)? {
+        return Err(ProxyError::new(format!(
+            "{interface} is not an ipvlan link"
+        )));
+    }
+    let vlan = match VlanAddress::new(lease, interface) {
         Ok(f) => f,
         Err(e) => return Err(e),
     };
     let (_, mut netns) = core_utils::open_netlink_sockets(ns_path)?;
-    vlan.add_ip(&mut netns.netlink)?;
+    vlan.add_ip(&mut netns.netlink, ns_path)?;
     vlan.add_gws(&mut netns.netlink)
 }
 
-// teardown is likely unnecessary but holding place here
-pub fn teardown() -> Result<(), ProxyError> {
-    todo!()
+// refresh re-applies the address of a renewed lease so the kernel
+// starts the address lifetimes over.
+pub fn refresh(lease: &NetavarkLease, interface: &str, ns_path: &str) -> Result<(), ProxyError> {
+    debug!("refreshing address lifetimes on {}", interface);
+    let vlan = VlanAddress::new(lease, interface)?;
+    let (_, mut netns) = core_utils::open_netlink_sockets(ns_path)?;
+    vlan.add_ip(&mut netns.netlink, ns_path)
+}
+
+// reapply puts back whatever the namespace is missing of a lease that
+// was set up before, without failing on what is still in place.
+pub fn reapply(lease: &NetavarkLease, interface: &str, ns_path: &str) -> Result<(), ProxyError> {
+    debug!("re-applying the lease on {}", interface);
+    let vlan = VlanAddress::new(lease, interface)?;
+    let (_, mut netns) = core_utils::open_netlink_sockets(ns_path)?;
+    vlan.add_ip(&mut netns.netlink, ns_path)?;
+    vlan.replace_gws(&mut netns.netlink, ns_path)
+}
+
+// teardown takes the DHCP lease and removes the TCP/IP information
+// that setup applied to the namespace.
+pub fn teardown(lease: &NetavarkLease, interface: &str, ns_path: &str) -> Result<(), ProxyError> {
+    debug!("tearing down {}", interface);
+    let vlan = VlanAddress::new(lease, interface)?;
+    let (_, mut netns) = core_utils::open_netlink_sockets(ns_path)?;
+    vlan.remove(&mut netns.netlink)
+}
+
+// interface_exists checks whether the interface is still in the namespace.
+// A namespace that no longer exists has no interfaces left.
+pub fn interface_exists(interface: &str, ns_path: &str) -> Result<bool, ProxyError> {
+    if !Path::new(ns_path).exists() {
+        return Ok(false);
+    }
+    AddressSocket::open(ns_path)?.has_link(interface)
+}
+
+// interface_is_link_of checks that the interface in the namespace has the given mac
+// address and is a link of the given interface of the host.
+pub fn interface_is_link_of(
+    interface: &str,
+    mac_address: &str,
+    parent: &str,
+    ns_path: &str,
+) -> Result<bool, ProxyError> {
+    let mac = MacAddr::from_str(mac_address)
+        .map_err(|e| ProxyError::new(format!("invalid mac address {mac_address}: {e}")))?;
+    let parent = nix::net::if_::if_nametoindex(parent)?;
+    let link = match AddressSocket::open(ns_path)?.get_link(interface)? {
+        Some(link) => link,
+        None => return Ok(false),
+    };
+    let has_mac = link
+        .nlas
+        .iter()
+        .any(|nla| matches!(nla, LinkNla::Address(a) if a.as_slice() == mac.as_bytes()));
+    let has_parent = link
+        .nlas
+        .iter()
+        .any(|nla| matches!(nla, LinkNla::Link(index) if *index == parent));
+    Ok(has_mac && has_parent)
 }
 
 /// get_prefix_lengh takes a subnet mask in str form and
@@ -209,6 +378,265 @@ fn get_prefix_length_v4(netmask: &str) -> Result<u32, ProxyError> {
     Ok(u32::from(sub_mask).count_ones())
 }
 
+/// get_lifetimes returns the valid and preferred lifetime of an address
+/// from the lease time and t1 of its lease.
+///
+/// # Arguments
+///
+/// * `lease_time`: lease time in seconds
+/// * `t1`: renewal time in seconds, 0 if the server did not send one
+///
+/// returns: (u32, u32)
+fn get_lifetimes(lease_time: u32, t1: u32) -> (u32, u32) {
+    // A lease without a lease time never runs out
+    if lease_time == 0 || lease_time == INFINITY_LIFE_TIME {
+        return (INFINITY_LIFE_TIME, INFINITY_LIFE_TIME);
+    }
+    // Without t1 the lease is renewed halfway, as per RFC 2131
+    let preferred = match t1 {
+        0 => lease_time / 2,
+        t => t.min(lease_time),
+    };
+    (lease_time, preferred)
+}
+
+/// A netlink route socket opened in a container network namespace.
+///
+/// The netavark socket can not set address lifetimes, so addresses are
+/// added through this one instead. It is also used to look up links
+/// and replace routes without treating the current state as an error.
+struct AddressSocket {
+    socket: netlink_sys::Socket,
+}
+
+impl AddressSocket {
+    /// Open a netlink route socket in the network namespace at ns_path. A
+    /// netlink socket belongs to the namespace it was created in, so only
+    /// the creation happens inside of the namespace.
+    fn open(ns_path: &str) -> Result<AddressSocket, ProxyError> {
+        let netns = NamespaceGuard::enter(ns_path)?;
+        let socket = netlink_sys::Socket::new(NETLINK_ROUTE);
+        drop(netns);
+        let mut socket = socket?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(AddressSocket { socket })
+    }
+
+    /// Add or replace an address on a link with the given lifetimes in seconds
+    fn replace_addr(
+        &self,
+        link_id: u32,
+        ip: &IpNet,
+        valid_lft: u32,
+        preferred_lft: u32,
+    ) -> Result<(), ProxyError> {
+        let mut msg = AddressMessage::default();
+        msg.header.index = link_id;
+        msg.header.prefix_len = ip.prefix_len();
+        match ip {
+            IpNet::V4(v4) => {
+                msg.header.family = AF_INET as u8;
+                let addr = v4.addr().octets().to_vec();
+                msg.nlas.push(Nla::Local(addr.clone()));
+                msg.nlas.push(Nla::Address(addr));
+                if v4.prefix_len() < 31 {
+                    msg.nlas
+                        .push(Nla::Broadcast(v4.broadcast().octets().to_vec()));
+                }
+            }
+            IpNet::V6(v6) => {
+                msg.header.family = AF_INET6 as u8;
+                msg.nlas.push(Nla::Address(v6.addr().octets().to_vec()));
+            }
+        }
+        // struct ifa_cacheinfo, the timestamps are ignored by the kernel
+        let mut cache_info = vec![0; 16];
+        cache_info[0..4].copy_from_slice(&preferred_lft.to_ne_bytes());
+        cache_info[4..8].copy_from_slice(&valid_lft.to_ne_bytes());
+        msg.nlas.push(Nla::CacheInfo(cache_info));
+
+        let mut req = NetlinkMessage::from(RtnlMessage::NewAddress(msg));
+        req.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE;
+        req.finalize();
+        let ack = self.request(&req)?;
+        match ack.payload {
+            NetlinkPayload::Error(e) => match e.code {
+                Some(code) => Err(ProxyError::new(format!(
+                    "failed to add address {}: {}",
+                    ip,
+                    io::Error::from_raw_os_error(-code.get())
+                ))),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Add a default route through the gateway, or replace the one that is there
+    fn replace_default_route(&self, link_id: u32, gw: Ipv4Addr) -> Result<(), ProxyError> {
+        let mut msg = RouteMessage::default();
+        msg.header.address_family = AF_INET as u8;
+        msg.header.table = RT_TABLE_MAIN;
+        msg.header.protocol = RTPROT_STATIC;
+        msg.header.scope = RT_SCOPE_UNIVERSE;
+        msg.header.kind = RTN_UNICAST;
+        msg.nlas.push(RouteNla::Gateway(gw.octets().to_vec()));
+        msg.nlas.push(RouteNla::Oif(link_id));
+
+        let mut req = NetlinkMessage::from(RtnlMessage::NewRoute(msg));
+        req.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE;
+        req.finalize();
+        let ack = self.request(&req)?;
+        match ack.payload {
+            NetlinkPayload::Error(e) => match e.code {
+                Some(code) => Err(ProxyError::new(format!(
+                    "failed to add route via {}: {}",
+                    gw,
+                    io::Error::from_raw_os_error(-code.get())
+                ))),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a link with the given name exists in the namespace
+    fn has_link(&self, name: &str) -> Result<bool, ProxyError> {
+        Ok(self.get_link(name)?.is_some())
+    }
+
+    /// Whether the link with the given name is an ipvlan link
+    fn is_ipvlan(&self, name: &str) -> Result<bool, ProxyError> {
+        let link = match self.get_link(name)? {
+            Some(link) => link,
+            None => return Err(ProxyError::new(format!("link {name} does not exist"))),
+        };
+        Ok(link.nlas.iter().any(|nla| match nla {
+            LinkNla::Info(infos) => infos
+                .iter()
+                .any(|info| matches!(info, Info::Kind(InfoKind::IpVlan))),
+            _ => false,
+        }))
+    }
+
+    /// The link with the given name, None when the namespace has no such link
+    fn get_link(&self, name: &str) -> Result<Option<LinkMessage>, ProxyError> {
+        let mut msg = LinkMessage::default();
+        msg.nlas.push(LinkNla::IfName(name.to_string()));
+        let mut req = NetlinkMessage::from(RtnlMessage::GetLink(msg));
+        req.header.flags = NLM_F_REQUEST;
+        req.finalize();
+        let reply = self.request(&req)?;
+        match reply.payload {
+            NetlinkPayload::InnerMessage(RtnlMessage::NewLink(link)) => Ok(Some(link)),
+            NetlinkPayload::Error(e) => match e.code {
+                Some(code) if -code.get() == Errno::ENODEV as i32 => Ok(None),
+                Some(code) => Err(ProxyError::new(format!(
+                    "failed to get link {}: {}",
+                    name,
+                    io::Error::from_raw_os_error(-code.get())
+                ))),
+                None => Err(ProxyError::new(format!("no link {name} in netlink reply"))),
+            },
+            _ => Err(ProxyError::new(format!(
+                "unexpected netlink reply for link {name}"
+            ))),
+        }
+    }
+
+    /// Send a request and return the first reply
+    fn request(
+        &self,
+        req: &NetlinkMessage<RtnlMessage>,
+    ) -> Result<NetlinkMessage<RtnlMessage>, ProxyError> {
+        let mut buf = vec![0; req.buffer_len()];
+        req.serialize(&mut buf);
+        self.socket.send(&buf, 0)?;
+
+        let mut resp = Vec::with_capacity(4096);
+        self.socket.recv(&mut resp, 0)?;
+        match NetlinkMessage::<RtnlMessage>::deserialize(&resp) {
+            Ok(m) => Ok(m),
+            Err(e) => Err(ProxyError::new(format!("bad netlink reply: {e}"))),
+        }
+    }
+}
+
+/// Keeps the calling thread in a network namespace until it is dropped. Sockets
+/// opened meanwhile stay in that namespace.
+pub struct NamespaceGuard {
+    host_ns: File,
+}
+
+impl NamespaceGuard {
+    /// Switch the calling thread into the namespace at the given path
+    pub fn enter(ns_path: &str) -> Result<NamespaceGuard, ProxyError> {
+        let host_ns = File::open("/proc/thread-self/ns/net")?;
+        let container_ns = File::open(ns_path)?;
+        setns(container_ns.as_raw_fd(), CloneFlags::CLONE_NEWNET)?;
+        Ok(NamespaceGuard { host_ns })
+    }
+}
+
+impl Drop for NamespaceGuard {
+    /// Return to the host namespace. A thread that can not return would run whatever
+    /// is scheduled on it next inside the container, so the proxy aborts instead.
+    fn drop(&mut self) {
+        if let Err(e) = setns(self.host_ns.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+            error!("could not return to the host network namespace: {}", e);
+            std::process::abort();
+        }
+    }
+}
+
+/// Watches the links of the host network namespace. Removing a parent
+/// interface takes the container interfaces on top of it along.
+pub struct LinkMonitor {
+    socket: netlink_sys::Socket,
+}
+
+impl LinkMonitor {
+    /// Subscribe to the link changes of the host
+    pub fn open() -> Result<LinkMonitor, ProxyError> {
+        let mut socket = netlink_sys::Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        socket.add_membership(RTNLGRP_LINK)?;
+        Ok(LinkMonitor { socket })
+    }
+
+    /// Block until links are removed from the host and return their names
+    pub fn removed_links(&self) -> Result<Vec<String>, ProxyError> {
+        loop {
+            let mut buf = Vec::with_capacity(65536);
+            self.socket.recv(&mut buf, 0)?;
+            let mut removed = Vec::new();
+            let mut offset = 0;
+            // A single read can hold several messages
+            while offset < buf.len() {
+                let msg = match NetlinkMessage::<RtnlMessage>::deserialize(&buf[offset..]) {
+                    Ok(m) => m,
+                    Err(e) => return Err(ProxyError::new(format!("bad netlink message: {e}"))),
+                };
+                if msg.header.length == 0 {
+                    break;
+                }
+                offset += msg.header.length as usize;
+                if let NetlinkPayload::InnerMessage(RtnlMessage::DelLink(link)) = msg.payload {
+                    for nla in link.nlas {
+                        if let LinkNla::IfName(name) = nla {
+                            removed.push(name);
+                        }
+                    }
+                }
+            }
+            if !removed.is_empty() {
+                return Ok(removed);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -232,4 +660,30 @@ mod tests {
     fn test_bad_input() {
         assert!(get_prefix_length_v4("255.255.128").is_err())
     }
+
+    #[test]
+    fn test_missing_namespace() {
+        assert!(!interface_exists("eth0", "/run/netns/nv-proxy-does-not-exist").unwrap());
+        assert!(NamespaceGuard::enter("/run/netns/nv-proxy-does-not-exist").is_err());
+    }
+
+    #[test]
+    fn test_lifetimes() {
+        assert_eq!(get_lifetimes(3600, 1800), (3600, 1800));
+        // no t1 means renewing halfway
+        assert_eq!(get_lifetimes(3600, 0), (3600, 1800));
+        assert_eq!(get_lifetimes(3600, 7200), (3600, 3600));
+    }
+
+    #[test]
+    fn test_infinite_lifetimes() {
+        assert_eq!(
+            get_lifetimes(u32::MAX, 1800),
+            (INFINITY_LIFE_TIME, INFINITY_LIFE_TIME)
+        );
+        assert_eq!(
+            get_lifetimes(0, 0),
+            (INFINITY_LIFE_TIME, INFINITY_LIFE_TIME)
+        );
+    }
 }
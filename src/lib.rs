@@ -1,35 +1,69 @@
 extern crate core;
 
-use crate::g_rpc::{Lease, NetworkConfig};
+use crate::g_rpc::{
+    InspectRequest, InspectResponse, Lease, LeaseEvent, NetworkConfig, ProxyInfo, WatchRequest,
+};
 use std::error::Error;
 
+pub mod audit;
+pub mod auth;
 pub mod cache;
 pub mod dhcp_service;
 pub mod ip;
+pub mod metrics;
+pub mod proxy_client;
 pub mod proxy_conf;
+pub mod relay;
 pub mod types;
 
-use crate::g_rpc::netavark_proxy_client::NetavarkProxyClient;
-use http::Uri;
-use log::debug;
+use crate::types::{CustomErr, ProxyError};
+use macaddr::MacAddr;
 use std::fs::File;
 use std::net::AddrParseError;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
-use tokio::net::UnixStream;
-use tonic::transport::{Channel, Endpoint};
-use tonic::{Request, Status};
-use tower::service_fn;
+use tonic::{Status, Streaming};
+use tonic_health::proto::HealthCheckResponse;
+
+pub use crate::proxy_client::{ClientTls, ProxyClient, ProxyEndpoint};
 
 #[allow(clippy::unwrap_used)]
 pub mod g_rpc {
     include!("../proto-build/netavark_proxy.rs");
+    use crate::proxy_conf::{API_VERSION, CAP_IPVLAN, CAP_REQUESTED_ADDRESS, CAP_V6};
     use crate::types::{CustomErr, ProxyError};
     use crate::VectorConv;
     use mozim::DhcpV4Lease;
+    use serde::Serializer;
     use std::net::Ipv4Addr;
     use std::str::FromStr;
 
+    impl WatchRequest {
+        /// Whether a lease event passes the filters of the watch request
+        pub fn matches(&self, event: &LeaseEvent) -> bool {
+            if !self.mac_address.is_empty()
+                && !self.mac_address.eq_ignore_ascii_case(&event.mac_address)
+            {
+                return false;
+            }
+            if !self.interface.is_empty()
+                && self.interface != event.host_iface
+                && self.interface != event.container_iface
+            {
+                return false;
+            }
+            true
+        }
+    }
+
+    /// Lease event kinds are written out by name instead of their proto number
+    pub fn serialize_event_kind<S: Serializer>(kind: &i32, s: S) -> Result<S::Ok, S::Error> {
+        match LeaseEventKind::from_i32(*kind) {
+            Some(k) => s.serialize_str(k.as_str_name()),
+            None => s.serialize_i32(*kind),
+        }
+    }
+
     impl Lease {
         /// Add mac address to a lease
         pub fn add_mac_address(&mut self, mac_addr: &String) {
@@ -117,6 +151,49 @@ pub mod g_rpc {
         }
     }
 
+    impl NetworkConfig {
+        /// The optional features of the proxy a setup of this configuration needs
+        pub fn required_capabilities(&self) -> Vec<&'static str> {
+            let mut required = Vec::new();
+            if self.version == Version::V6 as i32 {
+                required.push(CAP_V6);
+            }
+            if self.ipvlan {
+                required.push(CAP_IPVLAN);
+            }
+            if !self.requested_address.is_empty() {
+                required.push(CAP_REQUESTED_ADDRESS);
+            }
+            required
+        }
+    }
+
+    impl ProxyInfo {
+        /// Check that a client of this version can set up the configuration with the
+        /// proxy. A proxy that predates GetInfo reports no API version and no capabilities.
+        pub fn check(&self, nc: &NetworkConfig) -> Result<(), tonic::Status> {
+            let proxy = if self.version.is_empty() {
+                "the proxy".to_string()
+            } else {
+                format!("proxy {}", self.version)
+            };
+            if self.api_version != 0 && self.api_version != API_VERSION {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "{} speaks API version {}, this client speaks version {}",
+                    proxy, self.api_version, API_VERSION
+                )));
+            }
+            for capability in nc.required_capabilities() {
+                if !self.capabilities.iter().any(|c| c == capability) {
+                    return Err(tonic::Status::failed_precondition(format!(
+                        "{proxy} does not support {capability}"
+                    )));
+                }
+            }
+            Ok(())
+        }
+    }
+
     fn handle_ip_vectors(ip: Option<Vec<std::net::Ipv4Addr>>) -> Vec<String> {
         let mut ips: Vec<String> = Vec::new();
         if let Some(j) = ip {
@@ -148,6 +225,55 @@ pub mod g_rpc {
         }
     }
 
+    #[test]
+    fn test_watch_request_matches() {
+        let event = LeaseEvent {
+            kind: LeaseEventKind::Acquired as i32,
+            mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+            host_iface: "eth0".to_string(),
+            container_iface: "eth1".to_string(),
+            ..Default::default()
+        };
+        assert!(WatchRequest::default().matches(&event));
+        let mut request = WatchRequest {
+            mac_address: "aa:bb:cc:dd:ee:ff".to_string(),
+            interface: "eth1".to_string(),
+        };
+        assert!(request.matches(&event));
+        request.interface = "eth2".to_string();
+        assert!(!request.matches(&event));
+        request.interface = String::new();
+        request.mac_address = "aa:bb:cc:dd:ee:00".to_string();
+        assert!(!request.matches(&event));
+    }
+
+    #[test]
+    fn test_proxy_info_check() {
+        let mut nc = NetworkConfig::default();
+        let info = ProxyInfo {
+            version: "1.0.0".to_string(),
+            api_version: API_VERSION,
+            capabilities: vec![CAP_IPVLAN.to_string()],
+        };
+        assert!(info.check(&nc).is_ok());
+        // A proxy without GetInfo can set up plain configurations
+        assert!(ProxyInfo::default().check(&nc).is_ok());
+
+        nc.ipvlan = true;
+        assert!(info.check(&nc).is_ok());
+        assert!(ProxyInfo::default().check(&nc).is_err());
+        nc.version = Version::V6 as i32;
+        let err = info.check(&nc).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(err.message(), "proxy 1.0.0 does not support v6");
+
+        let newer = ProxyInfo {
+            api_version: API_VERSION + 1,
+            ..info
+        };
+        assert!(newer.check(&NetworkConfig::default()).is_err());
+    }
+
     #[test]
     fn test_handle_gw() {
         use std::str::FromStr;
@@ -171,43 +297,88 @@ impl NetworkConfig {
         Ok(serde_json::from_reader(file)?)
     }
 
-    /// get_client is an internal function to obtain the uds endpoint
+    /// Check that a configuration built by hand has what a setup needs, before it is
+    /// sent to the proxy
+    pub fn validate_setup(&self) -> Result<(), ProxyError> {
+        for (name, value) in [
+            ("host interface", &self.host_iface),
+            ("container interface", &self.container_iface),
+            ("network namespace", &self.ns_path),
+        ] {
+            if value.is_empty() {
+                return Err(ProxyError::new(format!("no {name} given")));
+            }
+        }
+        if MacAddr::from_str(&self.container_mac_addr).is_err() {
+            return Err(ProxyError::new(format!(
+                "invalid mac address {:?}",
+                self.container_mac_addr
+            )));
+        }
+        if !self.requested_address.is_empty() {
+            IpAddr::from_str(&self.requested_address)?;
+        }
+        Ok(())
+    }
+
+    /// Check that a configuration built by hand names the lease to tear down, by mac
+    /// address or container
+    pub fn validate_teardown(&self) -> Result<(), ProxyError> {
+        if !self.container_mac_addr.is_empty() {
+            if MacAddr::from_str(&self.container_mac_addr).is_err() {
+                return Err(ProxyError::new(format!(
+                    "invalid mac address {:?}",
+                    self.container_mac_addr
+                )));
+            }
+        } else if self.container_id.is_empty() {
+            return Err(ProxyError::new(
+                "no mac address or container id given".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The client identifier of a container on an ipvlan link. All containers on the
+    /// parent interface share its mac address, so the container and its interface tell
+    /// them apart instead. None when the mac address identifies the container.
+    pub fn client_id(&self) -> Option<String> {
+        if !self.ipvlan {
+            return None;
+        }
+        let container = if self.container_id.is_empty() {
+            &self.ns_path
+        } else {
+            &self.container_id
+        };
+        Some(format!("{}/{}", container, self.container_iface))
+    }
+
+    /// get_lease is a wrapper function for obtaining a lease
+    /// over grpc from the nvproxy-server
     ///
     /// # Arguments
     ///
-    /// * `p`: path to uds
+    /// * `p`: endpoint of the proxy
     ///
-    /// returns: Result<NetavarkProxyClient<Channel>, Status>
+    /// returns: Result<Lease, Status>
     ///
     /// # Examples
     ///
     /// ```
     ///
     /// ```
-    async fn get_client(p: String) -> Result<NetavarkProxyClient<Channel>, Status> {
-        // We do not know why the uds connections need to be done like this.  The
-        // maintainer suggested it is part of the their API.
-        let endpoint = Endpoint::try_from("http://[::1]:10000")
-            .map_err(|e| Status::internal(e.to_string()))?;
-
-        let channel = endpoint
-            .connect_with_connector(service_fn(move |_: Uri| {
-                let pp = p.clone();
-                debug!("using uds path: {}", pp);
-                UnixStream::connect(pp)
-            }))
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
-
-        Ok(NetavarkProxyClient::new(channel))
+    pub async fn get_lease(self, p: &ProxyEndpoint) -> Result<Lease, Status> {
+        ProxyClient::connect(p.clone()).await?.setup(self).await
     }
 
-    /// get_lease is a wrapper function for obtaining a lease
-    /// over grpc from the nvproxy-server
+    /// drop_lease is a wrapper function to release the current
+    /// DHCP lease via the nvproxy
+    ///
     ///
     /// # Arguments
     ///
-    /// * `p`: path to uds
+    /// * `p`: endpoint of the proxy
     ///
     /// returns: Result<Lease, Status>
     ///
@@ -216,39 +387,65 @@ impl NetworkConfig {
     /// ```
     ///
     /// ```
-    pub async fn get_lease(self, p: &str) -> Result<Lease, Status> {
-        let mut client = NetworkConfig::get_client(p.to_string()).await?;
-        let lease = match client.setup(Request::new(self)).await {
-            Ok(l) => l.into_inner(),
-            Err(s) => return Err(s),
-        };
-        Ok(lease)
+    pub async fn drop_lease(self, p: &ProxyEndpoint) -> Result<Lease, Status> {
+        ProxyClient::connect(p.clone()).await?.teardown(self).await
     }
-
-    /// drop_lease is a wrapper function to release the current
-    /// DHCP lease via the nvproxy
+}
+impl ProxyInfo {
+    /// get is a wrapper function to ask the nvproxy-server for its
+    /// version and capabilities
+    ///
+    /// # Arguments
     ///
+    /// * `p`: endpoint of the proxy
+    ///
+    /// returns: Result<ProxyInfo, Status>
+    pub async fn get(p: &ProxyEndpoint) -> Result<ProxyInfo, Status> {
+        ProxyClient::connect(p.clone()).await?.info().await
+    }
+}
+
+impl WatchRequest {
+    /// watch is a wrapper function to stream lease events from the
+    /// nvproxy-server that pass the filters of the request
     ///
     /// # Arguments
     ///
-    /// * `p`:  path to uds
+    /// * `p`: endpoint of the proxy
     ///
-    /// returns: Result<Lease, Status>
+    /// returns: Result<Streaming<LeaseEvent>, Status>
+    pub async fn watch(self, p: &ProxyEndpoint) -> Result<Streaming<LeaseEvent>, Status> {
+        ProxyClient::connect(p.clone()).await?.watch(self).await
+    }
+}
+
+impl InspectRequest {
+    /// inspect is a wrapper function to obtain the cached leases
+    /// that pass the filters of the request from the nvproxy-server
     ///
-    /// # Examples
+    /// # Arguments
     ///
-    /// ```
+    /// * `p`: endpoint of the proxy
     ///
-    /// ```
-    pub async fn drop_lease(self, p: &str) -> Result<Lease, Status> {
-        let mut client = NetworkConfig::get_client(p.to_string()).await?;
-        let lease = match client.teardown(Request::new(self)).await {
-            Ok(l) => l.into_inner(),
-            Err(e) => return Err(e),
-        };
-        Ok(lease)
+    /// returns: Result<InspectResponse, Status>
+    pub async fn inspect(self, p: &ProxyEndpoint) -> Result<InspectResponse, Status> {
+        ProxyClient::connect(p.clone()).await?.inspect(self).await
     }
 }
+
+/// health is a wrapper function to ask the nvproxy-server for its
+/// health through the standard grpc.health.v1.Health service
+///
+/// # Arguments
+///
+/// * `p`: endpoint of the proxy
+/// * `service`: name of the service to check, the empty name checks the whole server
+///
+/// returns: Result<HealthCheckResponse, Status>
+pub async fn health(p: &ProxyEndpoint, service: &str) -> Result<HealthCheckResponse, Status> {
+    ProxyClient::connect(p.clone()).await?.health(service).await
+}
+
 trait VectorConv {
     fn to_v4_addrs(&self) -> Result<Option<Vec<Ipv4Addr>>, AddrParseError>;
     fn to_v6_addrs(&self) -> Result<Option<Vec<Ipv6Addr>>, AddrParseError>;
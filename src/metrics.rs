@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tonic::Code;
+
+// Upper bounds in seconds of the DORA latency histogram buckets
+const DORA_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Counters of the proxy that are exported in the prometheus text format.
+///
+/// The gRPC service counts the requests it serves and the cache writes that failed, the
+/// dhcp service counts the DHCP exchanges it ran. NAKs are only counted in relay mode,
+/// mozim drops them and the exchange times out instead.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // requests by (method, result code)
+    requests: Mutex<BTreeMap<(&'static str, String), u64>>,
+    dora_buckets: [AtomicU64; DORA_BUCKETS.len()],
+    dora_count: AtomicU64,
+    // total DORA time in microseconds
+    dora_sum_us: AtomicU64,
+    renewals_ok: AtomicU64,
+    renewals_failed: AtomicU64,
+    expirations: AtomicU64,
+    naks: AtomicU64,
+    invalid_replies: AtomicU64,
+    cache_write_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Count a served gRPC request by its result code
+    pub fn observe_request(&self, method: &'static str, code: Code) {
+        match self.requests.lock() {
+            Ok(mut r) => *r.entry((method, format!("{code:?}"))).or_insert(0) += 1,
+            Err(e) => log::error!("{e}"),
+        }
+    }
+
+    /// Record how long a successful DORA took
+    pub fn observe_dora(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (i, bound) in DORA_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                self.dora_buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.dora_count.fetch_add(1, Ordering::Relaxed);
+        self.dora_sum_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_renewal(&self, success: bool) {
+        match success {
+            true => self.renewals_ok.fetch_add(1, Ordering::Relaxed),
+            false => self.renewals_failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Count a lease that ran out without being renewed
+    pub fn observe_expiry(&self) {
+        self.expirations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a DHCP server refusing a request. Only the relay client sees the NAKs.
+    pub fn observe_nak(&self) {
+        self.naks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a reply of a DHCP server that the proxy could not use
+    pub fn observe_invalid_reply(&self) {
+        self.invalid_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_cache_write_error(&self) {
+        self.cache_write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the prometheus text exposition format
+    ///
+    /// # Arguments
+    ///
+    /// * `active_leases`: number of active leases keyed by the parent interface
+    ///
+    /// returns: String
+    pub fn render(&self, active_leases: &HashMap<String, usize>) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_active_leases Leases currently held per parent interface.\n\
+             # TYPE nv_proxy_active_leases gauge"
+        );
+        let mut ifaces: Vec<_> = active_leases.iter().collect();
+        ifaces.sort();
+        for (iface, n) in ifaces {
+            let _ = writeln!(
+                out,
+                "nv_proxy_active_leases{{interface=\"{}\"}} {n}",
+                escape(iface)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_requests_total gRPC requests by method and result code.\n\
+             # TYPE nv_proxy_requests_total counter"
+        );
+        if let Ok(requests) = self.requests.lock() {
+            for ((method, code), n) in requests.iter() {
+                let _ = writeln!(
+                    out,
+                    "nv_proxy_requests_total{{method=\"{method}\",code=\"{code}\"}} {n}"
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_dora_duration_seconds Time taken to obtain a lease.\n\
+             # TYPE nv_proxy_dora_duration_seconds histogram"
+        );
+        for (i, bound) in DORA_BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "nv_proxy_dora_duration_seconds_bucket{{le=\"{bound}\"}} {}",
+                self.dora_buckets[i].load(Ordering::Relaxed)
+            );
+        }
+        let count = self.dora_count.load(Ordering::Relaxed);
+        let sum = self.dora_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(
+            out,
+            "nv_proxy_dora_duration_seconds_bucket{{le=\"+Inf\"}} {count}\n\
+             nv_proxy_dora_duration_seconds_sum {sum}\n\
+             nv_proxy_dora_duration_seconds_count {count}"
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_renewals_total Lease renewals by result.\n\
+             # TYPE nv_proxy_renewals_total counter\n\
+             nv_proxy_renewals_total{{result=\"success\"}} {}\n\
+             nv_proxy_renewals_total{{result=\"failure\"}} {}",
+            self.renewals_ok.load(Ordering::Relaxed),
+            self.renewals_failed.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_lease_expirations_total Leases that ran out without being renewed.\n\
+             # TYPE nv_proxy_lease_expirations_total counter\n\
+             nv_proxy_lease_expirations_total {}",
+            self.expirations.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_dhcp_naks_total Requests refused by a DHCP server, in relay mode only.\n\
+             # TYPE nv_proxy_dhcp_naks_total counter\n\
+             nv_proxy_dhcp_naks_total {}",
+            self.naks.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_dhcp_invalid_replies_total DHCP server replies the proxy could not use.\n\
+             # TYPE nv_proxy_dhcp_invalid_replies_total counter\n\
+             nv_proxy_dhcp_invalid_replies_total {}",
+            self.invalid_replies.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nv_proxy_cache_write_errors_total Failed writes of the lease file.\n\
+             # TYPE nv_proxy_cache_write_errors_total counter\n\
+             nv_proxy_cache_write_errors_total {}",
+            self.cache_write_errors.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+// Escape a label value as per the exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn render_requests() {
+        let metrics = Metrics::new();
+        metrics.observe_request("setup", Code::Ok);
+        metrics.observe_request("setup", Code::Ok);
+        metrics.observe_request("teardown", Code::NotFound);
+        let out = metrics.render(&HashMap::new());
+        assert!(out.contains("nv_proxy_requests_total{method=\"setup\",code=\"Ok\"} 2\n"));
+        assert!(out.contains("nv_proxy_requests_total{method=\"teardown\",code=\"NotFound\"} 1\n"));
+    }
+
+    #[test]
+    fn render_dora_histogram() {
+        let metrics = Metrics::new();
+        metrics.observe_dora(Duration::from_millis(300));
+        metrics.observe_dora(Duration::from_secs(60));
+        let out = metrics.render(&HashMap::new());
+        assert!(out.contains("nv_proxy_dora_duration_seconds_bucket{le=\"0.25\"} 0\n"));
+        assert!(out.contains("nv_proxy_dora_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(out.contains("nv_proxy_dora_duration_seconds_bucket{le=\"30\"} 1\n"));
+        assert!(out.contains("nv_proxy_dora_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("nv_proxy_dora_duration_seconds_sum 60.3\n"));
+    }
+
+    #[test]
+    fn render_active_leases() {
+        let metrics = Metrics::new();
+        let active = HashMap::from([("eth0".to_string(), 3), ("en\"1".to_string(), 1)]);
+        let out = metrics.render(&active);
+        assert!(out.contains("nv_proxy_active_leases{interface=\"eth0\"} 3\n"));
+        assert!(out.contains("nv_proxy_active_leases{interface=\"en\\\"1\"} 1\n"));
+    }
+}
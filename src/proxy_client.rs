@@ -0,0 +1,579 @@
+/*
+   Client of the proxy for netavark and other programs.
+
+   A ProxyClient connects once and sends every call over the same connection,
+   which is opened again when it breaks. Programs without a tokio runtime use
+   the BlockingProxyClient, which runs the calls on a runtime of its own.
+*/
+
+use crate::g_rpc::netavark_proxy_client::NetavarkProxyClient;
+use crate::g_rpc::{
+    Empty, InspectRequest, InspectResponse, Lease, LeaseEvent, NetworkConfig, OperationResponse,
+    ProxyInfo, WatchRequest,
+};
+use http::Uri;
+use log::debug;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tokio::runtime::{Builder, Runtime};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Request, Status, Streaming};
+use tonic_health::proto::health_client::HealthClient;
+use tonic_health::proto::{HealthCheckRequest, HealthCheckResponse};
+use tower::service_fn;
+
+/// Where a client reaches the proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyEndpoint {
+    /// Path of the unix socket of the proxy
+    Uds(String),
+    /// `https://host:port` url of the mutual TLS listener of the proxy
+    Tls { url: String, tls: ClientTls },
+}
+
+/// PEM files a client authenticates the proxy and itself with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientTls {
+    /// CA certificate the certificate of the proxy is verified against
+    pub ca: String,
+    /// Certificate of the client
+    pub cert: String,
+    /// Private key of the client certificate
+    pub key: String,
+}
+
+impl ProxyEndpoint {
+    /// Choose the endpoint the client flags describe. An endpoint that is given as a url is
+    /// reached over TCP and needs the TLS files, anything else is the path of a unix socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint`: uds path or `https://host:port` url
+    /// * `tls`: the TLS files, only used with a url
+    ///
+    /// returns: Result<ProxyEndpoint, String>
+    pub fn new(endpoint: &str, tls: Option<ClientTls>) -> Result<ProxyEndpoint, String> {
+        if !endpoint.contains("://") {
+            return Ok(ProxyEndpoint::Uds(endpoint.to_string()));
+        }
+        if !endpoint.starts_with("https://") {
+            return Err(format!("{endpoint} is not an https url"));
+        }
+        match tls {
+            Some(tls) => Ok(ProxyEndpoint::Tls {
+                url: endpoint.to_string(),
+                tls,
+            }),
+            None => Err(format!(
+                "{endpoint} needs a CA certificate, a client certificate and a key"
+            )),
+        }
+    }
+}
+
+impl From<&str> for ProxyEndpoint {
+    fn from(path: &str) -> Self {
+        ProxyEndpoint::Uds(path.to_string())
+    }
+}
+
+impl Display for ProxyEndpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyEndpoint::Uds(path) => write!(f, "{path}"),
+            ProxyEndpoint::Tls { url, .. } => write!(f, "{url}"),
+        }
+    }
+}
+
+/// How a ProxyClient connects and how long it waits for the proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Time to wait for a connection to the proxy
+    pub connect_timeout: Duration,
+    /// Time to wait for the answer to a call, none to wait as long as the proxy takes.
+    /// A setup waits for the DHCP server, so this should be longer than its timeout.
+    pub request_timeout: Option<Duration>,
+    /// Number of times to try to connect before giving up, at least one
+    pub connect_attempts: u32,
+    /// Time to wait before the second attempt to connect, doubled for every attempt after
+    pub retry_delay: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: None,
+            connect_attempts: 3,
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// A connection to the proxy with a method for every call of its API
+#[derive(Debug)]
+pub struct ProxyClient {
+    endpoint: ProxyEndpoint,
+    proxy: NetavarkProxyClient<Channel>,
+    health: HealthClient<Channel>,
+    // what the proxy told about itself, asked for once before the first setup
+    info: Mutex<Option<ProxyInfo>>,
+}
+
+impl ProxyClient {
+    /// Connect to the proxy with the default options
+    pub async fn connect(endpoint: ProxyEndpoint) -> Result<ProxyClient, Status> {
+        ProxyClient::connect_with(endpoint, ClientOptions::default()).await
+    }
+
+    /// Connect to the proxy, trying again as often as the options allow
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint`: where the proxy listens
+    /// * `options`: timeouts and retries of the connection
+    ///
+    /// returns: Result<ProxyClient, Status>
+    pub async fn connect_with(
+        endpoint: ProxyEndpoint,
+        options: ClientOptions,
+    ) -> Result<ProxyClient, Status> {
+        let mut delay = options.retry_delay;
+        let mut attempt = 1;
+        let channel = loop {
+            match connect(&endpoint, &options).await {
+                Ok(c) => break c,
+                // Bad TLS files do not get better by trying again
+                Err(e) if e.code() == Code::InvalidArgument => return Err(e),
+                Err(e) if attempt >= options.connect_attempts => return Err(e),
+                Err(e) => debug!(
+                    "attempt {} to connect to {} failed: {}",
+                    attempt,
+                    endpoint,
+                    e.message()
+                ),
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        };
+        Ok(ProxyClient {
+            endpoint,
+            proxy: NetavarkProxyClient::new(channel.clone()),
+            health: HealthClient::new(channel),
+            info: Mutex::new(None),
+        })
+    }
+
+    /// The endpoint the client is connected to
+    pub fn endpoint(&self) -> &ProxyEndpoint {
+        &self.endpoint
+    }
+
+    /// Get a lease for the configuration and set it up in the container namespace. The
+    /// proxy is checked to support what the configuration needs first.
+    pub async fn setup(&self, nc: NetworkConfig) -> Result<Lease, Status> {
+        self.compatible_info().await?.check(&nc)?;
+        let response = self.proxy.clone().setup(Request::new(nc)).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Release the lease of the configuration
+    pub async fn teardown(&self, nc: NetworkConfig) -> Result<Lease, Status> {
+        let response = self.proxy.clone().teardown(Request::new(nc)).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Drop all cached leases
+    pub async fn clean(&self) -> Result<OperationResponse, Status> {
+        let response = self.proxy.clone().clean(Request::new(Empty {})).await?;
+        Ok(response.into_inner())
+    }
+
+    /// The cached leases that pass the filters of the request
+    pub async fn inspect(&self, request: InspectRequest) -> Result<InspectResponse, Status> {
+        let response = self.proxy.clone().inspect(Request::new(request)).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Stream the events of the leases that pass the filters of the request
+    pub async fn watch(&self, request: WatchRequest) -> Result<Streaming<LeaseEvent>, Status> {
+        let response = self
+            .proxy
+            .clone()
+            .watch_leases(Request::new(request))
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// The version and capabilities of the proxy
+    pub async fn info(&self) -> Result<ProxyInfo, Status> {
+        let response = self.proxy.clone().get_info(Request::new(Empty {})).await?;
+        Ok(response.into_inner())
+    }
+
+    /// The health of a service of the proxy, the empty name checks the whole proxy
+    pub async fn health(&self, service: &str) -> Result<HealthCheckResponse, Status> {
+        let response = self
+            .health
+            .clone()
+            .check(Request::new(HealthCheckRequest {
+                service: service.to_string(),
+            }))
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// The info of the proxy to check configurations against. A proxy that predates
+    /// GetInfo is taken to support no optional features.
+    async fn compatible_info(&self) -> Result<ProxyInfo, Status> {
+        if let Some(info) = self.cached_info() {
+            return Ok(info);
+        }
+        let info = match self.info().await {
+            Ok(i) => i,
+            Err(s) if s.code() == Code::Unimplemented => ProxyInfo::default(),
+            Err(s) => return Err(s),
+        };
+        if let Ok(mut cached) = self.info.lock() {
+            *cached = Some(info.clone());
+        }
+        Ok(info)
+    }
+
+    fn cached_info(&self) -> Option<ProxyInfo> {
+        self.info.lock().ok().and_then(|i| i.clone())
+    }
+}
+
+/// A ProxyClient for programs without a tokio runtime. Every call blocks until the proxy
+/// answers. It must not be used from within a tokio runtime.
+#[derive(Debug)]
+pub struct BlockingProxyClient {
+    client: ProxyClient,
+    runtime: Runtime,
+}
+
+impl BlockingProxyClient {
+    /// Connect to the proxy, see ProxyClient::connect_with
+    pub fn connect(
+        endpoint: ProxyEndpoint,
+        options: ClientOptions,
+    ) -> Result<BlockingProxyClient, Status> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let client = runtime.block_on(ProxyClient::connect_with(endpoint, options))?;
+        Ok(BlockingProxyClient { client, runtime })
+    }
+
+    pub fn setup(&self, nc: NetworkConfig) -> Result<Lease, Status> {
+        self.runtime.block_on(self.client.setup(nc))
+    }
+
+    pub fn teardown(&self, nc: NetworkConfig) -> Result<Lease, Status> {
+        self.runtime.block_on(self.client.teardown(nc))
+    }
+
+    pub fn clean(&self) -> Result<OperationResponse, Status> {
+        self.runtime.block_on(self.client.clean())
+    }
+
+    pub fn inspect(&self, request: InspectRequest) -> Result<InspectResponse, Status> {
+        self.runtime.block_on(self.client.inspect(request))
+    }
+
+    /// Iterate over the lease events, every step blocks until the next event comes
+    pub fn watch(
+        &self,
+        request: WatchRequest,
+    ) -> Result<impl Iterator<Item = Result<LeaseEvent, Status>> + '_, Status> {
+        let mut events = self.runtime.block_on(self.client.watch(request))?;
+        Ok(std::iter::from_fn(move || {
+            self.runtime.block_on(events.message()).transpose()
+        }))
+    }
+
+    pub fn info(&self) -> Result<ProxyInfo, Status> {
+        self.runtime.block_on(self.client.info())
+    }
+
+    pub fn health(&self, service: &str) -> Result<HealthCheckResponse, Status> {
+        self.runtime.block_on(self.client.health(service))
+    }
+}
+
+/// Open a channel to the endpoint of the proxy
+async fn connect(p: &ProxyEndpoint, options: &ClientOptions) -> Result<Channel, Status> {
+    let endpoint = match p {
+        // We do not know why the uds connections need to be done like this.  The
+        // maintainer suggested it is part of the their API.
+        ProxyEndpoint::Uds(_) => {
+            Endpoint::try_from("http://[::1]:10000").map_err(|e| Status::internal(e.to_string()))?
+        }
+        ProxyEndpoint::Tls { url, tls } => Endpoint::from_shared(url.to_string())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .tls_config(tls_config(tls)?)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?,
+    };
+    let mut endpoint = endpoint.connect_timeout(options.connect_timeout);
+    if let Some(timeout) = options.request_timeout {
+        endpoint = endpoint.timeout(timeout);
+    }
+    let channel = match p {
+        ProxyEndpoint::Uds(path) => {
+            let path = path.clone();
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    debug!("using uds path: {}", path);
+                    UnixStream::connect(path.clone())
+                }))
+                .await
+        }
+        ProxyEndpoint::Tls { url, .. } => {
+            debug!("using tls endpoint: {}", url);
+            endpoint.connect().await
+        }
+    };
+    channel.map_err(|e| {
+        // The transport error itself only says "transport error"
+        let cause = e.source().map(|s| s.to_string()).unwrap_or_default();
+        Status::unavailable(format!("could not connect to {p}: {e}: {cause}"))
+    })
+}
+
+fn tls_config(tls: &ClientTls) -> Result<ClientTlsConfig, Status> {
+    let read = |path: &str| {
+        std::fs::read(path)
+            .map_err(|e| Status::invalid_argument(format!("could not read {path}: {e}")))
+    };
+    Ok(ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(read(&tls.ca)?))
+        .identity(Identity::from_pem(read(&tls.cert)?, read(&tls.key)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::g_rpc::netavark_proxy_server::{NetavarkProxy, NetavarkProxyServer};
+    use crate::proxy_conf::{API_VERSION, CAP_IPVLAN};
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::UnixListener;
+    use tokio_stream::wrappers::UnixListenerStream;
+    use tokio_stream::{Stream, StreamExt};
+    use tonic::transport::Server;
+    use tonic::Response;
+
+    /// A proxy that hands out the same lease to everyone and counts the calls it gets
+    #[derive(Default)]
+    struct MockProxy {
+        setups: Arc<AtomicUsize>,
+        infos: Arc<AtomicUsize>,
+    }
+
+    fn lease() -> Lease {
+        Lease {
+            yiaddr: "10.0.0.5".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl NetavarkProxy for MockProxy {
+        type WatchLeasesStream = Pin<Box<dyn Stream<Item = Result<LeaseEvent, Status>> + Send>>;
+
+        async fn setup(&self, _: Request<NetworkConfig>) -> Result<Response<Lease>, Status> {
+            self.setups.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::new(lease()))
+        }
+
+        async fn teardown(
+            &self,
+            request: Request<NetworkConfig>,
+        ) -> Result<Response<Lease>, Status> {
+            // A slow teardown, for the request timeout
+            tokio::time::sleep(Duration::from_millis(
+                request.into_inner().host_name.parse().unwrap_or(0),
+            ))
+            .await;
+            Ok(Response::new(lease()))
+        }
+
+        async fn clean(&self, _: Request<Empty>) -> Result<Response<OperationResponse>, Status> {
+            Ok(Response::new(OperationResponse { success: true }))
+        }
+
+        async fn watch_leases(
+            &self,
+            _: Request<WatchRequest>,
+        ) -> Result<Response<Self::WatchLeasesStream>, Status> {
+            let events = (0..2).map(|i| {
+                Ok(LeaseEvent {
+                    timestamp: i,
+                    ..Default::default()
+                })
+            });
+            Ok(Response::new(Box::pin(tokio_stream::iter(events))))
+        }
+
+        async fn inspect(
+            &self,
+            _: Request<InspectRequest>,
+        ) -> Result<Response<InspectResponse>, Status> {
+            Ok(Response::new(InspectResponse::default()))
+        }
+
+        async fn get_info(&self, _: Request<Empty>) -> Result<Response<ProxyInfo>, Status> {
+            self.infos.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::new(ProxyInfo {
+                version: "0.0.1".to_string(),
+                api_version: API_VERSION,
+                capabilities: vec![],
+            }))
+        }
+    }
+
+    fn temp_sock() -> PathBuf {
+        let name: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        std::env::temp_dir().join(format!("nv-proxy-client-{name}.sock"))
+    }
+
+    /// Serve the mock on a unix socket, returns the number of connections accepted
+    fn serve(path: &PathBuf, proxy: MockProxy) -> Arc<AtomicUsize> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let incoming = UnixListenerStream::new(UnixListener::bind(path).expect("could not bind"))
+            .map(move |c| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                c
+            });
+        tokio::spawn(
+            Server::builder()
+                .add_service(NetavarkProxyServer::new(proxy))
+                .serve_with_incoming(incoming),
+        );
+        connections
+    }
+
+    #[tokio::test]
+    async fn reuses_connection() {
+        let path = temp_sock();
+        let proxy = MockProxy::default();
+        let (setups, infos) = (proxy.setups.clone(), proxy.infos.clone());
+        let connections = serve(&path, proxy);
+
+        let client = ProxyClient::connect(ProxyEndpoint::from(path.to_str().unwrap()))
+            .await
+            .expect("could not connect");
+        for _ in 0..3 {
+            let lease = client
+                .setup(NetworkConfig::default())
+                .await
+                .expect("no lease");
+            assert_eq!(lease.yiaddr, "10.0.0.5");
+        }
+        client
+            .inspect(InspectRequest::default())
+            .await
+            .expect("no leases");
+        client.clean().await.expect("not cleaned");
+        let mut events = client
+            .watch(WatchRequest::default())
+            .await
+            .expect("no events");
+        assert!(events.message().await.unwrap().is_some());
+
+        assert_eq!(setups.load(Ordering::SeqCst), 3);
+        // The proxy is only asked once whether it is compatible
+        assert_eq!(infos.load(Ordering::SeqCst), 1);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // The mock does not support ipvlan, so the setup never reaches it
+        let ipvlan = NetworkConfig {
+            ipvlan: true,
+            ..Default::default()
+        };
+        let err = client.setup(ipvlan).await.unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+        assert!(err.message().contains(CAP_IPVLAN));
+        assert_eq!(setups.load(Ordering::SeqCst), 3);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn retries_connect() {
+        let path = temp_sock();
+        let endpoint = ProxyEndpoint::from(path.to_str().unwrap());
+        let once = ClientOptions {
+            connect_attempts: 1,
+            ..Default::default()
+        };
+        let err = ProxyClient::connect_with(endpoint.clone(), once)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+
+        // The proxy comes up while the client is still trying
+        let late = path.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            serve(&late, MockProxy::default());
+        });
+        let options = ClientOptions {
+            connect_attempts: 10,
+            retry_delay: Duration::from_millis(20),
+            request_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let client = ProxyClient::connect_with(endpoint, options)
+            .await
+            .expect("could not connect");
+        client
+            .teardown(NetworkConfig::default())
+            .await
+            .expect("no lease");
+        let slow = NetworkConfig {
+            host_name: "1000".to_string(),
+            ..Default::default()
+        };
+        assert!(client.teardown(slow).await.is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn blocking_client() {
+        let path = temp_sock();
+        let server = Runtime::new().expect("no runtime");
+        server.block_on(async { serve(&path, MockProxy::default()) });
+
+        let client = BlockingProxyClient::connect(
+            ProxyEndpoint::from(path.to_str().unwrap()),
+            ClientOptions::default(),
+        )
+        .expect("could not connect");
+        assert_eq!(client.info().expect("no info").version, "0.0.1");
+        let lease = client.setup(NetworkConfig::default()).expect("no lease");
+        assert_eq!(lease.yiaddr, "10.0.0.5");
+        let events: Vec<LeaseEvent> = client
+            .watch(WatchRequest::default())
+            .expect("no events")
+            .collect::<Result<_, _>>()
+            .expect("bad event");
+        assert_eq!(events.len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -20,8 +20,36 @@ pub const DEFAULT_TIMEOUT: isize = 8;
 pub const PROXY_SOCK_NAME: &str = "nv-proxy.sock";
 // Where leases are stored on the filesystem
 pub const CACHE_FILE_NAME: &str = "nv-proxy.lease";
+// Where the lease journal is stored on the filesystem
+pub const JOURNAL_FILE_NAME: &str = "nv-proxy.journal";
+// Where the audit log of lease operations is written
+pub const AUDIT_FILE_NAME: &str = "nv-proxy.audit";
+// Size in bytes after which the audit log is rotated
+pub const AUDIT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+// Number of rotated audit logs that are kept
+pub const AUDIT_ROTATIONS: usize = 5;
 // Seconds until the service should exit
 pub const DEFAULT_INACTIVITY_TIMEOUT: u64 = 300;
+// Seconds to let the requests in flight finish once the proxy shuts down
+pub const SHUTDOWN_DRAIN_TIMEOUT: u64 = 5;
+// Seconds between checks for leases that need to be renewed or have expired
+pub const LEASE_CHECK_INTERVAL: u64 = 5;
+// Minimum seconds to wait before retrying a failed renewal, as per RFC 2131
+pub const MIN_RENEWAL_RETRY: u64 = 60;
+// Seconds between checks for leases whose container namespace or interface is gone
+pub const RECONCILE_INTERVAL: u64 = 30;
+// Version of the grpc protocol, raised when clients of the previous version can no longer
+// talk to the proxy
+pub const API_VERSION: u32 = 1;
+// Optional features a proxy reports to its clients
+pub const CAP_V6: &str = "v6";
+pub const CAP_RENEWAL: &str = "renewal";
+pub const CAP_REQUESTED_ADDRESS: &str = "requested_address";
+pub const CAP_IPVLAN: &str = "ipvlan";
+pub const CAP_INSPECT: &str = "inspect";
+pub const CAP_WATCH: &str = "watch";
+pub const CAP_RELAY: &str = "relay";
+pub const CAP_IN_NAMESPACE: &str = "in_namespace";
 
 /// Get the RUN_DIR where the proxy cache and socket
 /// are stored
@@ -94,11 +122,37 @@ pub fn get_cache_fqname(run_dir: Option<&str>) -> PathBuf {
     Path::new(&run_dir).join(CACHE_FILE_NAME)
 }
 
+/// Returns the fully qualified path of the lease journal including the
+/// journal file name
+///
+/// # Arguments
+///
+/// * `run_dir`:
+///
+/// returns: PathBuf
+pub fn get_journal_fqname(run_dir: Option<&str>) -> PathBuf {
+    let run_dir = get_run_dir(run_dir);
+    Path::new(&run_dir).join(JOURNAL_FILE_NAME)
+}
+
+/// Returns the fully qualified path of the audit log including its file name
+///
+/// # Arguments
+///
+/// * `run_dir`:
+///
+/// returns: PathBuf
+pub fn get_audit_fqname(run_dir: Option<&str>) -> PathBuf {
+    let run_dir = get_run_dir(run_dir);
+    Path::new(&run_dir).join(AUDIT_FILE_NAME)
+}
+
 #[cfg(test)]
 mod conf_tests {
     use crate::proxy_conf::{
-        get_cache_fqname, get_proxy_sock_fqname, get_run_dir, CACHE_FILE_NAME,
-        NETAVARK_PROXY_RUN_DIR, NETAVARK_PROXY_RUN_DIR_ENV, PROXY_SOCK_NAME,
+        get_audit_fqname, get_cache_fqname, get_journal_fqname, get_proxy_sock_fqname, get_run_dir,
+        AUDIT_FILE_NAME, CACHE_FILE_NAME, JOURNAL_FILE_NAME, NETAVARK_PROXY_RUN_DIR,
+        NETAVARK_PROXY_RUN_DIR_ENV, PROXY_SOCK_NAME,
     };
     use std::path::Path;
 
@@ -261,6 +315,28 @@ mod conf_tests {
         })
     }
 
+    #[test]
+    fn test_get_journal_with_opt() {
+        let r = random_string(25);
+        with_var_unset(NETAVARK_PROXY_RUN_DIR_ENV, || {
+            assert_eq!(
+                get_journal_fqname(Some(&r)),
+                Path::new(&r).join(JOURNAL_FILE_NAME)
+            )
+        })
+    }
+
+    #[test]
+    fn test_get_audit_with_opt() {
+        let r = random_string(25);
+        with_var_unset(NETAVARK_PROXY_RUN_DIR_ENV, || {
+            assert_eq!(
+                get_audit_fqname(Some(&r)),
+                Path::new(&r).join(AUDIT_FILE_NAME)
+            )
+        })
+    }
+
     #[test]
     fn test_get_cache_as_none() {
         with_var_unset(NETAVARK_PROXY_RUN_DIR_ENV, || {
@@ -0,0 +1,1167 @@
+/*
+   A DHCP relay agent for the leases of the proxy.
+
+   mozim can only broadcast on the parent interface, which does not reach DHCP
+   servers on another subnet. In relay mode the proxy builds the DHCP messages
+   itself, sets giaddr to an address of the host and unicasts them to the
+   configured servers. The servers send their replies back to giaddr, where a
+   single socket hands them to the exchange they belong to.
+
+   The relay can also add the Relay Agent Information option (82, RFC 3046) to
+   the requests, with sub-options built from the network configuration of the
+   container. Servers echo it in their replies, it is removed again before the
+   reply is turned into a lease.
+
+   The client only sees the relay through the DhcpTransport trait, which sends
+   messages and hands back the raw replies of an exchange. Tests put scripted
+   servers behind it, so the client can be driven without sockets.
+*/
+
+use crate::g_rpc::NetworkConfig;
+use log::{debug, warn};
+use mozim::{DhcpError, DhcpV4Lease, ErrorKind};
+use nix::errno::Errno;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// UDP port DHCP servers and relay agents listen on
+pub const DHCP_SERVER_PORT: u16 = 67;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Asks for replies to be broadcast, for clients that cannot receive them by mac address
+const BROADCAST_FLAG: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Time to wait for a reply before the message is sent again, doubled with every
+// retransmission, as per RFC 2131
+const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(4);
+// Time to wait before receiving again after the relay socket failed
+const RECEIVE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// Size of the fixed part of a DHCP message, up to the magic cookie
+const HEADER_LEN: usize = 236;
+
+// DHCP options, as per RFC 2132
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVER: u8 = 6;
+const OPT_HOST_NAME: u8 = 12;
+const OPT_DOMAIN_NAME: u8 = 15;
+const OPT_MTU: u8 = 26;
+const OPT_BROADCAST_ADDR: u8 = 28;
+const OPT_NTP_SERVER: u8 = 42;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_CLIENT_ID: u8 = 61;
+const OPT_AGENT_INFORMATION: u8 = 82;
+const OPT_END: u8 = 255;
+
+// Sub-options of the relay agent information, as per RFC 3046
+const AGENT_CIRCUIT_ID: u8 = 1;
+const AGENT_REMOTE_ID: u8 = 2;
+
+// Options the proxy needs to set up the container
+const PARAMETER_LIST: [u8; 10] = [
+    OPT_SUBNET_MASK,
+    OPT_ROUTER,
+    OPT_DNS_SERVER,
+    OPT_DOMAIN_NAME,
+    OPT_MTU,
+    OPT_BROADCAST_ADDR,
+    OPT_NTP_SERVER,
+    OPT_LEASE_TIME,
+    OPT_RENEWAL_TIME,
+    OPT_REBINDING_TIME,
+];
+
+/// The DHCP message types, as per RFC 2132
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+}
+
+impl MessageType {
+    fn from_u8(t: u8) -> Option<MessageType> {
+        match t {
+            1 => Some(MessageType::Discover),
+            2 => Some(MessageType::Offer),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Decline),
+            5 => Some(MessageType::Ack),
+            6 => Some(MessageType::Nak),
+            7 => Some(MessageType::Release),
+            _ => None,
+        }
+    }
+}
+
+/// A DHCPv4 message as it is sent over the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpMessage {
+    pub op: u8,
+    pub hops: u8,
+    pub xid: u32,
+    pub secs: u16,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: [u8; 6],
+    /// Options in the order they are sent, without pad and end
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+impl DhcpMessage {
+    /// A request from the client with the given hardware address
+    pub fn request(kind: MessageType, xid: u32, chaddr: [u8; 6]) -> Self {
+        DhcpMessage {
+            op: BOOTREQUEST,
+            hops: 0,
+            xid,
+            secs: 0,
+            flags: 0,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            options: vec![(OPT_MESSAGE_TYPE, vec![kind as u8])],
+        }
+    }
+
+    /// The value of the first option with the given code
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Add an option, or replace the value of the option that is there
+    pub fn set_option(&mut self, code: u8, value: Vec<u8>) {
+        match self.options.iter_mut().find(|(c, _)| *c == code) {
+            Some(o) => o.1 = value,
+            None => self.options.push((code, value)),
+        }
+    }
+
+    /// Take an option out of the message
+    pub fn remove_option(&mut self, code: u8) -> Option<Vec<u8>> {
+        let index = self.options.iter().position(|(c, _)| *c == code)?;
+        Some(self.options.remove(index).1)
+    }
+
+    pub fn message_type(&self) -> Option<MessageType> {
+        self.option(OPT_MESSAGE_TYPE)
+            .and_then(|t| t.first())
+            .and_then(|t| MessageType::from_u8(*t))
+    }
+
+    /// Write the message in its wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + 64);
+        buf.extend_from_slice(&[self.op, HTYPE_ETHERNET, self.chaddr.len() as u8, self.hops]);
+        buf.extend_from_slice(&self.xid.to_be_bytes());
+        buf.extend_from_slice(&self.secs.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for addr in [self.ciaddr, self.yiaddr, self.siaddr, self.giaddr] {
+            buf.extend_from_slice(&addr.octets());
+        }
+        // chaddr is 16 bytes, followed by the unused sname and file fields
+        buf.extend_from_slice(&self.chaddr);
+        buf.resize(HEADER_LEN, 0);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+        // The relay agent information goes last, as per RFC 3046
+        let options = self
+            .options
+            .iter()
+            .filter(|(c, _)| *c != OPT_AGENT_INFORMATION)
+            .chain(
+                self.options
+                    .iter()
+                    .filter(|(c, _)| *c == OPT_AGENT_INFORMATION),
+            );
+        for (code, value) in options {
+            // Longer values are split over several options, as per RFC 3396
+            for chunk in value.chunks(u8::MAX as usize) {
+                buf.push(*code);
+                buf.push(chunk.len() as u8);
+                buf.extend_from_slice(chunk);
+            }
+            if value.is_empty() {
+                buf.extend_from_slice(&[*code, 0]);
+            }
+        }
+        buf.push(OPT_END);
+        buf
+    }
+
+    /// Read a message from its wire format
+    pub fn decode(buf: &[u8]) -> Result<DhcpMessage, DhcpError> {
+        let invalid =
+            |msg: &str| DhcpError::new(ErrorKind::InvalidDhcpServerReply, msg.to_string());
+        if buf.len() < HEADER_LEN + MAGIC_COOKIE.len() {
+            return Err(invalid("DHCP message is too short"));
+        }
+        if buf[HEADER_LEN..HEADER_LEN + MAGIC_COOKIE.len()] != MAGIC_COOKIE {
+            return Err(invalid("DHCP message has no magic cookie"));
+        }
+        let addr = |at: usize| Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3]);
+        let mut chaddr = [0; 6];
+        chaddr.copy_from_slice(&buf[28..34]);
+        let mut msg = DhcpMessage {
+            op: buf[0],
+            hops: buf[3],
+            xid: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            secs: u16::from_be_bytes([buf[8], buf[9]]),
+            flags: u16::from_be_bytes([buf[10], buf[11]]),
+            ciaddr: addr(12),
+            yiaddr: addr(16),
+            siaddr: addr(20),
+            giaddr: addr(24),
+            chaddr,
+            options: Vec::new(),
+        };
+        let mut at = HEADER_LEN + MAGIC_COOKIE.len();
+        while at < buf.len() {
+            let code = buf[at];
+            match code {
+                OPT_PAD => {
+                    at += 1;
+                    continue;
+                }
+                OPT_END => break,
+                _ => {}
+            }
+            let len =
+                *buf.get(at + 1)
+                    .ok_or_else(|| invalid("DHCP option without length"))? as usize;
+            let value = buf
+                .get(at + 2..at + 2 + len)
+                .ok_or_else(|| invalid("DHCP option is cut off"))?;
+            // Options split over several entries are joined again, as per RFC 3396
+            match msg.options.iter_mut().find(|(c, _)| *c == code) {
+                Some(o) => o.1.extend_from_slice(value),
+                None => msg.options.push((code, value.to_vec())),
+            }
+            at += 2 + len;
+        }
+        Ok(msg)
+    }
+
+    /// The lease handed out by an ACK
+    pub fn to_lease(&self) -> Result<DhcpV4Lease, DhcpError> {
+        let invalid = |msg: String| DhcpError::new(ErrorKind::InvalidDhcpServerReply, msg);
+        let addr = |code: u8| -> Result<Option<Ipv4Addr>, DhcpError> {
+            match self.option(code) {
+                None => Ok(None),
+                Some(v) if v.len() == 4 => Ok(Some(Ipv4Addr::new(v[0], v[1], v[2], v[3]))),
+                Some(_) => Err(invalid(format!("DHCP option {code} is not an address"))),
+            }
+        };
+        let addrs = |code: u8| -> Result<Option<Vec<Ipv4Addr>>, DhcpError> {
+            match self.option(code) {
+                None => Ok(None),
+                Some(v) if v.len() % 4 == 0 => Ok(Some(
+                    v.chunks(4)
+                        .map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+                        .collect(),
+                )),
+                Some(_) => Err(invalid(format!(
+                    "DHCP option {code} is not a list of addresses"
+                ))),
+            }
+        };
+        let seconds = |code: u8| -> Result<u32, DhcpError> {
+            match self.option(code) {
+                None => Ok(0),
+                Some(v) => match <[u8; 4]>::try_from(v) {
+                    Ok(b) => Ok(u32::from_be_bytes(b)),
+                    Err(_) => Err(invalid(format!("DHCP option {code} is not a time"))),
+                },
+            }
+        };
+        let text = |code: u8| {
+            self.option(code).map(|v| {
+                String::from_utf8_lossy(v)
+                    .trim_end_matches('\0')
+                    .to_string()
+            })
+        };
+        let mtu = match self.option(OPT_MTU) {
+            None => None,
+            Some(v) => match <[u8; 2]>::try_from(v) {
+                Ok(b) => Some(u16::from_be_bytes(b)),
+                Err(_) => return Err(invalid("DHCP option 26 is not an mtu".to_string())),
+            },
+        };
+
+        let mut lease = DhcpV4Lease::default();
+        lease.siaddr = self.siaddr;
+        lease.yiaddr = self.yiaddr;
+        lease.t1 = seconds(OPT_RENEWAL_TIME)?;
+        lease.t2 = seconds(OPT_REBINDING_TIME)?;
+        lease.lease_time = seconds(OPT_LEASE_TIME)?;
+        lease.srv_id = addr(OPT_SERVER_ID)?
+            .ok_or_else(|| invalid("DHCP reply has no server identifier".to_string()))?;
+        lease.subnet_mask = addr(OPT_SUBNET_MASK)?.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        lease.broadcast_addr = addr(OPT_BROADCAST_ADDR)?;
+        lease.dns_srvs = addrs(OPT_DNS_SERVER)?;
+        lease.gateways = addrs(OPT_ROUTER)?;
+        lease.ntp_srvs = addrs(OPT_NTP_SERVER)?;
+        lease.mtu = mtu;
+        lease.host_name = text(OPT_HOST_NAME);
+        lease.domain_name = text(OPT_DOMAIN_NAME);
+        Ok(lease)
+    }
+}
+
+/// Templates of the relay agent information sub-options. Fields of the network
+/// configuration are put in with `{name}`, for example `{host_iface}:{container_id}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgentInformation {
+    circuit_id: Option<String>,
+    remote_id: Option<String>,
+}
+
+impl AgentInformation {
+    /// Check the templates of the circuit id and remote id sub-options
+    pub fn new(circuit_id: Option<String>, remote_id: Option<String>) -> Result<Self, String> {
+        for template in circuit_id.iter().chain(remote_id.iter()) {
+            expand(template, &NetworkConfig::default())?;
+        }
+        Ok(AgentInformation {
+            circuit_id,
+            remote_id,
+        })
+    }
+
+    /// The value of option 82 for the given configuration, None when no sub-option is
+    /// configured. Sub-options whose template expands to nothing are left out.
+    pub fn encode(&self, nc: &NetworkConfig) -> Result<Option<Vec<u8>>, DhcpError> {
+        let mut value = Vec::new();
+        for (code, template) in [
+            (AGENT_CIRCUIT_ID, &self.circuit_id),
+            (AGENT_REMOTE_ID, &self.remote_id),
+        ] {
+            let template = match template {
+                Some(t) => t,
+                None => continue,
+            };
+            let sub_option =
+                expand(template, nc).map_err(|e| DhcpError::new(ErrorKind::InvalidArgument, e))?;
+            if sub_option.is_empty() {
+                continue;
+            }
+            if sub_option.len() > u8::MAX as usize {
+                return Err(DhcpError::new(
+                    ErrorKind::InvalidArgument,
+                    format!("relay agent sub-option {code} is longer than 255 bytes"),
+                ));
+            }
+            value.push(code);
+            value.push(sub_option.len() as u8);
+            value.extend_from_slice(sub_option.as_bytes());
+        }
+        if value.len() > u8::MAX as usize {
+            return Err(DhcpError::new(
+                ErrorKind::InvalidArgument,
+                "relay agent information is longer than 255 bytes".to_string(),
+            ));
+        }
+        Ok(if value.is_empty() { None } else { Some(value) })
+    }
+}
+
+/// Put the fields of the network configuration into a template
+fn expand(template: &str, nc: &NetworkConfig) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("unterminated field in template {template}")),
+        };
+        let value = match &rest[start + 1..end] {
+            "host_iface" => &nc.host_iface,
+            "container_iface" => &nc.container_iface,
+            "mac_address" => &nc.container_mac_addr,
+            "host_name" => &nc.host_name,
+            "container_id" => &nc.container_id,
+            "container_name" => &nc.container_name,
+            "network_name" => &nc.network_name,
+            field => return Err(format!("unknown field {field} in template {template}")),
+        };
+        expanded.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Where relayed DHCP messages are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayConfig {
+    /// Address of the host the DHCP servers send their replies to (giaddr). It also
+    /// selects the subnet the servers hand out addresses from.
+    pub agent_address: Ipv4Addr,
+    /// DHCP servers every request is unicast to
+    pub servers: Vec<Ipv4Addr>,
+    /// Relay agent information added to DISCOVER and REQUEST messages
+    pub agent_information: AgentInformation,
+}
+
+/// How the messages of a client reach the DHCP servers
+pub trait DhcpTransport: Debug + Send + Sync {
+    /// The relay agent the messages are sent from
+    fn config(&self) -> &RelayConfig;
+
+    /// Start an exchange, the replies with its transaction id are received until it is
+    /// dropped
+    fn exchange(&self) -> Result<Box<dyn Exchange + '_>, DhcpError>;
+
+    /// Time to wait for the first reply before a message is sent again
+    fn retransmit_interval(&self) -> Duration {
+        RETRANSMIT_INTERVAL
+    }
+}
+
+/// A single transaction with the DHCP servers
+pub trait Exchange {
+    /// The transaction id of the messages and their replies
+    fn xid(&self) -> u32;
+
+    /// Send a message to the given servers
+    fn send(&self, msg: &DhcpMessage, servers: &[Ipv4Addr]) -> Result<(), DhcpError>;
+
+    /// Wait for the next reply in its wire format. Replies are decoded by the client,
+    /// so that a malformed one can be skipped.
+    fn recv(&self, timeout: Duration) -> Result<Vec<u8>, DhcpError>;
+}
+
+/// The socket of the relay agent. It is shared by all DHCP exchanges, the replies are
+/// handed to the exchange with the same transaction id.
+#[derive(Debug)]
+pub struct Relay {
+    config: RelayConfig,
+    socket: UdpSocket,
+    server_port: u16,
+    // exchanges waiting for replies, by transaction id
+    pending: Mutex<HashMap<u32, Sender<Vec<u8>>>>,
+}
+
+impl Relay {
+    /// Listen for relayed replies on the agent address
+    pub fn bind(config: RelayConfig) -> io::Result<Arc<Relay>> {
+        let local = SocketAddrV4::new(config.agent_address, DHCP_SERVER_PORT);
+        Relay::bind_at(config, local, DHCP_SERVER_PORT)
+    }
+
+    fn bind_at(
+        config: RelayConfig,
+        local: SocketAddrV4,
+        server_port: u16,
+    ) -> io::Result<Arc<Relay>> {
+        let socket = UdpSocket::bind(local)?;
+        let relay = Arc::new(Relay {
+            config,
+            socket: socket.try_clone()?,
+            server_port,
+            pending: Mutex::new(HashMap::new()),
+        });
+        let receiver = relay.clone();
+        std::thread::spawn(move || receiver.receive(socket));
+        Ok(relay)
+    }
+
+    /// Hand every reply to the exchange it belongs to. Errors of a single receive, like an
+    /// ICMP error queued on the socket, are logged and the next reply is waited for. Only a
+    /// closed socket ends the loop.
+    fn receive(&self, socket: UdpSocket) {
+        let mut buf = [0; 1500];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.raw_os_error() == Some(Errno::EBADF as i32) => {
+                    warn!("DHCP relay socket closed: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Could not receive on the DHCP relay socket: {}", e);
+                    // do not spin on an error that does not go away
+                    std::thread::sleep(RECEIVE_RETRY_INTERVAL);
+                    continue;
+                }
+            };
+            // Only the transaction id is read here, the exchange decodes the rest
+            let reply = &buf[..len];
+            if reply.len() < HEADER_LEN || reply[0] != BOOTREPLY {
+                debug!("ignoring DHCP message from {}", from);
+                continue;
+            }
+            let xid = u32::from_be_bytes([reply[4], reply[5], reply[6], reply[7]]);
+            if let Ok(pending) = self.pending.lock() {
+                if let Some(tx) = pending.get(&xid) {
+                    let _ = tx.send(reply.to_vec());
+                }
+            }
+        }
+    }
+}
+
+impl DhcpTransport for Relay {
+    fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    fn exchange(&self) -> Result<Box<dyn Exchange + '_>, DhcpError> {
+        let (tx, rx) = channel();
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|e| DhcpError::new(ErrorKind::Bug, e.to_string()))?;
+        let mut xid: u32 = rand::thread_rng().gen();
+        while pending.contains_key(&xid) {
+            xid = rand::thread_rng().gen();
+        }
+        pending.insert(xid, tx);
+        Ok(Box::new(RelayExchange {
+            relay: self,
+            xid,
+            replies: rx,
+        }))
+    }
+}
+
+/// An exchange through the socket of the relay agent
+struct RelayExchange<'a> {
+    relay: &'a Relay,
+    xid: u32,
+    replies: Receiver<Vec<u8>>,
+}
+
+impl Drop for RelayExchange<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.relay.pending.lock() {
+            pending.remove(&self.xid);
+        }
+    }
+}
+
+impl Exchange for RelayExchange<'_> {
+    fn xid(&self) -> u32 {
+        self.xid
+    }
+
+    fn send(&self, msg: &DhcpMessage, servers: &[Ipv4Addr]) -> Result<(), DhcpError> {
+        let buf = msg.encode();
+        let mut sent = false;
+        for server in servers {
+            let to = SocketAddr::from((*server, self.relay.server_port));
+            match self.relay.socket.send_to(&buf, to) {
+                Ok(_) => sent = true,
+                Err(e) => warn!("Could not send DHCP message to {}: {}", server, e),
+            }
+        }
+        // Same as a server that does not answer, so the setup is not failed as a bug
+        if !sent {
+            return Err(DhcpError::new(
+                ErrorKind::Timeout,
+                "could not reach any DHCP server".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<Vec<u8>, DhcpError> {
+        match self.replies.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => Err(DhcpError::new(
+                ErrorKind::Timeout,
+                "no reply from the DHCP servers".to_string(),
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(DhcpError::new(
+                ErrorKind::Bug,
+                "DHCP relay socket is closed".to_string(),
+            )),
+        }
+    }
+}
+
+/// A DHCP client whose messages go through the relay agent
+#[derive(Debug)]
+pub struct RelayClient {
+    relay: Arc<dyn DhcpTransport>,
+    chaddr: [u8; 6],
+    client_id: Vec<u8>,
+    // set for containers that share their mac address with others
+    broadcast: bool,
+    host_name: String,
+    // value of the relay agent information option, if any
+    agent_information: Option<Vec<u8>>,
+    // the lease to start from instead of a DORA
+    lease: Option<DhcpV4Lease>,
+    // the address to ask for in the DISCOVER of a DORA
+    requested_address: Option<Ipv4Addr>,
+}
+
+impl RelayClient {
+    /// Create a client for the container of the network configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `relay`: the relay agent
+    /// * `nc`: network configuration of the container, with its mac address and host name
+    /// * `lease`: an existing lease to request again, None to start a new DORA
+    pub fn new(
+        relay: Arc<dyn DhcpTransport>,
+        nc: &NetworkConfig,
+        lease: Option<DhcpV4Lease>,
+    ) -> Result<RelayClient, DhcpError> {
+        let mac_address = &nc.container_mac_addr;
+        let mac = macaddr::MacAddr6::from_str(mac_address).map_err(|e| {
+            DhcpError::new(
+                ErrorKind::InvalidArgument,
+                format!("invalid mac address {mac_address}: {e}"),
+            )
+        })?;
+        let chaddr = mac.into_array();
+        // Client identifier of type ethernet, or of type 0 for containers on ipvlan
+        // links, as per RFC 2132
+        let (client_id, broadcast) = match nc.client_id() {
+            Some(id) => ([&[0], id.as_bytes()].concat(), true),
+            None => ([&[HTYPE_ETHERNET], &chaddr[..]].concat(), false),
+        };
+        let agent_information = relay.config().agent_information.encode(nc)?;
+        Ok(RelayClient {
+            relay,
+            chaddr,
+            client_id,
+            broadcast,
+            host_name: nc.host_name.clone(),
+            agent_information,
+            lease,
+            requested_address: None,
+        })
+    }
+
+    /// Ask for the given address in the DISCOVER, as per RFC 2131 4.4.1. A server that can
+    /// not hand it out offers another address.
+    pub fn with_requested_address(mut self, address: Ipv4Addr) -> Self {
+        self.requested_address = Some(address);
+        self
+    }
+
+    /// A request of the client, relayed by the agent
+    fn message(&self, kind: MessageType, xid: u32) -> DhcpMessage {
+        let mut msg = DhcpMessage::request(kind, xid, self.chaddr);
+        msg.hops = 1;
+        msg.giaddr = self.relay.config().agent_address;
+        if self.broadcast {
+            msg.flags |= BROADCAST_FLAG;
+        }
+        msg.set_option(OPT_CLIENT_ID, self.client_id.clone());
+        if !self.host_name.is_empty() && kind != MessageType::Release {
+            msg.set_option(OPT_HOST_NAME, self.host_name.as_bytes().to_vec());
+        }
+        if matches!(kind, MessageType::Discover | MessageType::Request) {
+            msg.set_option(OPT_PARAMETER_LIST, PARAMETER_LIST.to_vec());
+            if let Some(info) = &self.agent_information {
+                msg.set_option(OPT_AGENT_INFORMATION, info.clone());
+            }
+        }
+        msg
+    }
+
+    /// Send a message and wait for a reply of one of the given types. Without a reply the
+    /// message is sent again, the interval doubles every time, as per RFC 2131.
+    fn transact(
+        &self,
+        exchange: &dyn Exchange,
+        msg: &DhcpMessage,
+        servers: &[Ipv4Addr],
+        kinds: &[MessageType],
+        timeout: Duration,
+    ) -> Result<DhcpMessage, DhcpError> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = self.relay.retransmit_interval();
+        loop {
+            exchange.send(msg, servers)?;
+            let resend = deadline.min(Instant::now() + interval);
+            interval *= 2;
+            loop {
+                let left = resend.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                let buf = match exchange.recv(left) {
+                    Ok(b) => b,
+                    Err(e) if e.kind() == ErrorKind::Timeout => break,
+                    Err(e) => return Err(e),
+                };
+                let mut reply = match DhcpMessage::decode(&buf) {
+                    Ok(r) if r.op == BOOTREPLY && r.xid == exchange.xid() => r,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        debug!("ignoring DHCP reply: {}", e);
+                        continue;
+                    }
+                };
+                match reply.message_type() {
+                    Some(t) if kinds.contains(&t) => {
+                        // The information is meant for the relay, not for the client
+                        reply.remove_option(OPT_AGENT_INFORMATION);
+                        return Ok(reply);
+                    }
+                    t => debug!("ignoring DHCP reply of type {:?}", t),
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(DhcpError::new(
+                    ErrorKind::Timeout,
+                    "no reply from the DHCP servers".to_string(),
+                ));
+            }
+            debug!(
+                "no DHCP reply yet, sending the {:?} again",
+                msg.message_type()
+            );
+        }
+    }
+
+    /// Obtain a lease. Starting from a lease the client asks for that lease again,
+    /// otherwise it runs a DORA.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: seconds to wait for each reply
+    pub fn get_lease(&mut self, timeout: isize) -> Result<DhcpV4Lease, DhcpError> {
+        let timeout = Duration::from_secs(timeout.max(1) as u64);
+        let exchange = self.relay.exchange()?;
+        let exchange = exchange.as_ref();
+        let xid = exchange.xid();
+        let servers = self.relay.config().servers.clone();
+        let ack = match self.lease.take() {
+            Some(lease) => {
+                let mut request = self.message(MessageType::Request, xid);
+                let to = if lease.srv_id.is_unspecified() {
+                    // INIT-REBOOT, any server that knows the address answers
+                    request.set_option(OPT_REQUESTED_IP, lease.yiaddr.octets().to_vec());
+                    servers
+                } else {
+                    // RENEWING, the server that handed out the lease answers
+                    request.ciaddr = lease.yiaddr;
+                    vec![lease.srv_id]
+                };
+                let replies = [MessageType::Ack, MessageType::Nak];
+                self.transact(exchange, &request, &to, &replies, timeout)?
+            }
+            None => {
+                let mut discover = self.message(MessageType::Discover, xid);
+                if let Some(address) = self.requested_address {
+                    discover.set_option(OPT_REQUESTED_IP, address.octets().to_vec());
+                }
+                let offer = self.transact(
+                    exchange,
+                    &discover,
+                    &servers,
+                    &[MessageType::Offer],
+                    timeout,
+                )?;
+                let srv_id = offer.option(OPT_SERVER_ID).ok_or_else(|| {
+                    DhcpError::new(
+                        ErrorKind::InvalidDhcpServerReply,
+                        "DHCP offer has no server identifier".to_string(),
+                    )
+                })?;
+                let mut request = self.message(MessageType::Request, xid);
+                request.set_option(OPT_REQUESTED_IP, offer.yiaddr.octets().to_vec());
+                request.set_option(OPT_SERVER_ID, srv_id.to_vec());
+                // Every server sees the request, so the others can take back their offers
+                let replies = [MessageType::Ack, MessageType::Nak];
+                self.transact(exchange, &request, &servers, &replies, timeout)?
+            }
+        };
+        if ack.message_type() == Some(MessageType::Nak) {
+            return Err(DhcpError::new(
+                ErrorKind::NoLease,
+                "DHCP server refused the lease".to_string(),
+            ));
+        }
+        ack.to_lease()
+    }
+
+    /// Give a lease back to the server that handed it out
+    pub fn release(&mut self, lease: &DhcpV4Lease) -> Result<(), DhcpError> {
+        let exchange = self.relay.exchange()?;
+        let mut release = self.message(MessageType::Release, exchange.xid());
+        release.ciaddr = lease.yiaddr;
+        release.set_option(OPT_SERVER_ID, lease.srv_id.octets().to_vec());
+        let to = if lease.srv_id.is_unspecified() {
+            self.relay.config().servers.clone()
+        } else {
+            vec![lease.srv_id]
+        };
+        exchange.send(&release, &to)
+    }
+}
+
+/// A transport to DHCP servers that answer from a script, for tests that should not
+/// need sockets
+#[cfg(test)]
+pub(crate) mod scripted {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// What the server does with a message it receives
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) enum Reply {
+        /// Answer with a message of the given type
+        Message(MessageType),
+        /// Answer with a message of the given type whose options do not make a lease
+        Malformed(MessageType),
+        /// Answer with bytes that are not a DHCP message
+        Garbage,
+        /// Do not answer
+        Silence,
+    }
+
+    /// Every message that is not a RELEASE takes the next reply of the script, the
+    /// server is silent once the script runs out
+    #[derive(Debug)]
+    pub(crate) struct ScriptedTransport {
+        config: RelayConfig,
+        script: Mutex<VecDeque<Reply>>,
+        received: Mutex<Vec<DhcpMessage>>,
+    }
+
+    impl ScriptedTransport {
+        pub(crate) fn new(script: Vec<Reply>) -> Arc<ScriptedTransport> {
+            Arc::new(ScriptedTransport {
+                config: RelayConfig {
+                    agent_address: Ipv4Addr::new(10, 0, 0, 2),
+                    servers: vec![Ipv4Addr::new(10, 0, 0, 1)],
+                    agent_information: AgentInformation::default(),
+                },
+                script: Mutex::new(script.into()),
+                received: Mutex::new(Vec::new()),
+            })
+        }
+
+        /// The messages the servers received so far
+        pub(crate) fn received(&self) -> Vec<DhcpMessage> {
+            self.received.lock().expect("poisoned").clone()
+        }
+    }
+
+    impl DhcpTransport for ScriptedTransport {
+        fn config(&self) -> &RelayConfig {
+            &self.config
+        }
+
+        fn exchange(&self) -> Result<Box<dyn Exchange + '_>, DhcpError> {
+            Ok(Box::new(ScriptedExchange {
+                transport: self,
+                xid: rand::thread_rng().gen(),
+                replies: RefCell::new(VecDeque::new()),
+            }))
+        }
+
+        fn retransmit_interval(&self) -> Duration {
+            Duration::from_millis(50)
+        }
+    }
+
+    struct ScriptedExchange<'a> {
+        transport: &'a ScriptedTransport,
+        xid: u32,
+        replies: RefCell<VecDeque<Vec<u8>>>,
+    }
+
+    impl Exchange for ScriptedExchange<'_> {
+        fn xid(&self) -> u32 {
+            self.xid
+        }
+
+        fn send(&self, msg: &DhcpMessage, _servers: &[Ipv4Addr]) -> Result<(), DhcpError> {
+            self.transport
+                .received
+                .lock()
+                .expect("poisoned")
+                .push(msg.clone());
+            if msg.message_type() == Some(MessageType::Release) {
+                return Ok(());
+            }
+            let next = self.transport.script.lock().expect("poisoned").pop_front();
+            let reply = match next.unwrap_or(Reply::Silence) {
+                Reply::Message(kind) => answer(msg, kind).encode(),
+                Reply::Malformed(kind) => {
+                    let mut reply = answer(msg, kind);
+                    reply.set_option(OPT_SERVER_ID, vec![10, 0, 0]);
+                    reply.encode()
+                }
+                Reply::Garbage => vec![BOOTREPLY; HEADER_LEN],
+                Reply::Silence => return Ok(()),
+            };
+            self.replies.borrow_mut().push_back(reply);
+            Ok(())
+        }
+
+        fn recv(&self, timeout: Duration) -> Result<Vec<u8>, DhcpError> {
+            if let Some(reply) = self.replies.borrow_mut().pop_front() {
+                return Ok(reply);
+            }
+            std::thread::sleep(timeout);
+            Err(DhcpError::new(
+                ErrorKind::Timeout,
+                "no reply from the DHCP servers".to_string(),
+            ))
+        }
+    }
+
+    // The reply of a server of 10.0.0.0/24 that hands out 10.0.0.5
+    fn answer(request: &DhcpMessage, kind: MessageType) -> DhcpMessage {
+        let mut reply = DhcpMessage::request(kind, request.xid, request.chaddr);
+        reply.op = BOOTREPLY;
+        reply.giaddr = request.giaddr;
+        reply.set_option(OPT_SERVER_ID, vec![10, 0, 0, 1]);
+        if kind != MessageType::Nak {
+            reply.yiaddr = Ipv4Addr::new(10, 0, 0, 5);
+            reply.set_option(OPT_SUBNET_MASK, vec![255, 255, 255, 0]);
+            reply.set_option(OPT_ROUTER, vec![10, 0, 0, 1]);
+            reply.set_option(OPT_DNS_SERVER, vec![10, 0, 0, 1, 10, 0, 0, 3]);
+            reply.set_option(OPT_DOMAIN_NAME, b"example.com".to_vec());
+            reply.set_option(OPT_MTU, 1400_u16.to_be_bytes().to_vec());
+            reply.set_option(OPT_LEASE_TIME, 3600_u32.to_be_bytes().to_vec());
+            reply.set_option(OPT_RENEWAL_TIME, 1800_u32.to_be_bytes().to_vec());
+            reply.set_option(OPT_REBINDING_TIME, 3150_u32.to_be_bytes().to_vec());
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+
+    // A DHCP server on the loopback address that answers with the given replies
+    fn scripted_server(
+        replies: Vec<MessageType>,
+    ) -> (UdpSocket, std::thread::JoinHandle<Vec<DhcpMessage>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("could not bind server");
+        let server = socket.try_clone().expect("could not clone socket");
+        let handle = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0; 1500];
+            for kind in replies {
+                let (len, from) = server.recv_from(&mut buf).expect("could not receive");
+                let request = DhcpMessage::decode(&buf[..len]).expect("bad request");
+                let mut reply = DhcpMessage::request(kind, request.xid, request.chaddr);
+                reply.op = BOOTREPLY;
+                reply.yiaddr = Ipv4Addr::new(10, 0, 0, 5);
+                reply.giaddr = request.giaddr;
+                reply.set_option(OPT_SERVER_ID, vec![127, 0, 0, 1]);
+                reply.set_option(OPT_SUBNET_MASK, vec![255, 255, 255, 0]);
+                reply.set_option(OPT_ROUTER, vec![10, 0, 0, 1]);
+                reply.set_option(OPT_LEASE_TIME, 3600_u32.to_be_bytes().to_vec());
+                if let Some(info) = request.option(OPT_AGENT_INFORMATION) {
+                    reply.set_option(OPT_AGENT_INFORMATION, info.to_vec());
+                }
+                received.push(request);
+                server
+                    .send_to(&reply.encode(), from)
+                    .expect("could not reply");
+            }
+            received
+        });
+        (socket, handle)
+    }
+
+    fn config(host_name: &str) -> NetworkConfig {
+        NetworkConfig {
+            host_iface: "eth0".to_string(),
+            container_mac_addr: MAC.to_string(),
+            host_name: host_name.to_string(),
+            container_id: "abc123".to_string(),
+            network_name: "podman1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn relay_to(server: &UdpSocket, agent_information: AgentInformation) -> Arc<Relay> {
+        let port = server.local_addr().expect("no server address").port();
+        let config = RelayConfig {
+            agent_address: Ipv4Addr::LOCALHOST,
+            servers: vec![Ipv4Addr::LOCALHOST],
+            agent_information,
+        };
+        Relay::bind_at(config, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), port)
+            .expect("could not bind relay")
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut msg = DhcpMessage::request(MessageType::Discover, 42, [1, 2, 3, 4, 5, 6]);
+        msg.giaddr = Ipv4Addr::new(192, 168, 1, 1);
+        msg.set_option(OPT_HOST_NAME, b"foobar".to_vec());
+        // Longer options are split on the wire and joined again
+        msg.set_option(OPT_DOMAIN_NAME, vec![b'a'; 300]);
+        let decoded = DhcpMessage::decode(&msg.encode()).expect("could not decode");
+        assert_eq!(decoded, msg);
+        assert_eq!(decoded.message_type(), Some(MessageType::Discover));
+
+        assert!(DhcpMessage::decode(&msg.encode()[..100]).is_err());
+        let mut truncated = msg.encode();
+        truncated.truncate(HEADER_LEN + 6);
+        assert!(DhcpMessage::decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn relayed_dora() {
+        let (server, handle) = scripted_server(vec![MessageType::Offer, MessageType::Ack]);
+        let info = AgentInformation::new(Some("{host_iface}:{container_id}".to_string()), None)
+            .expect("invalid template");
+        let mut client = RelayClient::new(relay_to(&server, info), &config("foobar"), None)
+            .expect("could not create client");
+        let lease = client.get_lease(2).expect("no lease");
+        assert_eq!(lease.yiaddr, Ipv4Addr::new(10, 0, 0, 5));
+        assert_eq!(lease.srv_id, Ipv4Addr::LOCALHOST);
+        assert_eq!(lease.lease_time, 3600);
+        assert_eq!(lease.gateways, Some(vec![Ipv4Addr::new(10, 0, 0, 1)]));
+
+        let received = handle.join().expect("server failed");
+        assert_eq!(received[0].message_type(), Some(MessageType::Discover));
+        assert_eq!(received[0].giaddr, Ipv4Addr::LOCALHOST);
+        assert_eq!(received[0].hops, 1);
+        assert_eq!(received[0].flags, 0);
+        assert_eq!(
+            received[0].option(OPT_CLIENT_ID),
+            Some(&[1, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff][..])
+        );
+        assert_eq!(received[1].message_type(), Some(MessageType::Request));
+        assert_eq!(
+            received[1].option(OPT_REQUESTED_IP),
+            Some(&[10, 0, 0, 5][..])
+        );
+        for request in &received {
+            assert_eq!(
+                request.option(OPT_AGENT_INFORMATION),
+                Some(&b"\x01\x0beth0:abc123"[..])
+            );
+        }
+    }
+
+    #[test]
+    fn agent_information() {
+        let nc = config("foobar");
+        let info = AgentInformation::new(
+            Some("{host_iface}".to_string()),
+            Some("{network_name}/{container_id}".to_string()),
+        )
+        .expect("invalid templates");
+        assert_eq!(
+            info.encode(&nc).expect("could not encode"),
+            Some(b"\x01\x04eth0\x02\x0epodman1/abc123".to_vec())
+        );
+        // empty sub-options are left out
+        let info = AgentInformation::new(Some("{container_name}".to_string()), None)
+            .expect("invalid template");
+        assert_eq!(info.encode(&nc).expect("could not encode"), None);
+
+        assert!(AgentInformation::new(Some("{pod}".to_string()), None).is_err());
+        assert!(AgentInformation::new(None, Some("{host_iface".to_string())).is_err());
+
+        // the option is sent last and taken out of the replies
+        let mut msg = DhcpMessage::request(MessageType::Ack, 1, [0; 6]);
+        msg.options
+            .insert(0, (OPT_AGENT_INFORMATION, vec![1, 1, b'a']));
+        let mut decoded = DhcpMessage::decode(&msg.encode()).expect("could not decode");
+        assert_eq!(
+            decoded.options.last().map(|o| o.0),
+            Some(OPT_AGENT_INFORMATION)
+        );
+        assert_eq!(
+            decoded.remove_option(OPT_AGENT_INFORMATION),
+            Some(vec![1, 1, b'a'])
+        );
+        assert_eq!(decoded.option(OPT_AGENT_INFORMATION), None);
+    }
+
+    #[test]
+    fn no_reachable_server() {
+        let relay_config = RelayConfig {
+            agent_address: Ipv4Addr::LOCALHOST,
+            servers: vec![],
+            agent_information: AgentInformation::default(),
+        };
+        let relay = Relay::bind_at(relay_config, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0), 0)
+            .expect("could not bind relay");
+        let mut client =
+            RelayClient::new(relay, &config(""), None).expect("could not create client");
+        let err = client
+            .get_lease(1)
+            .expect_err("got a lease without a server");
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+    }
+
+    #[test]
+    fn relayed_nak() {
+        let (server, handle) = scripted_server(vec![MessageType::Nak]);
+        let mut lease = DhcpV4Lease::default();
+        lease.yiaddr = Ipv4Addr::new(10, 0, 0, 9);
+        let relay = relay_to(&server, AgentInformation::default());
+        let mut client =
+            RelayClient::new(relay, &config(""), Some(lease)).expect("could not create client");
+        let err = client.get_lease(2).expect_err("lease was not refused");
+        assert_eq!(err.kind(), ErrorKind::NoLease);
+        let received = handle.join().expect("server failed");
+        // INIT-REBOOT asks for the address without a server identifier
+        assert_eq!(
+            received[0].option(OPT_REQUESTED_IP),
+            Some(&[10, 0, 0, 9][..])
+        );
+        assert_eq!(received[0].option(OPT_SERVER_ID), None);
+    }
+
+    #[test]
+    fn relayed_ipvlan() {
+        let (server, handle) = scripted_server(vec![MessageType::Offer, MessageType::Ack]);
+        let relay = relay_to(&server, AgentInformation::default());
+        let nc = NetworkConfig {
+            container_iface: "eth1".to_string(),
+            ipvlan: true,
+            ..config("")
+        };
+        let mut client = RelayClient::new(relay, &nc, None).expect("could not create client");
+        client.get_lease(2).expect("no lease");
+        let received = handle.join().expect("server failed");
+        for request in &received {
+            assert_eq!(request.flags, BROADCAST_FLAG);
+            assert_eq!(request.option(OPT_CLIENT_ID), Some(&b"\0abc123/eth1"[..]));
+        }
+    }
+}
@@ -1,33 +1,65 @@
 #![cfg_attr(not(unix), allow(unused_imports))]
-use clap::Parser;
-use log::{debug, error, warn};
+use clap::{ArgEnum, Parser};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use log::{debug, error, info, warn};
 use macaddr::MacAddr;
-use netavark_proxy::cache::{Clear, LeaseCache};
-use netavark_proxy::dhcp_service::DhcpService;
+use netavark_proxy::audit::{AuditLog, Operation, Record};
+use netavark_proxy::auth::{Caller, Namespace, Policy};
+use netavark_proxy::cache::{
+    unix_now, AtomicFile, CachedLease, JournalStore, LeaseCache, LeaseKey, LeaseStore,
+    SnapshotStore,
+};
+use netavark_proxy::dhcp_service::{DhcpService, DhcpSettings};
 use netavark_proxy::g_rpc::netavark_proxy_server::{NetavarkProxy, NetavarkProxyServer};
-use netavark_proxy::g_rpc::{Empty, Lease as NetavarkLease, NetworkConfig, OperationResponse};
+use netavark_proxy::g_rpc::{
+    Empty, InspectRequest, InspectResponse, Lease as NetavarkLease, LeaseEvent, LeaseInfo,
+    NetworkConfig, OperationResponse, ProxyInfo, WatchRequest,
+};
 use netavark_proxy::ip;
+use netavark_proxy::metrics::Metrics;
 use netavark_proxy::proxy_conf::{
-    get_cache_fqname, get_proxy_sock_fqname, DEFAULT_INACTIVITY_TIMEOUT, DEFAULT_TIMEOUT,
+    get_audit_fqname, get_cache_fqname, get_journal_fqname, get_proxy_sock_fqname, API_VERSION,
+    AUDIT_MAX_SIZE, AUDIT_ROTATIONS, CAP_INSPECT, CAP_IN_NAMESPACE, CAP_IPVLAN, CAP_RELAY,
+    CAP_RENEWAL, CAP_REQUESTED_ADDRESS, CAP_WATCH, DEFAULT_INACTIVITY_TIMEOUT, DEFAULT_TIMEOUT,
+    LEASE_CHECK_INTERVAL, MIN_RENEWAL_RETRY, RECONCILE_INTERVAL, SHUTDOWN_DRAIN_TIMEOUT,
 };
-use std::fs::File;
-use std::io::Write;
+use netavark_proxy::relay::{AgentInformation, Relay, RelayConfig};
+use nix::unistd::Uid;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::net::UnixListener as stdUnixListener;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::{env, fs};
+use std::{env, fs, io};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, watch};
 use tokio::time::{timeout, Duration};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::TcpListenerStream;
 #[cfg(unix)]
 use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::server::NamedService;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
 use tonic::{transport::Server, Code, Code::Internal, Request, Response, Status};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 
 #[derive(Debug)]
 /// This is the tonic netavark proxy service that is required to impl the Netavark Proxy trait which
@@ -41,16 +73,36 @@ use tonic::{transport::Server, Code, Code::Internal, Request, Response, Status};
 ///    tonic creates its own runtime for each request and mozim trys to make its own runtime inside of
 ///    a runtime.
 ///
-struct NetavarkProxyService<W: Write + Clear> {
+struct NetavarkProxyService<W: LeaseStore> {
     // cache is the lease hashmap
     cache: Arc<Mutex<LeaseCache<W>>>,
-    // the timeout for the dora operation
-    dora_timeout: isize,
+    // how the DHCP servers are reached
+    dhcp: Arc<DhcpSettings>,
     // channel send-side for resetting the inactivity timeout
     timeout_sender: Arc<Mutex<Sender<i32>>>,
+    // counters exported on the metrics listener
+    metrics: Arc<Metrics>,
+    // who may call the proxy
+    policy: Arc<Policy>,
+    // record of the operations that changed leases
+    audit: Arc<AuditLog>,
 }
 
-impl<W: Write + Clear> NetavarkProxyService<W> {
+impl<W: LeaseStore> NetavarkProxyService<W> {
+    /// The optional features this proxy supports, as reported by GetInfo
+    fn capabilities(&self) -> Vec<&'static str> {
+        let mut capabilities = vec![CAP_RENEWAL, CAP_IPVLAN, CAP_INSPECT, CAP_WATCH];
+        // Only the relay can ask for an address
+        if self.dhcp.relay.is_some() {
+            capabilities.push(CAP_REQUESTED_ADDRESS);
+            capabilities.push(CAP_RELAY);
+        }
+        if self.dhcp.in_namespace {
+            capabilities.push(CAP_IN_NAMESPACE);
+        }
+        capabilities
+    }
+
     fn reset_inactivity_timeout(&self) {
         let sender = self.timeout_sender.clone();
         let locked_sender = match sender.lock() {
@@ -65,11 +117,38 @@ impl<W: Write + Clear> NetavarkProxyService<W> {
             Err(e) => log::error!("{}", e.to_string()),
         }
     }
+
+    /// Count a finished request by its result code
+    fn observe<T>(&self, method: &'static str, result: &Result<T, Status>) {
+        let code = match result {
+            Ok(_) => Code::Ok,
+            Err(s) => s.code(),
+        };
+        self.metrics.observe_request(method, code);
+    }
+
+    /// Write the result of an operation on a lease to the audit log
+    fn audit(&self, record: Record, result: &Result<Response<NetavarkLease>, Status>) {
+        let record = match result {
+            Ok(r) => record.with_lease(r.get_ref()),
+            Err(s) => record.with_error(s.message()),
+        };
+        self.audit.write(&record);
+    }
+
+    /// The caller of a request, if it may use the proxy
+    fn caller<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        let caller = Caller::of(request)?;
+        self.policy.authorize(&caller)?;
+        Ok(caller)
+    }
 }
 
 // gRPC request and response methods
 #[tonic::async_trait]
-impl<W: Write + Clear + Send + 'static> NetavarkProxy for NetavarkProxyService<W> {
+impl<W: LeaseStore + Send + 'static> NetavarkProxy for NetavarkProxyService<W> {
+    type WatchLeasesStream = Pin<Box<dyn Stream<Item = Result<LeaseEvent, Status>> + Send>>;
+
     /// gRPC connection to get a lease
     async fn setup(
         &self,
@@ -79,12 +158,31 @@ impl<W: Write + Clear + Send + 'static> NetavarkProxy for NetavarkProxyService<W
         // notify server of activity
         self.reset_inactivity_timeout();
 
+        let caller = Caller::of(&request);
+        let record = Record::new(Operation::Setup)
+            .with_config(request.get_ref())
+            .with_caller(caller.as_ref().ok());
+        let policy = self.policy.clone();
         let cache = self.cache.clone();
-        let timeout = self.dora_timeout;
+        let dhcp = self.dhcp.clone();
+        let metrics = self.metrics.clone();
+        let capabilities = self.capabilities();
         //Spawn a new thread to avoid tokio runtime issues
-        std::thread::spawn(move || {
+        let result = std::thread::spawn(move || {
             // Set up some common values
             let network_config = &request.into_inner();
+            let caller = caller?;
+            // Refuse what GetInfo does not report, instead of silently leaving it out
+            if let Some(missing) = network_config
+                .required_capabilities()
+                .into_iter()
+                .find(|c| !capabilities.contains(c))
+            {
+                return Err(Status::failed_precondition(format!(
+                    "the proxy does not support {missing}"
+                )));
+            }
+            let netns = policy.authorize_namespace(&caller, &network_config.ns_path)?;
             let container_network_interface = network_config.container_iface.clone();
             let mac_addr = network_config.container_mac_addr.clone();
             if mac_addr.is_empty() {
@@ -97,14 +195,37 @@ impl<W: Write + Clear + Send + 'static> NetavarkProxy for NetavarkProxyService<W
                 Ok(_) => {}
                 Err(_) => return Err(Status::new(Code::InvalidArgument, "Invalid mac address")),
             }
-            // create a dhcp service to get a lease.
-            let lease = DhcpService::new(network_config, timeout)?.get_lease()?;
+            if !caller.uid.is_root() {
+                check_claim(&cache, network_config, &netns, &caller)?;
+            }
+            // Everything in the namespace goes through the namespace that was checked. The
+            // configuration keeps the path netavark sent, it may make up the client id.
+            let ns_path = netns.path();
+            let dhcp = dhcp.with_namespace(ns_path.clone());
+            // A retried setup keeps the lease the container already holds
+            if let Some(lease) = reuse_lease(&cache, &metrics, network_config, &ns_path, &dhcp)? {
+                return Ok(Response::new(lease));
+            }
+            // Ask for the address the container had before, or the one it asked for. The
+            // server offers another address when it can not hand that one out.
+            let service = match address_to_request(&cache, network_config, &dhcp)? {
+                Some(address) => DhcpService::requesting(network_config, address, &dhcp)?,
+                // create a dhcp service to get a lease.
+                None => DhcpService::new(network_config, &dhcp)?,
+            };
+            let lease = service.with_metrics(metrics.clone()).get_lease()?;
             // Try and add the lease information to the cache
             if let Err(e) = cache
                 .lock()
                 .expect("Could not unlock cache. A thread was poisoned")
-                .add_lease(&mac_addr, &lease)
+                .add_owned_lease(
+                    &LeaseKey::from_config(network_config),
+                    &lease,
+                    network_config,
+                    netns.owner().map(|o| o.as_raw()),
+                )
             {
+                metrics.observe_cache_write_error();
                 return Err(Status::new(
                     Internal,
                     format!("Error caching the lease: {e}"),
@@ -116,13 +237,17 @@ impl<W: Write + Clear + Send + 'static> NetavarkProxy for NetavarkProxyService<W
             ip::setup(
                 &lease,
                 &container_network_interface,
-                &network_config.ns_path.to_string(),
+                &ns_path,
+                ip::LinkKind::of(network_config),
             )?;
 
             Ok(Response::new(lease))
         })
         .join()
-        .expect("Error joining thread")
+        .expect("Error joining thread");
+        self.observe("setup", &result);
+        self.audit(record, &result);
+        result
     }
 
     /// When a container is shut down this method should be called. It will clear the lease information
@@ -133,40 +258,158 @@ impl<W: Write + Clear + Send + 'static> NetavarkProxy for NetavarkProxyService<W
     ) -> Result<Response<NetavarkLease>, Status> {
         // notify server of activity
         self.reset_inactivity_timeout();
+        let caller = self.caller(&request);
+        let record = Record::new(Operation::Teardown)
+            .with_config(request.get_ref())
+            .with_caller(caller.as_ref().ok());
         let nc = request.into_inner();
 
+        let policy = self.policy.clone();
         let cache = self.cache.clone();
-        let timeout = self.dora_timeout;
+        let dhcp = self.dhcp.clone();
+        let metrics = self.metrics.clone();
 
-        std::thread::spawn(move || {
-            // Remove the client from the cache dir
-            let lease = cache
-                .clone()
+        let result = std::thread::spawn(move || {
+            let caller = caller?;
+            let mut locked_cache = cache
                 .lock()
-                .expect("Could not unlock cache. A thread was poisoned")
-                .remove_lease(&nc.container_mac_addr)
-                .map_err(|e| Status::internal(e.to_string()))?;
+                .expect("Could not unlock cache. A thread was poisoned");
+            let key = locked_cache.lookup(&nc).ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "container {} has more than one lease, add the network name or interface",
+                    nc.container_id
+                ))
+            })?;
+            // An expired lease is no longer ours to release. A lease found by container
+            // is released with the configuration it was set up with, unless it was stored
+            // before the configuration was kept with the lease.
+            let (expired, owner, nc) = match locked_cache.get(&key) {
+                Some(l) if l.network_config.host_iface.is_empty() => (l.expired, l.owner, nc),
+                Some(l) => (l.expired, l.owner, l.network_config.clone()),
+                None => (false, None, nc),
+            };
+            // The owner of the namespace is kept with the lease, the namespace may be
+            // gone already. Leases stored before that are checked against the namespace.
+            let netns = match owner {
+                Some(o) => policy.authorize_owner(&caller, Uid::from_raw(o), &nc.ns_path)?,
+                None => policy.authorize_namespace(&caller, &nc.ns_path)?,
+            };
+            // Remove the client from the cache dir
+            let lease = locked_cache.remove_lease(&key).map_err(|e| {
+                metrics.observe_cache_write_error();
+                Status::internal(e.to_string())
+            })?;
+            drop(locked_cache);
+            if expired {
+                return Ok(Response::new(lease));
+            }
 
             // Send the DHCP release message
-            DhcpService::new(&nc, timeout)?
+            DhcpService::new(&nc, &dhcp.with_namespace(netns.path()))?
                 .release_lease(&lease)
                 .map_err(|e| Status::internal(e.to_string()))?;
 
             Ok(Response::new(lease))
         })
         .join()
-        .expect("Error joining thread")
+        .expect("Error joining thread");
+        self.observe("teardown", &result);
+        self.audit(record, &result);
+        result
     }
 
     /// On teardown of the proxy the cache will be cleared gracefully.
     async fn clean(&self, request: Request<Empty>) -> Result<Response<OperationResponse>, Status> {
         log::debug!("Request from client: {:?}", request.remote_addr());
-        self.cache
-            .clone()
+        let caller = self.caller(&request);
+        let mut record = Record::new(Operation::Clean).with_caller(caller.as_ref().ok());
+        let result = caller
+            .and_then(|caller| self.policy.authorize_all(&caller))
+            .and_then(|_| {
+                self.cache
+                    .clone()
+                    .lock()
+                    .expect("Could not unlock cache. A thread was poisoned")
+                    .teardown()
+                    .map_err(|e| {
+                        self.metrics.observe_cache_write_error();
+                        Status::from(e)
+                    })
+            })
+            .map(|_| Response::new(OperationResponse { success: true }));
+        self.observe("clean", &result);
+        if let Err(s) = &result {
+            record = record.with_error(s.message());
+        }
+        self.audit.write(&record);
+        result
+    }
+
+    /// Return the cached leases that pass the filters of the request
+    async fn inspect(
+        &self,
+        request: Request<InspectRequest>,
+    ) -> Result<Response<InspectResponse>, Status> {
+        let caller = self.caller(&request);
+        let filter = request.into_inner();
+        let result = caller
+            .and_then(|_| {
+                self.cache
+                    .lock()
+                    .map_err(|e| Status::internal(e.to_string()))
+            })
+            .map(|c| c.find(&filter.mac_address, &filter.container_id))
+            .map(|found| {
+                let leases = found
+                    .into_iter()
+                    .map(|(_, entry)| LeaseInfo {
+                        config: Some(entry.network_config),
+                        lease: Some(entry.lease),
+                        obtained_at: entry.obtained_at,
+                        expires_at: entry.expires_at.unwrap_or(0),
+                        expired: entry.expired,
+                    })
+                    .collect();
+                Response::new(InspectResponse { leases })
+            });
+        self.observe("inspect", &result);
+        result
+    }
+
+    /// Stream the events of the leases that pass the filters of the request until the
+    /// client goes away.
+    async fn watch_leases(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchLeasesStream>, Status> {
+        self.caller(&request)?;
+        let filter = request.into_inner();
+        let events = self
+            .cache
             .lock()
             .expect("Could not unlock cache. A thread was poisoned")
-            .teardown()?;
-        Ok(Response::new(OperationResponse { success: true }))
+            .subscribe();
+        let stream = BroadcastStream::new(events).filter_map(move |event| match event {
+            Ok(e) if filter.matches(&e) => Some(Ok(e)),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                warn!("Lease watcher fell behind, skipped {} events", n);
+                None
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    /// Tell the client which version of the proxy it talks to and what the proxy supports
+    async fn get_info(&self, request: Request<Empty>) -> Result<Response<ProxyInfo>, Status> {
+        // notify server of activity
+        self.reset_inactivity_timeout();
+        self.caller(&request)?;
+        Ok(Response::new(ProxyInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            api_version: API_VERSION,
+            capabilities: self.capabilities().into_iter().map(String::from).collect(),
+        }))
     }
 }
 
@@ -185,16 +428,69 @@ struct Opts {
     /// activity timeout
     #[clap(short, long)]
     activity_timout: Option<u64>,
+    /// serve prometheus metrics on this unix domain socket
+    #[clap(long)]
+    metrics_uds: Option<String>,
+    /// serve prometheus metrics on this port of the loopback address
+    #[clap(long, conflicts_with = "metrics-uds")]
+    metrics_port: Option<u16>,
+    /// how leases are stored across restarts
+    #[clap(long, arg_enum, default_value = "snapshot")]
+    lease_store: StoreKind,
+    /// group whose members may call the proxy for network namespaces they own,
+    /// by default only root may call it
+    #[clap(long)]
+    allow_group: Option<String>,
+    /// run the DHCP client inside the network namespace of the container
+    #[clap(long, conflicts_with = "relay-address")]
+    in_namespace: bool,
+    /// relay DHCP messages from this address of the host instead of broadcasting them
+    #[clap(long, requires = "relay-server")]
+    relay_address: Option<Ipv4Addr>,
+    /// DHCP server to relay the messages to, can be given more than once
+    #[clap(long, requires = "relay-address", multiple_occurrences = true)]
+    relay_server: Vec<Ipv4Addr>,
+    /// template of the circuit id sent in the relay agent information option (82)
+    #[clap(long, requires = "relay-address")]
+    circuit_id: Option<String>,
+    /// template of the remote id sent in the relay agent information option (82)
+    #[clap(long, requires = "relay-address")]
+    remote_id: Option<String>,
+    /// also serve the proxy on this TCP address, only to clients with a certificate
+    /// signed by the client CA
+    #[clap(long, requires_all = &["tls-cert", "tls-key", "tls-client-ca"])]
+    tcp_address: Option<SocketAddr>,
+    /// certificate of the TCP listener, in PEM format
+    #[clap(long, requires = "tcp-address")]
+    tls_cert: Option<PathBuf>,
+    /// private key of the certificate of the TCP listener, in PEM format
+    #[clap(long, requires = "tcp-address")]
+    tls_key: Option<PathBuf>,
+    /// CA certificate the certificates of TCP clients are verified against, in PEM format
+    #[clap(long, requires = "tcp-address")]
+    tls_client_ca: Option<PathBuf>,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum StoreKind {
+    /// rewrite a json map of all leases on every change
+    Snapshot,
+    /// append every change to a journal that is compacted from time to time
+    Journal,
 }
 
+/// The lease store picked on the command line
+type Store = Box<dyn LeaseStore + Send>;
+
 /// Handle SIGINT signal.
 ///
-/// Will wait until process receives a SIGINT/ ctrl+c signal and then clean up and shut down
-async fn handle_signal(uds_path: PathBuf) {
+/// Will wait until process receives a SIGINT/ ctrl+c signal and then tell the server to shut
+/// down, which reports NOT_SERVING, drains the listeners and cleans up.
+async fn handle_signal(shutdown: oneshot::Sender<()>) {
+    // Handle signal hooks with expect, it is important these are setup so data is not corrupted
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not set up SIGTERM hook");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Could not set up SIGINT hook");
     tokio::spawn(async move {
-        // Handle signal hooks with expect, it is important these are setup so data is not corrupted
-        let mut sigterm = signal(SignalKind::terminate()).expect("Could not set up SIGTERM hook");
-        let mut sigint = signal(SignalKind::interrupt()).expect("Could not set up SIGINT hook");
         // Wait for either a SIGINT or a SIGTERM to clean up
         tokio::select! {
             _ = sigterm.recv() => {
@@ -204,21 +500,41 @@ async fn handle_signal(uds_path: PathBuf) {
                 warn!("Received SIGINT, cleaning up and exiting");
             }
         }
-        if let Err(e) = fs::remove_file(uds_path) {
-            error!("Could not close uds socket: {}", e);
-        }
-
-        std::process::exit(0x0100);
+        let _ = shutdown.send(());
     });
 }
 
+/// Resolves once the listeners should stop taking new connections
+async fn drained(mut draining: watch::Receiver<bool>) {
+    while !*draining.borrow() {
+        if draining.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
 #[tokio::main]
 #[allow(unused)]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::builder().format_timestamp(None).init();
     let opts = Opts::parse();
     let optional_run_dir = opts.dir.as_deref();
-    let dora_timeout = opts.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let mut dhcp = DhcpSettings::new(opts.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    dhcp.in_namespace = opts.in_namespace;
+    if let Some(agent_address) = opts.relay_address {
+        let config = RelayConfig {
+            agent_address,
+            servers: opts.relay_server.clone(),
+            agent_information: AgentInformation::new(
+                opts.circuit_id.clone(),
+                opts.remote_id.clone(),
+            )?,
+        };
+        debug!("relaying DHCP messages to {:?}", config.servers);
+        dhcp.relay = Some(Relay::bind(config)?);
+    }
+    let dhcp = Arc::new(dhcp);
+    let policy = Arc::new(Policy::new(opts.allow_group.as_deref()).map_err(|e| e.to_string())?);
     let inactivity_timeout =
         Duration::from_secs(opts.activity_timout.unwrap_or(DEFAULT_INACTIVITY_TIMEOUT));
 
@@ -236,8 +552,16 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         Some(f) => tokio::fs::create_dir_all(f).await?,
     }
+    // Health checks report NOT_SERVING until the cache is loaded
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    set_health(&mut health_reporter, ServingStatus::NotServing).await;
+
     // Watch for signals after the uds path has been created, so that the socket can be closed.
-    handle_signal(uds_path.clone()).await;
+    let (signal_tx, signal_rx) = oneshot::channel();
+    handle_signal(signal_tx).await;
+    // Set once shutdown starts, the listeners then finish the requests in flight
+    let (drain_tx, drain_rx) = watch::channel(false);
+    let metrics_path = opts.metrics_uds.as_ref().map(PathBuf::from);
 
     // check if the UDS is a systemd socket activated service.  if it is,
     // then systemd hands this over to us on FD 3.
@@ -257,20 +581,21 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let uds_stream = UnixListenerStream::new(uds);
 
-    // Create the cache file
-    let fq_cache_path = get_cache_fqname(optional_run_dir);
-    let file = match File::create(&fq_cache_path) {
-        Ok(file) => {
-            debug!("Successfully created leases file: {:?}", fq_cache_path);
-            file
+    // Load the leases of a previous run from the lease store
+    let store: Store = match opts.lease_store {
+        StoreKind::Snapshot => {
+            let fq_cache_path = get_cache_fqname(optional_run_dir);
+            debug!("Using leases file: {:?}", fq_cache_path);
+            Box::new(SnapshotStore::new(AtomicFile::open(fq_cache_path)))
         }
-        Err(e) => {
-            error!("Exiting. Could not create lease cache file: {:?}", e);
-            return Ok(());
+        StoreKind::Journal => {
+            let fq_journal_path = get_journal_fqname(optional_run_dir);
+            debug!("Using lease journal: {:?}", fq_journal_path);
+            Box::new(JournalStore::new(AtomicFile::open(fq_journal_path)))
         }
     };
 
-    let cache = match LeaseCache::new(file) {
+    let cache = match LeaseCache::new(store) {
         Ok(c) => Arc::new(Mutex::new(c)),
         Err(e) => {
             log::error!("Could not setup the cache: {}", e.to_string());
@@ -278,31 +603,248 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let metrics = Arc::new(Metrics::new());
+
+    let fq_audit_path = get_audit_fqname(optional_run_dir);
+    debug!("Using audit log: {:?}", fq_audit_path);
+    let audit = Arc::new(AuditLog::open(
+        &fq_audit_path,
+        AUDIT_MAX_SIZE,
+        AUDIT_ROTATIONS,
+    )?);
+
+    // Renew leases in the background and take away the ones that run out.
+    // mozim can not run inside of the tokio runtime so this gets its own thread.
+    let maintenance_cache = cache.clone();
+    let maintenance_metrics = metrics.clone();
+    let maintenance_dhcp = dhcp.clone();
+    let maintenance_audit = audit.clone();
+    std::thread::spawn(move || {
+        maintain_leases(
+            maintenance_cache,
+            maintenance_metrics,
+            maintenance_dhcp,
+            maintenance_audit,
+            Duration::from_secs(LEASE_CHECK_INTERVAL),
+        )
+    });
+
+    // Drop the leases of containers that went away without a teardown. Removed host links
+    // trigger a check right away, the namespaces are checked periodically either way.
+    let (link_tx, link_rx) = std::sync::mpsc::channel();
+    match ip::LinkMonitor::open() {
+        Ok(monitor) => {
+            std::thread::spawn(move || loop {
+                match monitor.removed_links() {
+                    Ok(links) => {
+                        debug!("links removed: {}", links.join(", "));
+                        if link_tx.send(()).is_err() {
+                            return;
+                        }
+                    }
+                    // Missed messages may have been removed links, so check the namespaces
+                    // right away and keep on monitoring
+                    Err(e) => {
+                        warn!("Link monitor failed: {}", e.to_string());
+                        if link_tx.send(()).is_err() {
+                            return;
+                        }
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            });
+        }
+        Err(e) => warn!(
+            "Could not monitor links, relying on periodic checks: {}",
+            e.to_string()
+        ),
+    }
+    let reconcile_cache = cache.clone();
+    let reconcile_metrics = metrics.clone();
+    let reconcile_dhcp = dhcp.clone();
+    let reconcile_audit = audit.clone();
+    std::thread::spawn(move || {
+        reconcile_leases(
+            reconcile_cache,
+            reconcile_metrics,
+            reconcile_dhcp,
+            reconcile_audit,
+            link_rx,
+            Duration::from_secs(RECONCILE_INTERVAL),
+        )
+    });
+
+    // The metrics listener is optional, failing to set it up does not stop the proxy
+    if let Some(path) = &metrics_path {
+        // Remove a socket left behind by a previous run
+        match remove_socket(path).and_then(|_| UnixListener::bind(path)) {
+            Ok(l) => {
+                debug!("serving metrics on {:?}", path);
+                tokio::spawn(serve_metrics(
+                    UnixListenerStream::new(l),
+                    metrics.clone(),
+                    cache.clone(),
+                ));
+            }
+            Err(e) => error!("Could not listen for metrics on {:?}: {}", path, e),
+        }
+    } else if let Some(port) = opts.metrics_port {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        match TcpListener::bind(addr).await {
+            Ok(l) => {
+                debug!("serving metrics on {}", addr);
+                tokio::spawn(serve_metrics(
+                    TcpListenerStream::new(l),
+                    metrics.clone(),
+                    cache.clone(),
+                ));
+            }
+            Err(e) => error!("Could not listen for metrics on {}: {}", addr, e),
+        }
+    }
+
     // Create send and receive channels for activity timeout. If anything is
     // sent by the tx side, the inactivity timeout is reset
     let (activity_timeout_tx, activity_timeout_rx) = mpsc::channel(5);
-    let netavark_proxy_service = NetavarkProxyService {
+    let netavark_proxy_service = Arc::new(NetavarkProxyService {
         cache: cache.clone(),
-        dora_timeout,
+        dhcp,
         timeout_sender: Arc::new(Mutex::new(activity_timeout_tx.clone())),
-    };
+        metrics: metrics.clone(),
+        policy,
+        audit,
+    });
+
+    // The TCP listener serves the same service next to the uds, the TLS files were asked
+    // for explicitly so failing to load them stops the proxy
+    if let Some(addr) = opts.tcp_address {
+        let tls = server_tls_config(
+            opts.tls_cert.as_deref(),
+            opts.tls_key.as_deref(),
+            opts.tls_client_ca.as_deref(),
+        )?;
+        let listener = TcpListener::bind(addr).await?;
+        debug!("serving mutual TLS on {}", addr);
+        let tcp_server = Server::builder()
+            .tls_config(tls)?
+            .add_service(health_service.clone())
+            .add_service(NetavarkProxyServer::from_arc(
+                netavark_proxy_service.clone(),
+            ))
+            .serve_with_incoming_shutdown(
+                TcpListenerStream::new(listener),
+                drained(drain_rx.clone()),
+            );
+        tokio::spawn(async move {
+            if let Err(e) = tcp_server.await {
+                error!("TCP listener on {} failed: {}", addr, e);
+            }
+        });
+    }
+
+    set_health(&mut health_reporter, ServingStatus::Serving).await;
 
     let server = Server::builder()
-        .add_service(NetavarkProxyServer::new(netavark_proxy_service))
-        .serve_with_incoming(uds_stream);
+        .add_service(health_service)
+        .add_service(NetavarkProxyServer::from_arc(netavark_proxy_service))
+        .serve_with_incoming_shutdown(uds_stream, drained(drain_rx));
 
     tokio::pin!(server);
 
+    let mut stopped = false;
     tokio::select! {
         //  a timeout duration of 0 means NEVER
         _ = handle_wakeup(activity_timeout_rx, inactivity_timeout, cache.clone()), if inactivity_timeout.as_secs() > 0  => {},
-        _ = &mut server => {},
+        _ = signal_rx => {},
+        _ = &mut server => stopped = true,
     };
 
-    fs::remove_file(uds_path);
+    // Health checks see NOT_SERVING before the listeners stop taking new connections
+    set_health(&mut health_reporter, ServingStatus::NotServing).await;
+    let _ = drain_tx.send(true);
+    // Streams like WatchLeases never end on their own, so the drain is bounded
+    let drain = Duration::from_secs(SHUTDOWN_DRAIN_TIMEOUT);
+    if !stopped && timeout(drain, &mut server).await.is_err() {
+        warn!(
+            "Requests still in flight after {} secs, exiting",
+            SHUTDOWN_DRAIN_TIMEOUT
+        );
+    }
+
+    if let Err(e) = fs::remove_file(uds_path) {
+        error!("Could not close uds socket: {}", e);
+    }
+    if let Some(path) = metrics_path {
+        if let Err(e) = remove_socket(&path) {
+            error!("Could not close metrics socket: {}", e);
+        }
+    }
     Ok(())
 }
 
+/// Remove the unix socket at the given path. Anything else found at the path is left alone.
+fn remove_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{path:?} exists and is not a socket"),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Build the TLS configuration of the TCP listener. Clients have to present a certificate
+/// that is signed by the client CA.
+///
+/// # Arguments
+///
+/// * `cert`: certificate of the listener
+/// * `key`: private key of the certificate
+/// * `client_ca`: CA certificate the client certificates are verified against
+///
+/// returns: Result<ServerTlsConfig, io::Error>
+fn server_tls_config(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    client_ca: Option<&Path>,
+) -> Result<ServerTlsConfig, io::Error> {
+    let read = |path: Option<&Path>, what: &str| match path {
+        Some(p) => fs::read(p)
+            .map_err(|e| io::Error::new(e.kind(), format!("could not read {what} {p:?}: {e}"))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the TCP listener needs a {what}"),
+        )),
+    };
+    Ok(ServerTlsConfig::new()
+        .identity(Identity::from_pem(
+            read(cert, "certificate")?,
+            read(key, "key")?,
+        ))
+        .client_ca_root(Certificate::from_pem(read(client_ca, "client CA")?)))
+}
+
+/// Set the health of the proxy as reported by the grpc.health.v1.Health service. Both the
+/// overall health of the server (the empty service name) and the proxy service are set.
+///
+/// # Arguments
+///
+/// * `reporter`: the health reporter that is linked to the health service
+/// * `status`: the new serving status
+///
+/// returns: ()
+async fn set_health(reporter: &mut HealthReporter, status: ServingStatus) {
+    reporter.set_service_status("", status).await;
+    reporter
+        .set_service_status(
+            <NetavarkProxyServer<NetavarkProxyService<Store>> as NamedService>::NAME,
+            status,
+        )
+        .await;
+}
+
 /// manages the timeout lifecycle for the proxy server based on a defined timeout.
 ///
 /// # Arguments
@@ -317,7 +859,7 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 /// ```
 ///
 /// ```
-async fn handle_wakeup<W: Write + Clear>(
+async fn handle_wakeup<W: LeaseStore>(
     mut rx: tokio::sync::mpsc::Receiver<i32>,
     timeout_duration: Duration,
     current_cache: Arc<Mutex<LeaseCache<W>>>,
@@ -359,7 +901,7 @@ async fn handle_wakeup<W: Write + Clear>(
 /// ```
 ///
 /// ```
-fn is_catch_empty<W: Write + Clear>(current_cache: Arc<Mutex<LeaseCache<W>>>) -> bool {
+fn is_catch_empty<W: LeaseStore>(current_cache: Arc<Mutex<LeaseCache<W>>>) -> bool {
     match current_cache.lock() {
         Ok(v) => {
             debug!("cache_len is {}", v.len().to_string());
@@ -371,3 +913,499 @@ fn is_catch_empty<W: Write + Clear>(current_cache: Arc<Mutex<LeaseCache<W>>>) ->
         }
     }
 }
+
+/// Serve the proxy metrics in the prometheus text format. Every request gets the metrics,
+/// regardless of its path.
+///
+/// # Arguments
+///
+/// * `incoming`: stream of connections of the metrics listener
+/// * `metrics`: counters of the proxy
+/// * `cache`: the lease cache, to count the active leases
+///
+/// returns: ()
+async fn serve_metrics<S, IO, W>(
+    incoming: S,
+    metrics: Arc<Metrics>,
+    cache: Arc<Mutex<LeaseCache<W>>>,
+) where
+    S: Stream<Item = io::Result<IO>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    W: LeaseStore + Send + 'static,
+{
+    let make_service = make_service_fn(move |_: &IO| {
+        let metrics = metrics.clone();
+        let cache = cache.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_: hyper::Request<Body>| {
+                let active_leases = match cache.lock() {
+                    Ok(c) => c.leases_per_interface(),
+                    Err(e) => {
+                        log::error!("{e}");
+                        HashMap::new()
+                    }
+                };
+                let body = metrics.render(&active_leases);
+                async move {
+                    Ok::<_, Infallible>(
+                        hyper::Response::builder()
+                            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Body::from(body))
+                            .unwrap_or_default(),
+                    )
+                }
+            }))
+        }
+    });
+    if let Err(e) = hyper::Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .await
+    {
+        error!("Metrics listener failed: {}", e);
+    }
+}
+
+/// Keeps the cached leases alive. A lease is renewed once T1 has passed, and rebound once
+/// T2 has passed. When a lease runs out without being renewed, the address and the routes
+/// are removed from the container namespace and the lease is marked expired.
+///
+/// # Arguments
+///
+/// * `cache`: the lease cache shared with the gRPC service
+/// * `metrics`: counters of the proxy
+/// * `dhcp`: how the DHCP servers are reached
+/// * `audit`: where renewals and expiries are recorded
+/// * `interval`: time between checks of the leases
+///
+/// returns: ()
+fn maintain_leases<W: LeaseStore>(
+    cache: Arc<Mutex<LeaseCache<W>>>,
+    metrics: Arc<Metrics>,
+    dhcp: Arc<DhcpSettings>,
+    audit: Arc<AuditLog>,
+    interval: Duration,
+) {
+    // earliest time to try again after a failed renewal, per lease
+    let mut retry_at: HashMap<LeaseKey, u64> = HashMap::new();
+    loop {
+        std::thread::sleep(interval);
+        let leases = match cache.lock() {
+            Ok(c) => c.active_leases(),
+            Err(e) => {
+                log::error!("{e}");
+                continue;
+            }
+        };
+        retry_at.retain(|key, _| leases.iter().any(|(k, _)| k == key));
+
+        let now = unix_now();
+        for (key, entry) in leases {
+            if entry.is_expired_at(now) {
+                retry_at.remove(&key);
+                let record = Record::new(Operation::Expiry)
+                    .with_config(&entry.network_config)
+                    .with_lease(&entry.lease);
+                match expire_lease(&cache, &metrics, &key, &entry) {
+                    Ok(true) => audit.write(&record),
+                    Ok(false) => {}
+                    Err(e) => audit.write(&record.with_error(e.message())),
+                }
+                continue;
+            }
+            let renew_at = match entry.renew_at() {
+                Some(t) => t.max(*retry_at.get(&key).unwrap_or(&0)),
+                None => continue,
+            };
+            if now < renew_at {
+                continue;
+            }
+            // Past T2 the lease is rebound, which lasts until the lease runs out
+            let (phase, deadline) = match entry.rebind_at() {
+                Some(t) if now < t => ("renew", t),
+                _ => ("rebind", entry.expires_at.unwrap_or(now)),
+            };
+            debug!("trying to {} lease for {}", phase, key);
+            let record = Record::new(Operation::Renewal).with_config(&entry.network_config);
+            match renew_lease(&cache, &metrics, &key, &entry, &dhcp) {
+                Ok(lease) => {
+                    retry_at.remove(&key);
+                    audit.write(&record.with_lease(&lease));
+                }
+                Err(e) => {
+                    audit.write(&record.with_lease(&entry.lease).with_error(e.message()));
+                    // As per RFC 2131, wait half of the remaining time, but at least 60 seconds
+                    let wait = (deadline.saturating_sub(now) / 2).max(MIN_RENEWAL_RETRY);
+                    warn!(
+                        "Failed to {} lease for {}, retrying in {} secs: {}",
+                        phase, key, wait, e
+                    );
+                    retry_at.insert(key, now + wait);
+                }
+            }
+        }
+    }
+}
+
+/// Renew a single lease and store the result in the cache. Should the server hand out a
+/// different address, the container namespace is switched over to it.
+///
+/// The cache stays locked while the namespace is changed, so the renewal does not mix with
+/// a teardown or a new setup of the container. A lease that was torn down or replaced while
+/// the DHCP exchange was in flight is left alone.
+fn renew_lease<W: LeaseStore>(
+    cache: &Arc<Mutex<LeaseCache<W>>>,
+    metrics: &Arc<Metrics>,
+    key: &LeaseKey,
+    entry: &CachedLease,
+    dhcp: &DhcpSettings,
+) -> Result<NetavarkLease, Status> {
+    let nc = &entry.network_config;
+    let lease = DhcpService::with_lease(nc, &entry.lease, dhcp)?
+        .with_metrics(metrics.clone())
+        .get_lease()?;
+    let mut locked_cache = cache.lock().map_err(|e| Status::internal(e.to_string()))?;
+    if locked_cache.get(key) != Some(entry) {
+        return Err(Status::new(
+            Code::Aborted,
+            format!("lease for {key} was torn down or replaced during the renewal"),
+        ));
+    }
+    if lease.yiaddr != entry.lease.yiaddr {
+        warn!(
+            "Lease for {} changed address from {} to {}",
+            key, entry.lease.yiaddr, lease.yiaddr
+        );
+        if let Err(e) = ip::teardown(&entry.lease, &nc.container_iface, &nc.ns_path) {
+            warn!(
+                "Could not remove old address {}: {}",
+                entry.lease.yiaddr,
+                e.to_string()
+            );
+        }
+        ip::setup(
+            &lease,
+            &nc.container_iface,
+            &nc.ns_path,
+            ip::LinkKind::of(nc),
+        )?;
+    } else {
+        // Start the kernel address lifetimes over with the renewed lease
+        ip::refresh(&lease, &nc.container_iface, &nc.ns_path)?;
+    }
+    locked_cache
+        .update_lease(key, lease.clone())
+        .map_err(|e| {
+            metrics.observe_cache_write_error();
+            Status::new(Internal, format!("Error caching the lease: {e}"))
+        })
+        .map(|_| lease)
+}
+
+/// Verify the cached lease of a configuration that is set up again, and put back what the
+/// namespace is missing of it. None when there is no cached lease to use, or the DHCP server
+/// refused it, so a new lease has to be obtained. The namespace is changed through ns_path,
+/// the namespace that was checked.
+fn reuse_lease<W: LeaseStore>(
+    cache: &Arc<Mutex<LeaseCache<W>>>,
+    metrics: &Arc<Metrics>,
+    nc: &NetworkConfig,
+    ns_path: &str,
+    dhcp: &DhcpSettings,
+) -> Result<Option<NetavarkLease>, Status> {
+    let key = LeaseKey::from_config(nc);
+    // Only the namespace the lease was set up for may keep it
+    let entry = match cache
+        .lock()
+        .map_err(|e| Status::internal(e.to_string()))?
+        .get(&key)
+    {
+        Some(e)
+            if !e.expired
+                && !e.is_expired_at(unix_now())
+                && e.network_config.ns_path == nc.ns_path =>
+        {
+            e.clone()
+        }
+        _ => return Ok(None),
+    };
+    debug!("verifying the cached lease for {}", key);
+    let verified = DhcpService::init_reboot(nc, &entry.lease, dhcp)?
+        .with_metrics(metrics.clone())
+        .get_lease()
+        .map_err(Status::from);
+    let lease = match verified {
+        Ok(l) if l.yiaddr == entry.lease.yiaddr => {
+            let mut locked_cache = cache.lock().map_err(|e| Status::internal(e.to_string()))?;
+            locked_cache.update_lease(&key, l.clone()).map_err(|e| {
+                metrics.observe_cache_write_error();
+                Status::new(Internal, format!("Error caching the lease: {e}"))
+            })?;
+            l
+        }
+        // Without an answer the lease stays in use until it runs out, as per RFC 2131
+        Err(e) if e.code() == Code::Aborted => {
+            debug!("No answer verifying the lease for {}, keeping it", key);
+            entry.lease.clone()
+        }
+        refused => {
+            info!(
+                "Cached lease for {} was not confirmed, obtaining a new one: {:?}",
+                key,
+                refused.err()
+            );
+            if let Err(e) = ip::teardown(&entry.lease, &nc.container_iface, ns_path) {
+                debug!("Could not remove the refused address: {}", e.to_string());
+            }
+            return Ok(None);
+        }
+    };
+    ip::reapply(&lease, &nc.container_iface, ns_path)?;
+    Ok(Some(lease))
+}
+
+/// Check that a caller that is not root only claims the mac address of its own container.
+/// The container interface has to carry the mac address on the parent interface, and a
+/// lease of that mac address has to belong to the same namespace and user.
+fn check_claim<W: LeaseStore>(
+    cache: &Arc<Mutex<LeaseCache<W>>>,
+    nc: &NetworkConfig,
+    netns: &Namespace,
+    caller: &Caller,
+) -> Result<(), Status> {
+    let key = LeaseKey::from_config(nc);
+    if let Some(entry) = cache
+        .lock()
+        .map_err(|e| Status::internal(e.to_string()))?
+        .get(&key)
+    {
+        if entry.network_config.ns_path != nc.ns_path || entry.owner != Some(caller.uid.as_raw()) {
+            return Err(Status::permission_denied(format!(
+                "the lease of {key} belongs to another network namespace"
+            )));
+        }
+    }
+    match ip::interface_is_link_of(
+        &nc.container_iface,
+        &nc.container_mac_addr,
+        &nc.host_iface,
+        &netns.path(),
+    ) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Status::permission_denied(format!(
+            "interface {} of network namespace {} is not a link of {} with mac address {}",
+            nc.container_iface, nc.ns_path, nc.host_iface, nc.container_mac_addr
+        ))),
+        Err(e) => Err(Status::permission_denied(format!(
+            "could not check interface {} of network namespace {}: {}",
+            nc.container_iface,
+            nc.ns_path,
+            e.to_string()
+        ))),
+    }
+}
+
+/// The address a configuration asks for, or else the address its container had before.
+/// None when there is no such address.
+///
+/// Only the relay can ask for an address, mozim has no way to put it in the DISCOVER.
+/// Without the relay a configuration that asks for an address is refused by setup, and the
+/// address the container had before is not asked for.
+fn address_to_request<W: LeaseStore>(
+    cache: &Arc<Mutex<LeaseCache<W>>>,
+    nc: &NetworkConfig,
+    dhcp: &DhcpSettings,
+) -> Result<Option<Ipv4Addr>, Status> {
+    // DHCPv6 is not implemented
+    if nc.version != 0 {
+        return Ok(None);
+    }
+    if dhcp.relay.is_none() {
+        if !nc.requested_address.is_empty() {
+            return Err(Status::failed_precondition(
+                "the proxy does not support requested_address",
+            ));
+        }
+        if let Some(previous) = cache
+            .lock()
+            .map_err(|e| Status::internal(e.to_string()))?
+            .previous_address(nc)
+        {
+            info!(
+                "Not asking for {}, the address {} had before: only the relay can ask for an address",
+                previous, nc.container_mac_addr
+            );
+        }
+        return Ok(None);
+    }
+    let address = if nc.requested_address.is_empty() {
+        let previous = cache
+            .lock()
+            .map_err(|e| Status::internal(e.to_string()))?
+            .previous_address(nc);
+        match previous {
+            Some(a) => a,
+            None => return Ok(None),
+        }
+    } else {
+        nc.requested_address.clone()
+    };
+    let address = Ipv4Addr::from_str(&address).map_err(|e| {
+        Status::invalid_argument(format!("Invalid requested address {address}: {e}"))
+    })?;
+    debug!("requesting {} for {}", address, nc.container_mac_addr);
+    Ok(Some(address))
+}
+
+/// Drops the leases of containers whose namespace or interface is gone. When netavark
+/// never sends a teardown, the lease would otherwise stay in the cache, keep the proxy
+/// alive and hold on to the address in the DHCP pool.
+///
+/// # Arguments
+///
+/// * `cache`: the lease cache shared with the gRPC service
+/// * `metrics`: counters of the proxy
+/// * `dhcp`: how the DHCP servers are reached
+/// * `audit`: where released leases are recorded
+/// * `links_removed`: woken up whenever a link is removed from the host
+/// * `interval`: time between checks when no link is removed
+///
+/// returns: ()
+fn reconcile_leases<W: LeaseStore>(
+    cache: Arc<Mutex<LeaseCache<W>>>,
+    metrics: Arc<Metrics>,
+    dhcp: Arc<DhcpSettings>,
+    audit: Arc<AuditLog>,
+    links_removed: std::sync::mpsc::Receiver<()>,
+    interval: Duration,
+) {
+    loop {
+        if let Err(std::sync::mpsc::RecvTimeoutError::Disconnected) =
+            links_removed.recv_timeout(interval)
+        {
+            // Without the link monitor the namespaces are still checked periodically
+            std::thread::sleep(interval);
+        }
+        let leases = match cache.lock() {
+            Ok(c) => c.leases(),
+            Err(e) => {
+                log::error!("{e}");
+                continue;
+            }
+        };
+        for (key, entry) in leases {
+            let nc = &entry.network_config;
+            if nc.ns_path.is_empty() {
+                continue;
+            }
+            match ip::interface_exists(&nc.container_iface, &nc.ns_path) {
+                Ok(true) => {}
+                Ok(false) => {
+                    let record = Record::new(Operation::Release)
+                        .with_config(nc)
+                        .with_lease(&entry.lease);
+                    match release_stale_lease(&cache, &metrics, &key, &entry, &dhcp) {
+                        Ok(true) => audit.write(&record),
+                        Ok(false) => {}
+                        Err(e) => audit.write(&record.with_error(e.message())),
+                    }
+                }
+                // Keep the lease when we can not tell, the next check will try again
+                Err(e) => debug!(
+                    "Could not check the interface of {}: {}",
+                    key,
+                    e.to_string()
+                ),
+            }
+        }
+    }
+}
+
+/// Release the lease of a container that went away and remove it from the cache. The lease
+/// is only removed while the cache still holds it for the same namespace and address, a
+/// container that was set up again under the same key keeps its new lease.
+///
+/// Returns false when the lease was kept for that reason. The lease is released with the
+/// DHCP server even when it could not be removed from the store.
+fn release_stale_lease<W: LeaseStore>(
+    cache: &Arc<Mutex<LeaseCache<W>>>,
+    metrics: &Arc<Metrics>,
+    key: &LeaseKey,
+    entry: &CachedLease,
+    dhcp: &DhcpSettings,
+) -> Result<bool, Status> {
+    let nc = &entry.network_config;
+    let removed = {
+        let mut c = cache.lock().map_err(|e| Status::internal(e.to_string()))?;
+        match c.get(key) {
+            Some(cached)
+                if cached.network_config.ns_path == nc.ns_path
+                    && cached.lease.yiaddr == entry.lease.yiaddr => {}
+            _ => {
+                debug!("lease for {} changed since it was checked, keeping it", key);
+                return Ok(false);
+            }
+        }
+        warn!(
+            "Interface {} in {} is gone, releasing lease for {}",
+            nc.container_iface, nc.ns_path, key
+        );
+        c.remove_lease(key).map(|_| ()).map_err(|e| {
+            metrics.observe_cache_write_error();
+            error!("Could not remove lease for {}: {}", key, e);
+            Status::internal(format!("Error removing the lease: {e}"))
+        })
+    };
+    // An expired lease is no longer ours to release
+    if !entry.expired {
+        DhcpService::new(nc, dhcp)
+            .map_err(|e| e.to_string())
+            .and_then(|s| s.release_lease(&entry.lease).map_err(|e| e.to_string()))
+            .map_err(|e| {
+                warn!("Could not release lease for {}: {}", key, e);
+                Status::internal(format!("Error releasing the lease: {e}"))
+            })?;
+    }
+    removed.map(|_| true)
+}
+
+/// Take away a lease that ran out. The address may already be handed to someone else by
+/// the DHCP server, so it must no longer be used by the container.
+///
+/// The cache stays locked while the address is removed, so a setup of the container that
+/// obtained a new lease in the meantime keeps it. Returns false when the cache no longer
+/// holds the lease that ran out. The lease is marked as expired even when its address
+/// could not be removed.
+fn expire_lease<W: LeaseStore>(
+    cache: &Arc<Mutex<LeaseCache<W>>>,
+    metrics: &Arc<Metrics>,
+    key: &LeaseKey,
+    entry: &CachedLease,
+) -> Result<bool, Status> {
+    let nc = &entry.network_config;
+    let mut locked_cache = cache.lock().map_err(|e| Status::internal(e.to_string()))?;
+    if locked_cache.get(key) != Some(entry) {
+        debug!("lease for {} changed since it ran out, keeping it", key);
+        return Ok(false);
+    }
+    // This is the event operators should alert on, so always log and count it
+    metrics.observe_expiry();
+    error!(
+        "Lease expired: mac address {} lost {} on {} in {}",
+        key.mac_address, entry.lease.yiaddr, nc.container_iface, nc.ns_path
+    );
+    let removed = ip::teardown(&entry.lease, &nc.container_iface, &nc.ns_path).map_err(|e| {
+        warn!(
+            "Could not remove expired address {} from {}: {}",
+            entry.lease.yiaddr,
+            nc.container_iface,
+            e.to_string()
+        );
+        Status::from(e)
+    });
+    locked_cache.expire_lease(key).map_err(|e| {
+        metrics.observe_cache_write_error();
+        error!("Could not mark lease for {} as expired: {}", key, e);
+        Status::internal(format!("Error marking the lease as expired: {e}"))
+    })?;
+    removed.map(|_| true)
+}
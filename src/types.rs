@@ -3,28 +3,63 @@ use mozim::DhcpError;
 use mozim::ErrorKind::InvalidArgument;
 use nv::error::NetavarkError;
 use std::net::AddrParseError;
-use std::num::ParseIntError;
 use std::str::FromStr;
 use std::string::ToString;
 use tonic::{Code, Status};
 
+use crate::g_rpc::Version;
 use crate::NetworkConfig;
+
+/// Parse the compact `key=value,key=value` form of a configuration, like
+/// `host_iface=eth0,container_iface=eth0,mac=3c:e1:a1:c1:7a:3f,ns=/run/netns/foo`.
+/// Keys are the fields of the configuration, `mac`, `ns`, `hostname`, `domain` and
+/// `family` are short for `container_mac_addr`, `ns_path`, `host_name`, `domain_name`
+/// and `version`. Fields that are left out are empty.
 impl FromStr for NetworkConfig {
-    type Err = ParseIntError;
-
-    fn from_str(_s: &str) -> Result<Self, Self::Err> {
-        // s is actually a string so if we intend to generate
-        // a `NetworkConfig` object from `s` parse `s` and populate
-        // instead of default empty values
-        Ok(NetworkConfig {
-            host_iface: "".to_string(),
-            container_mac_addr: "".to_string(),
-            domain_name: "".to_string(),
-            host_name: "".to_string(),
-            version: 0,
-            ns_path: "".to_string(),
-            container_iface: "".to_string(),
-        })
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut nc = NetworkConfig::default();
+        for pair in s.split(',').filter(|p| !p.trim().is_empty()) {
+            let (key, value) = match pair.split_once('=') {
+                Some((k, v)) => (k.trim().replace('-', "_"), v.trim().to_string()),
+                None => return Err(ProxyError::new(format!("{pair} is not a key=value pair"))),
+            };
+            match key.as_str() {
+                "host_iface" => nc.host_iface = value,
+                "container_iface" => nc.container_iface = value,
+                "mac" | "container_mac_addr" => nc.container_mac_addr = value,
+                "ns" | "ns_path" => nc.ns_path = value,
+                "hostname" | "host_name" => nc.host_name = value,
+                "domain" | "domain_name" => nc.domain_name = value,
+                "family" | "version" => nc.version = Version::from_str(&value)? as i32,
+                "container_id" => nc.container_id = value,
+                "container_name" => nc.container_name = value,
+                "network_name" => nc.network_name = value,
+                "requested_address" => nc.requested_address = value,
+                "ipvlan" => {
+                    nc.ipvlan = value.parse().map_err(|_| {
+                        ProxyError::new(format!("ipvlan must be true or false, not {value}"))
+                    })?
+                }
+                _ => return Err(ProxyError::new(format!("unknown configuration key {key}"))),
+            }
+        }
+        Ok(nc)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "4" | "v4" | "ipv4" => Ok(Version::V4),
+            "6" | "v6" | "ipv6" => Ok(Version::V6),
+            _ => Err(ProxyError::new(format!(
+                "{s} is not an address family, use 4 or 6"
+            ))),
+        }
     }
 }
 
@@ -65,6 +100,18 @@ impl From<PrefixLenError> for ProxyError {
     }
 }
 
+impl From<std::io::Error> for ProxyError {
+    fn from(e: std::io::Error) -> Self {
+        ProxyError::new(e.to_string())
+    }
+}
+
+impl From<nix::Error> for ProxyError {
+    fn from(e: nix::Error) -> Self {
+        ProxyError::new(e.to_string())
+    }
+}
+
 impl From<AddrParseError> for ProxyError {
     fn from(e: AddrParseError) -> Self {
         ProxyError::new(e.to_string())
@@ -76,3 +123,33 @@ impl From<ProxyError> for DhcpError {
         DhcpError::new(InvalidArgument, e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_config_from_str() {
+        let nc = NetworkConfig::from_str(
+            "host_iface=eth0, container_iface=eth1,mac=3c:e1:a1:c1:7a:3f,ns=/run/netns/foo,\
+             family=6,hostname=foo,ipvlan=true",
+        )
+        .expect("could not parse config");
+        assert_eq!(nc.host_iface, "eth0");
+        assert_eq!(nc.container_iface, "eth1");
+        assert_eq!(nc.container_mac_addr, "3c:e1:a1:c1:7a:3f");
+        assert_eq!(nc.ns_path, "/run/netns/foo");
+        assert_eq!(nc.host_name, "foo");
+        assert_eq!(nc.version, Version::V6 as i32);
+        assert!(nc.ipvlan);
+
+        assert_eq!(
+            NetworkConfig::from_str("").unwrap(),
+            NetworkConfig::default()
+        );
+        assert!(NetworkConfig::from_str("foo").is_err());
+        assert!(NetworkConfig::from_str("colour=blue").is_err());
+        assert!(NetworkConfig::from_str("family=5").is_err());
+        assert!(NetworkConfig::from_str("ipvlan=yes").is_err());
+    }
+}
@@ -27,6 +27,29 @@ EOF
         has_ip "$container_ip"
 }
 
+@test "repeated setup keeps the lease" {
+
+      read -r -d '\0' input_config <<EOF
+{
+  "host_iface": "veth1",
+  "container_iface": "veth0",
+  "container_mac_addr": "$CONTAINER_MAC",
+  "domain_name": "example.com",
+  "host_name": "foobar",
+  "version": 0,
+  "ns_path": "$NS_PATH"
+}
+  \0
+EOF
+
+        run_setup "$input_config"
+        container_ip=$(echo "$output" | jq -r .yiaddr)
+        # A retried setup returns the same lease instead of failing on the address
+        run_setup "$input_config"
+        assert `echo "$output" | jq -r .yiaddr` == "$container_ip"
+        has_ip "$container_ip"
+}
+
 
 @test "empty interface should fail 155" {
       read -r -d '\0' input_config <<EOF
@@ -100,3 +123,78 @@ EOF
         # error and a return code of 156
         expected_rc=156 run_setup "$input_config"
 }
+
+@test "ipvlan setup on another kind of link should fail 155" {
+      read -r -d '\0' input_config <<EOF
+{
+  "container_iface": "veth0",
+  "host_iface": "veth1",
+  "container_mac_addr": "$CONTAINER_MAC",
+  "domain_name": "example.com",
+  "host_name": "foobar",
+  "version": 0,
+  "ns_path": "$NS_PATH",
+  "container_id": "d3b07384d113",
+  "ipvlan": true
+}
+  \0
+EOF
+
+        # The address of an ipvlan lease is only put on an ipvlan link
+        expected_rc=155 run_setup "$input_config"
+}
+
+@test "requested address without the relay should fail" {
+      read -r -d '\0' input_config <<EOF
+{
+  "container_iface": "veth0",
+  "host_iface": "veth1",
+  "container_mac_addr": "$CONTAINER_MAC",
+  "domain_name": "example.com",
+  "host_name": "foobar",
+  "version": 0,
+  "ns_path": "$NS_PATH",
+  "requested_address": "10.10.10.50"
+}
+  \0
+EOF
+
+        # Only the relay can ask for an address, so the proxy does not offer it
+        expected_rc=1 run_setup "$input_config"
+}
+
+@test "setup is written to the audit log" {
+
+      read -r -d '\0' input_config <<EOF
+{
+  "host_iface": "veth1",
+  "container_iface": "veth0",
+  "container_mac_addr": "$CONTAINER_MAC",
+  "domain_name": "example.com",
+  "host_name": "foobar",
+  "version": 0,
+  "ns_path": "$NS_PATH"
+}
+  \0
+EOF
+
+        run_setup "$input_config"
+        container_ip=$(echo "$output" | jq -r .yiaddr)
+        record=$(tail -n 1 "$TMP_TESTDIR/nv-proxy.audit")
+        assert_json "$record" ".operation" == "setup" "audited operation"
+        assert_json "$record" ".outcome" == "success" "audited outcome"
+        assert_json "$record" ".uid" == "0" "audited caller"
+        assert_json "$record" ".address" == "$container_ip" "audited address"
+}
+
+@test "setup from command line flags" {
+        run_in_container_netns "./bin/client" --uds "$TMP_TESTDIR/nv-proxy.sock" setup \
+            host_iface=veth1,container_iface=veth0 --mac "$CONTAINER_MAC" --ns "$NS_PATH" --family 4
+        container_ip=$(echo "$output" | jq -r .yiaddr)
+        has_ip "$container_ip"
+}
+
+@test "setup from flags without a namespace should fail 156" {
+        expected_rc=156 run_in_container_netns "./bin/client" --uds "$TMP_TESTDIR/nv-proxy.sock" setup \
+            --host-iface veth1 --container-iface veth0 --mac "$CONTAINER_MAC"
+}
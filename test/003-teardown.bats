@@ -25,13 +25,42 @@ EOF
        before=$output
        # Check that our mac address is in the lease file which
        # ensures that it was added
-       run_helper jq "has(\"$CONTAINER_MAC\")" <<<"$before"
+       run_helper jq "any(.leases[]; .key.mac_address == \"$CONTAINER_MAC\")" <<<"$before"
        assert "$output" == "true"
        # Run teardown
        run_teardown "$input_config"
        run_helper cat "$TMP_TESTDIR/nv-proxy.lease"
-       # Check that the length of the lease file is now zero
-       run_helper jq ". | length" <<<"$output"
+       # Check that no leases are left in the lease file
+       run_helper jq ".leases | length" <<<"$output"
        assert "$output" == 0
 
 }
+
+@test "teardown by container id" {
+      read -r -d '\0' input_config <<EOF
+{
+  "host_iface": "veth1",
+  "container_iface": "veth0",
+  "container_mac_addr": "${CONTAINER_MAC}",
+  "domain_name": "example.com",
+  "host_name": "foobar",
+  "version": 0,
+  "ns_path": "$NS_PATH",
+  "container_id": "d3b07384d113",
+  "container_name": "foobar",
+  "network_name": "podman1"
+}
+  \0
+EOF
+
+       run_setup "$input_config"
+       # The lease can be found by its container
+       run_in_container_netns "./bin/client" --uds "$TMP_TESTDIR/nv-proxy.sock" inspect --container-id d3b07384d113
+       run_helper jq -r ".leases[0].config.network_name" <<<"$output"
+       assert "$output" == "podman1"
+       # Teardown without the mac address
+       run_teardown '{"container_id": "d3b07384d113"}'
+       run_helper cat "$TMP_TESTDIR/nv-proxy.lease"
+       run_helper jq ".leases | length" <<<"$output"
+       assert "$output" == 0
+}
@@ -4,6 +4,22 @@
 #
 
 load helpers
+@test "Health check" {
+  run_in_container_netns "./bin/client" --uds "$TMP_TESTDIR/nv-proxy.sock" health
+  assert `echo "$output" | jq -r .status` == "SERVING"
+}
+
+@test "Version" {
+  run_in_container_netns "./bin/client" --uds "$TMP_TESTDIR/nv-proxy.sock" version
+  info="$output"
+  assert_json "$info" ".proxy.api_version" == "1" "proxy api version"
+  assert_json "$info" ".client.api_version" == "1" "client api version"
+  assert_json "$info" '.proxy.capabilities | index("renewal") != null' == "true" "renewal capability"
+  assert_json "$info" '.proxy.capabilities | index("ipvlan") != null' == "true" "ipvlan capability"
+  # without the relay the proxy can not ask for an address
+  assert_json "$info" '.proxy.capabilities | index("requested_address") == null' == "true" "no requested_address capability"
+}
+
 @test "SIGINT Clean up" {
       read -r -d '\0' input_config <<EOF
 {